@@ -0,0 +1,1005 @@
+use crate::client::AsyncCisClientTrait;
+use crate::client::CisFut;
+use crate::dedup::Dedup;
+use crate::error::CisClientError;
+use crate::error::ProfileError;
+use crate::schema::profile_from_json;
+use chrono::DateTime;
+use chrono::SecondsFormat;
+use chrono::Utc;
+use cis_profile::schema::Profile;
+use futures::ready;
+use futures::task::Context;
+use futures::task::Poll;
+use futures::Stream;
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::time::Instant;
+use tokio::task::JoinHandle;
+use tokio::time::Sleep;
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct NextPage {
+    pub id: String,
+}
+
+/// Checkpoint of an export: the page to continue at and the filter the
+/// export ran with. Unlike a bare [NextPage] it can only be resumed with the
+/// same filter.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ResumeToken {
+    pub next_page: NextPage,
+    pub filter: Option<String>,
+}
+
+impl ResumeToken {
+    /// `next_page` if the token was issued for `filter`.
+    pub(crate) fn next_page_for(self, filter: &Option<String>) -> Result<NextPage, CisClientError> {
+        if self.filter != *filter {
+            return Err(CisClientError::ResumeFilterMismatch {
+                token: self.filter,
+                filter: filter.clone(),
+            });
+        }
+        Ok(self.next_page)
+    }
+}
+
+#[derive(Debug)]
+pub struct Batch {
+    pub items: Option<Vec<Profile>>,
+    pub next_page: Option<NextPage>,
+    /// Number of items in the response which could not be parsed as
+    /// [Profile] and are missing from `items`.
+    pub skipped: usize,
+}
+
+impl Batch {
+    /// Parse a `/users` response, skipping items which are no valid [Profile].
+    /// If `strict`, an item with fields unknown to [Profile] fails the whole
+    /// page with [ProfileError::UnknownFields].
+    pub(crate) fn from_json(mut json: Value, strict: bool) -> Result<Self, CisClientError> {
+        let mut skipped = 0;
+        let items = match json["Items"].take() {
+            Value::Array(items) => {
+                let mut profiles = Vec::with_capacity(items.len());
+                for item in items {
+                    profiles.extend(parse_profile(item, strict, &mut skipped)?);
+                }
+                Some(profiles)
+            }
+            _ => None,
+        };
+        let next_page = serde_json::from_value(json["nextPage"].take()).ok();
+        Ok(Batch {
+            items,
+            next_page,
+            skipped,
+        })
+    }
+}
+
+/// Parse one item of a `/users` response, counting it in `skipped` if it is
+/// no valid [Profile]. Unknown fields are an error if `strict`, see
+/// [profile_from_json].
+pub(crate) fn parse_profile(
+    item: Value,
+    strict: bool,
+    skipped: &mut usize,
+) -> Result<Option<Profile>, CisClientError> {
+    match profile_from_json(item, strict) {
+        Ok(profile) => Ok(Some(profile)),
+        Err(e @ CisClientError::ProfileError(ProfileError::UnknownFields(_))) => Err(e),
+        Err(e) => {
+            log::warn!("skipping malformed profile: {}", e);
+            *skipped += 1;
+            Ok(None)
+        }
+    }
+}
+
+/// `since` as sent in the `modifiedSince` query parameter.
+pub(crate) fn modified_since_param(since: DateTime<Utc>) -> String {
+    since.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+/// Fail with [CisClientError::ModifiedSinceIgnored] if `batch` contains a
+/// profile last modified before `since`, i.e. the server did not apply the
+/// `modifiedSince` parameter. Profiles without a parseable `last_modified`
+/// are not checked.
+pub(crate) fn check_modified_since(
+    batch: &Batch,
+    since: DateTime<Utc>,
+) -> Result<(), CisClientError> {
+    for profile in batch.items.iter().flatten() {
+        let last_modified = profile
+            .last_modified
+            .value
+            .as_deref()
+            .and_then(|last_modified| DateTime::parse_from_rfc3339(last_modified).ok());
+        if let Some(last_modified) = last_modified {
+            if last_modified < since {
+                return Err(CisClientError::ModifiedSinceIgnored {
+                    since,
+                    last_modified: last_modified.with_timezone(&Utc),
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+enum AsyncProfileIterState {
+    Uninitalized,
+    Inflight(CisFut<Batch>),
+    NextPage(NextPage),
+    Done,
+}
+
+/// Stream over batches of [Profile]s.
+/// Internally this retrieves batches of users from the `/users' endpoint.
+pub struct AsyncProfileIter<T> {
+    cis_client: T,
+    filter: Option<String>,
+    state: AsyncProfileIterState,
+    prefetch: usize,
+    buffer: VecDeque<Result<Batch, CisClientError>>,
+    next_page: Option<NextPage>,
+    deadline: Option<Instant>,
+    timer: Option<Pin<Box<Sleep>>>,
+    expected: Option<usize>,
+    yielded: usize,
+    dedup: Option<Dedup>,
+    modified_since: Option<DateTime<Utc>>,
+}
+
+impl<T> AsyncProfileIter<T> {
+    pub fn new(cis_client: T, filter: Option<String>) -> Self {
+        AsyncProfileIter {
+            cis_client,
+            filter,
+            state: AsyncProfileIterState::Uninitalized,
+            prefetch: 0,
+            buffer: VecDeque::new(),
+            next_page: None,
+            deadline: None,
+            timer: None,
+            expected: None,
+            yielded: 0,
+            dedup: None,
+            modified_since: None,
+        }
+    }
+
+    /// Number of profiles the export will yield at least, e.g. from
+    /// [AsyncCisClientTrait::get_user_count] when profiles are only added
+    /// during the export. Only used as lower bound in `size_hint`.
+    pub fn with_expected_count(mut self, count: usize) -> Self {
+        self.expected = Some(count);
+        self
+    }
+
+    /// Expected profiles not yet yielded.
+    fn remaining(&self) -> usize {
+        self.expected
+            .map(|expected| expected.saturating_sub(self.yielded))
+            .unwrap_or(0)
+    }
+
+    fn finished(&self) -> bool {
+        matches!(self.state, AsyncProfileIterState::Done) && self.buffer.is_empty()
+    }
+
+    /// Stop at `deadline`. Pages already fetched are still yielded, an
+    /// in-flight request is dropped and the stream ends with
+    /// [CisClientError::DeadlineExceeded].
+    pub fn with_deadline(mut self, deadline: Instant) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    fn deadline_passed(&mut self, cx: &mut Context) -> bool {
+        let deadline = match self.deadline {
+            Some(deadline) => deadline,
+            None => return false,
+        };
+        self.timer
+            .get_or_insert_with(|| {
+                Box::pin(tokio::time::sleep_until(tokio::time::Instant::from_std(
+                    deadline,
+                )))
+            })
+            .as_mut()
+            .poll(cx)
+            .is_ready()
+    }
+
+    /// Continue an export at `next_page`, see [AsyncProfileIter::next_page].
+    pub fn resume_from(cis_client: T, filter: Option<String>, next_page: NextPage) -> Self {
+        let mut iter = Self::new(cis_client, filter);
+        iter.state = AsyncProfileIterState::NextPage(next_page);
+        iter
+    }
+
+    /// Token to resume after the most recently yielded page. `None` before the
+    /// first page and after the last one.
+    pub fn next_page(&self) -> Option<&NextPage> {
+        self.next_page.as_ref()
+    }
+
+    /// Like [AsyncProfileIter::resume_from] but fails with
+    /// [CisClientError::ResumeFilterMismatch] if `token` was issued for
+    /// another filter.
+    pub fn resume_from_token(
+        cis_client: T,
+        filter: Option<String>,
+        token: ResumeToken,
+    ) -> Result<Self, CisClientError> {
+        let next_page = token.next_page_for(&filter)?;
+        Ok(Self::resume_from(cis_client, filter, next_page))
+    }
+
+    /// [AsyncProfileIter::next_page] together with the filter of this export.
+    pub fn resume_token(&self) -> Option<ResumeToken> {
+        self.next_page.clone().map(|next_page| ResumeToken {
+            next_page,
+            filter: self.filter.clone(),
+        })
+    }
+
+    /// Keep fetching up to `depth` pages ahead of the consumer. Pages are
+    /// fetched on spawned tokio tasks and yielded in order. Dropping the
+    /// iterator aborts a running fetch.
+    pub fn with_prefetch(mut self, depth: usize) -> Self {
+        self.prefetch = depth;
+        self
+    }
+
+    /// Drop profiles already yielded by this export, e.g. users returned on
+    /// two adjacent pages because of concurrent writes. Profiles are keyed on
+    /// `uuid`, falling back to `user_id`. With `window` only the keys of the
+    /// last `window` profiles are kept, bounding memory but missing duplicates
+    /// further apart. `None` keeps every key, so memory grows with the size of
+    /// the export.
+    pub fn with_dedup(mut self, window: Option<usize>) -> Self {
+        self.dedup = Some(Dedup::new(window));
+        self
+    }
+
+    /// Only fetch profiles modified at or after `since`, see
+    /// [AsyncCisClientTrait::get_batch_modified_since]. The stream fails with
+    /// [CisClientError::ModifiedSinceIgnored] on a page with an older profile.
+    pub fn with_modified_since(mut self, since: DateTime<Utc>) -> Self {
+        self.modified_since = Some(since);
+        self
+    }
+
+    /// Flatten the pages into a stream of individual [Profile]s.
+    pub fn profiles_stream(self) -> AsyncProfileStream<T> {
+        AsyncProfileStream {
+            pages: self,
+            current: Vec::new().into_iter(),
+        }
+    }
+
+    /// Pair every page with the token fetching the page after it, e.g. to
+    /// checkpoint an export. The last page is paired with `None`.
+    pub fn batches_with_token(self) -> AsyncBatchTokenStream<T> {
+        AsyncBatchTokenStream { pages: self }
+    }
+
+    /// Yield every fetched [Batch] as is, including its `next_page` and
+    /// `skipped` count. Unlike the page stream this also yields batches
+    /// without `Items`.
+    pub fn batches(self) -> AsyncBatchStream<T> {
+        AsyncBatchStream { pages: self }
+    }
+
+    /// Buffered pages yielding profiles, batches without `Items` are skipped
+    /// by the page stream.
+    fn buffered_pages(&self) -> usize {
+        self.buffer
+            .iter()
+            .filter(|batch| !matches!(batch, Ok(Batch { items: None, .. })))
+            .count()
+    }
+}
+
+impl<T: AsyncCisClientTrait> AsyncProfileIter<T> {
+    fn fetch(&self, next_page: Option<NextPage>) -> CisFut<Batch> {
+        let fut = match self.modified_since {
+            Some(since) => {
+                self.cis_client
+                    .get_batch_modified_since(&next_page, &self.filter, since)
+            }
+            None => self.cis_client.get_batch(&next_page, &self.filter),
+        };
+        if self.prefetch == 0 {
+            return fut;
+        }
+        Box::pin(PrefetchTask(tokio::spawn(fut)))
+    }
+}
+
+/// Page fetched on a spawned task which is aborted when dropped, e.g. with
+/// the [AsyncProfileIter] waiting for it.
+struct PrefetchTask(JoinHandle<Result<Batch, CisClientError>>);
+
+impl Future for PrefetchTask {
+    type Output = Result<Batch, CisClientError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        Poll::Ready(match ready!(Pin::new(&mut self.0).poll(cx)) {
+            Ok(batch) => batch,
+            Err(e) => Err(CisClientError::TaskFailed(e.to_string())),
+        })
+    }
+}
+
+impl Drop for PrefetchTask {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+impl<T: AsyncCisClientTrait + Unpin> AsyncProfileIter<T> {
+    /// Poll for the next batch in order, fetching ahead if prefetching.
+    fn poll_batch(&mut self, cx: &mut Context) -> Poll<Option<Result<Batch, CisClientError>>> {
+        loop {
+            if !matches!(self.state, AsyncProfileIterState::Done) && self.deadline_passed(cx) {
+                log::debug!("deadline exceeded");
+                self.state = AsyncProfileIterState::Done;
+                self.buffer.push_back(Err(CisClientError::DeadlineExceeded));
+            }
+            match std::mem::replace(&mut self.state, AsyncProfileIterState::Done) {
+                AsyncProfileIterState::Done => break,
+                AsyncProfileIterState::Uninitalized => {
+                    log::trace!("fetching first page");
+                    self.state = AsyncProfileIterState::Inflight(self.fetch(None));
+                }
+                AsyncProfileIterState::NextPage(next_page) => {
+                    if self.buffer.len() > self.prefetch {
+                        self.state = AsyncProfileIterState::NextPage(next_page);
+                        break;
+                    }
+                    log::trace!("fetching page {}", next_page.id);
+                    self.state = AsyncProfileIterState::Inflight(self.fetch(Some(next_page)));
+                }
+                AsyncProfileIterState::Inflight(mut fut) => match fut.as_mut().poll(cx) {
+                    Poll::Pending => {
+                        self.state = AsyncProfileIterState::Inflight(fut);
+                        break;
+                    }
+                    Poll::Ready(Err(e)) => {
+                        log::debug!("failed to fetch page: {}", e);
+                        self.buffer.push_back(Err(e));
+                    }
+                    Poll::Ready(Ok(batch)) => {
+                        if let Some(Err(e)) = self
+                            .modified_since
+                            .map(|since| check_modified_since(&batch, since))
+                        {
+                            log::debug!("{}", e);
+                            self.buffer.push_back(Err(e));
+                            continue;
+                        }
+                        if let Some(next_page) = &batch.next_page {
+                            log::trace!("got page, next page {}", next_page.id);
+                            self.state = AsyncProfileIterState::NextPage(next_page.clone());
+                        } else {
+                            log::debug!("got last page");
+                        }
+                        self.buffer.push_back(Ok(batch));
+                    }
+                },
+            }
+        }
+        match (self.buffer.pop_front(), &self.state) {
+            (Some(mut item), _) => {
+                if let (
+                    Ok(Batch {
+                        items: Some(items), ..
+                    }),
+                    Some(dedup),
+                ) = (&mut item, &mut self.dedup)
+                {
+                    dedup.retain_new(items);
+                }
+                if let Ok(batch) = &item {
+                    self.yielded += batch.items.as_ref().map_or(0, Vec::len);
+                    self.next_page = batch.next_page.clone();
+                }
+                Poll::Ready(Some(item))
+            }
+            (None, AsyncProfileIterState::Done) => Poll::Ready(None),
+            (None, _) => Poll::Pending,
+        }
+    }
+}
+
+impl<T: AsyncCisClientTrait + Unpin> Stream for AsyncProfileIter<T> {
+    type Item = Result<Vec<Profile>, CisClientError>;
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            match ready!(this.poll_batch(cx)) {
+                // nothing to yield, keep polling for the next page
+                Some(Ok(Batch { items: None, .. })) => {}
+                page => {
+                    return Poll::Ready(
+                        page.map(|page| page.map(|batch| batch.items.unwrap_or_default())),
+                    )
+                }
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        if self.finished() {
+            return (0, Some(0));
+        }
+        let pending = if self.remaining() > 0 { 1 } else { 0 };
+        (self.buffered_pages().max(pending), None)
+    }
+}
+
+/// Stream over individual [Profile]s, see [AsyncProfileIter::profiles_stream].
+/// A failing page fetch is yielded as an `Err` and ends the stream.
+pub struct AsyncProfileStream<T> {
+    pages: AsyncProfileIter<T>,
+    current: std::vec::IntoIter<Profile>,
+}
+
+impl<T: AsyncCisClientTrait + Unpin> Stream for AsyncProfileStream<T> {
+    type Item = Result<Profile, CisClientError>;
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            if let Some(profile) = this.current.next() {
+                return Poll::Ready(Some(Ok(profile)));
+            }
+            match Pin::new(&mut this.pages).poll_next(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Some(Ok(profiles))) => this.current = profiles.into_iter(),
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Some(Err(e))),
+                Poll::Ready(None) => return Poll::Ready(None),
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let current = self.current.len();
+        if self.pages.finished() {
+            return (current, Some(current));
+        }
+        (current + self.pages.remaining(), None)
+    }
+}
+
+/// Stream over pages and their next page token, see
+/// [AsyncProfileIter::batches_with_token].
+pub struct AsyncBatchTokenStream<T> {
+    pages: AsyncProfileIter<T>,
+}
+
+impl<T: AsyncCisClientTrait + Unpin> Stream for AsyncBatchTokenStream<T> {
+    type Item = Result<(Vec<Profile>, Option<NextPage>), CisClientError>;
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let pages = &mut self.get_mut().pages;
+        Pin::new(&mut *pages)
+            .poll_next(cx)
+            .map(|page| page.map(|page| page.map(|profiles| (profiles, pages.next_page.clone()))))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.pages.size_hint()
+    }
+}
+
+/// Stream over the fetched batches, see [AsyncProfileIter::batches].
+pub struct AsyncBatchStream<T> {
+    pages: AsyncProfileIter<T>,
+}
+
+impl<T: AsyncCisClientTrait + Unpin> Stream for AsyncBatchStream<T> {
+    type Item = Result<Batch, CisClientError>;
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        self.get_mut().pages.poll_batch(cx)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        if self.pages.finished() {
+            return (0, Some(0));
+        }
+        (self.pages.buffer.len(), None)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bulk::UpdateUsersResult;
+    use crate::client::CisClient;
+    use crate::getby::GetBy;
+    use crate::test_util::*;
+    use cis_profile::crypto::SecretStore;
+    use futures::future;
+    use futures::StreamExt;
+    use futures::TryStreamExt;
+    use mockito::Matcher;
+    use serde_json::json;
+    use serde_json::Value;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+
+    #[derive(Clone, Default)]
+    struct CisClientFaker {
+        count: usize,
+        fail_at: Option<usize>,
+        page_delay: Option<std::time::Duration>,
+        /// Repeat the last profile of the previous page on every page.
+        repeat: bool,
+        /// Pages fetched after their delay.
+        fetched: Arc<AtomicUsize>,
+    }
+    impl AsyncCisClientTrait for CisClientFaker {
+        fn get_user_by(&self, _: &str, _: &GetBy, _: Option<&str>) -> CisFut<Profile> {
+            unimplemented!()
+        }
+        fn get_inactive_user_by(&self, _: &str, _: &GetBy, _: Option<&str>) -> CisFut<Profile> {
+            unimplemented!()
+        }
+        fn get_any_user_by(&self, _: &str, _: &GetBy, _: Option<&str>) -> CisFut<Profile> {
+            unimplemented!()
+        }
+        fn get_user_raw_by(&self, _: &str, _: &GetBy, _: Option<&str>) -> CisFut<Value> {
+            unimplemented!()
+        }
+        fn get_users_iter(&self, filter: Option<&str>) -> AsyncProfileIter<Self> {
+            AsyncProfileIter::new(self.clone(), filter.map(String::from))
+        }
+        fn get_batch(
+            &self,
+            pagination_token: &Option<NextPage>,
+            _: &Option<String>,
+        ) -> CisFut<Batch> {
+            if pagination_token.is_none() && self.count == 0 {
+                return Box::pin(future::ok(Batch {
+                    items: None,
+                    next_page: None,
+                    skipped: 0,
+                }));
+            };
+            let left = if let Some(n) = pagination_token {
+                n.id.parse().unwrap()
+            } else {
+                self.count
+            };
+            if self.fail_at == Some(left) {
+                return Box::pin(future::err(CisClientError::ApiError {
+                    status: 500,
+                    body: String::from("boom"),
+                }));
+            }
+            let profile = |uuid: usize| {
+                let mut profile = Profile::default();
+                profile.uuid.value = Some(uuid.to_string());
+                profile
+            };
+            let mut items = vec![profile(left)];
+            if self.repeat && left < self.count {
+                items.insert(0, profile(left + 1));
+            }
+            let batch = Batch {
+                items: Some(items),
+                next_page: if left > 1 {
+                    Some(NextPage {
+                        id: format!("{}", left - 1),
+                    })
+                } else {
+                    None
+                },
+                skipped: 0,
+            };
+            let fetched = Arc::clone(&self.fetched);
+            let delay = self.page_delay;
+            Box::pin(async move {
+                if let Some(delay) = delay {
+                    tokio::time::sleep(delay).await;
+                }
+                fetched.fetch_add(1, Ordering::SeqCst);
+                Ok(batch)
+            })
+        }
+        fn get_user_count(&self, _: Option<&str>) -> CisFut<usize> {
+            unimplemented!()
+        }
+        fn create_user_raw(&self, _: Profile) -> CisFut<Value> {
+            unimplemented!()
+        }
+        fn update_user_raw(&self, _: &str, _: Profile) -> CisFut<Value> {
+            unimplemented!()
+        }
+        fn update_users(&self, _: &[Profile]) -> CisFut<UpdateUsersResult> {
+            unimplemented!()
+        }
+        fn delete_user_raw(&self, _: &str, _: Profile) -> CisFut<Value> {
+            unimplemented!()
+        }
+        fn delete_users(&self, _: &[Profile]) -> CisFut<Value> {
+            unimplemented!()
+        }
+        fn get_secret_store(&self) -> &SecretStore {
+            unimplemented!()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_async_profile_iter_empty() -> Result<(), CisClientError> {
+        let iter = AsyncProfileIter::new(
+            CisClientFaker {
+                count: 0,
+                ..Default::default()
+            },
+            None,
+        );
+        let batches: Vec<Vec<Profile>> = iter.try_collect().await?;
+        assert!(batches.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_batches_with_token3() -> Result<(), CisClientError> {
+        let iter = AsyncProfileIter::new(
+            CisClientFaker {
+                count: 3,
+                ..Default::default()
+            },
+            None,
+        );
+        let pages: Vec<(Vec<Profile>, Option<NextPage>)> =
+            iter.batches_with_token().try_collect().await?;
+        let tokens: Vec<Option<String>> = pages
+            .into_iter()
+            .map(|(_, next_page)| next_page.map(|n| n.id))
+            .collect();
+        assert_eq!(
+            tokens,
+            vec![Some(String::from("2")), Some(String::from("1")), None]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_batches3() -> Result<(), CisClientError> {
+        let iter = AsyncProfileIter::new(
+            CisClientFaker {
+                count: 3,
+                ..Default::default()
+            },
+            None,
+        );
+        let batches: Vec<Batch> = iter.batches().try_collect().await?;
+        let tokens: Vec<Option<String>> = batches
+            .iter()
+            .map(|batch| batch.next_page.as_ref().map(|n| n.id.clone()))
+            .collect();
+        assert_eq!(
+            tokens,
+            vec![Some(String::from("2")), Some(String::from("1")), None]
+        );
+        assert!(batches
+            .iter()
+            .all(|batch| batch.items.as_ref().map(Vec::len) == Some(1) && batch.skipped == 0));
+
+        let empty = AsyncProfileIter::new(CisClientFaker::default(), None);
+        let batches: Vec<Batch> = empty.batches().try_collect().await?;
+        assert_eq!(batches.len(), 1);
+        assert!(batches[0].items.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_async_profile_iter2() -> Result<(), CisClientError> {
+        let iter = AsyncProfileIter::new(
+            CisClientFaker {
+                count: 2,
+                ..Default::default()
+            },
+            None,
+        );
+        let batches: Vec<Vec<Profile>> = iter.try_collect().await?;
+        assert_eq!(batches.len(), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_async_profile_iter_completes() -> Result<(), CisClientError> {
+        let mut iter = AsyncProfileIter::new(
+            CisClientFaker {
+                count: 3,
+                ..Default::default()
+            },
+            None,
+        );
+        for _ in 0..3 {
+            assert!(iter.try_next().await?.is_some());
+        }
+        assert!(iter.try_next().await?.is_none());
+        assert!(iter.try_next().await?.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_async_profile_iter_prefetch_keeps_order() -> Result<(), CisClientError> {
+        let faker = CisClientFaker {
+            count: 5,
+            ..Default::default()
+        };
+        let stream = AsyncProfileIter::new(faker, None)
+            .with_prefetch(2)
+            .profiles_stream();
+        let profiles: Vec<Profile> = stream.try_collect().await?;
+        let uuids: Vec<_> = profiles
+            .iter()
+            .map(|p| p.uuid.value.as_deref().unwrap())
+            .collect();
+        assert_eq!(uuids, vec!["5", "4", "3", "2", "1"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_async_profile_iter_dedup() -> Result<(), CisClientError> {
+        let faker = CisClientFaker {
+            count: 3,
+            repeat: true,
+            ..Default::default()
+        };
+        let uuids = |profiles: Vec<Profile>| -> Vec<String> {
+            profiles.into_iter().filter_map(|p| p.uuid.value).collect()
+        };
+        let all: Vec<Profile> = AsyncProfileIter::new(faker.clone(), None)
+            .profiles_stream()
+            .try_collect()
+            .await?;
+        assert_eq!(uuids(all), vec!["3", "3", "2", "2", "1"]);
+        for window in &[None, Some(1)] {
+            let deduped: Vec<Profile> = AsyncProfileIter::new(faker.clone(), None)
+                .with_dedup(*window)
+                .profiles_stream()
+                .try_collect()
+                .await?;
+            assert_eq!(uuids(deduped), vec!["3", "2", "1"]);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_async_profile_iter_aborts_prefetch_on_drop() {
+        let faker = CisClientFaker {
+            count: 3,
+            page_delay: Some(std::time::Duration::from_millis(50)),
+            ..Default::default()
+        };
+        let fetched = Arc::clone(&faker.fetched);
+        let mut iter = AsyncProfileIter::new(faker, None).with_prefetch(1);
+        assert!(futures::poll!(iter.next()).is_pending());
+        drop(iter);
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+        assert_eq!(fetched.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn test_async_profile_iter_deadline() {
+        let iter = AsyncProfileIter::new(
+            CisClientFaker {
+                count: 1000,
+                page_delay: Some(std::time::Duration::from_millis(10)),
+                ..Default::default()
+            },
+            None,
+        )
+        .with_deadline(Instant::now() + std::time::Duration::from_millis(55));
+        let results: Vec<_> = iter.collect().await;
+        assert!(results.len() < 1000);
+        assert!(matches!(
+            results.last(),
+            Some(Err(CisClientError::DeadlineExceeded))
+        ));
+        assert!(results[..results.len() - 1].iter().all(Result::is_ok));
+    }
+
+    #[tokio::test]
+    async fn test_async_profile_iter_resume() -> Result<(), CisClientError> {
+        let faker = CisClientFaker {
+            count: 3,
+            ..Default::default()
+        };
+        let mut iter = AsyncProfileIter::new(faker.clone(), None);
+        iter.next().await.transpose()?;
+        let token = iter.next_page().cloned().expect("next page");
+        let rest: Vec<Vec<Profile>> = AsyncProfileIter::resume_from(faker, None, token)
+            .try_collect()
+            .await?;
+        let uuids: Vec<_> = rest
+            .iter()
+            .flatten()
+            .filter_map(|p| p.uuid.value.clone())
+            .collect();
+        assert_eq!(uuids, vec!["2", "1"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_async_profile_iter_resume_token_checks_filter() -> Result<(), CisClientError> {
+        let faker = CisClientFaker {
+            count: 3,
+            ..Default::default()
+        };
+        let filter = Some(String::from("staff"));
+        let mut iter = AsyncProfileIter::new(faker.clone(), filter.clone());
+        assert!(iter.resume_token().is_none());
+        iter.next().await.transpose()?;
+        let token = iter.resume_token().expect("resume token");
+        assert_eq!(token.filter, filter);
+        match AsyncProfileIter::resume_from_token(faker.clone(), None, token.clone()) {
+            Err(CisClientError::ResumeFilterMismatch { token, filter }) => {
+                assert_eq!(token.as_deref(), Some("staff"));
+                assert_eq!(filter, None);
+            }
+            _ => panic!("expected ResumeFilterMismatch"),
+        }
+        let rest: Vec<Vec<Profile>> = AsyncProfileIter::resume_from_token(faker, filter, token)?
+            .try_collect()
+            .await?;
+        assert_eq!(rest.len(), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_async_profile_iter_size_hint() -> Result<(), CisClientError> {
+        let faker = CisClientFaker {
+            count: 3,
+            ..Default::default()
+        };
+        let iter = AsyncProfileIter::new(faker.clone(), None);
+        assert_eq!(iter.size_hint(), (0, None));
+        let iter = AsyncProfileIter::new(faker.clone(), None).with_expected_count(3);
+        assert_eq!(iter.size_hint(), (1, None));
+        let mut stream = iter.profiles_stream();
+        assert_eq!(stream.size_hint(), (3, None));
+        stream.try_next().await?;
+        assert_eq!(stream.size_hint(), (2, None));
+        let rest: Vec<Profile> = (&mut stream).try_collect().await?;
+        assert_eq!(rest.len(), 2);
+        assert_eq!(stream.size_hint(), (0, Some(0)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_get_all_users4() -> Result<(), CisClientError> {
+        let faker = CisClientFaker {
+            count: 4,
+            ..Default::default()
+        };
+        assert_eq!(faker.get_all_users(None).await?.len(), 4);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_async_profile_stream3() -> Result<(), CisClientError> {
+        let stream = AsyncProfileIter::new(
+            CisClientFaker {
+                count: 3,
+                ..Default::default()
+            },
+            None,
+        )
+        .profiles_stream();
+        let profiles: Vec<Profile> = stream.try_collect().await?;
+        assert_eq!(profiles.len(), 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_async_profile_iter_yields_batch_error() -> Result<(), CisClientError> {
+        let faker = CisClientFaker {
+            count: 3,
+            fail_at: Some(2),
+            ..Default::default()
+        };
+        let mut iter = AsyncProfileIter::new(faker, None);
+        assert!(matches!(iter.next().await, Some(Ok(_))));
+        assert!(matches!(
+            iter.next().await,
+            Some(Err(CisClientError::ApiError { status: 500, .. }))
+        ));
+        assert!(iter.next().await.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_async_profile_stream_yields_batch_error() -> Result<(), CisClientError> {
+        let faker = CisClientFaker {
+            count: 3,
+            fail_at: Some(2),
+            ..Default::default()
+        };
+        let results: Vec<_> = AsyncProfileIter::new(faker, None)
+            .profiles_stream()
+            .collect()
+            .await;
+        assert_eq!(results.len(), 2);
+        assert!(results[0].is_ok());
+        assert!(results[1].is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_async_profile_iter_against_users_endpoint() -> Result<(), CisClientError> {
+        let mut server = mockito::Server::new_async().await;
+        mock_token(&mut server).await;
+        let profile: Value = serde_json::from_str(&profile_body("some-uuid"))?;
+        let first = server
+            .mock("GET", "/v2/users")
+            .match_query(Matcher::Any)
+            .with_body(json!({ "Items": [profile], "nextPage": { "id": "2" } }).to_string())
+            .expect(1)
+            .create_async()
+            .await;
+        let second = server
+            .mock("GET", "/v2/users")
+            .match_query(Matcher::UrlEncoded(
+                "nextPage".into(),
+                r#"{"id":"2"}"#.into(),
+            ))
+            .with_body(json!({ "Items": [profile, profile] }).to_string())
+            .expect(1)
+            .create_async()
+            .await;
+        let client = CisClient::from_settings(&settings_for(&server)).await?;
+        let batches: Vec<Vec<Profile>> = client.get_users_iter(None).try_collect().await?;
+        assert_eq!(batches.len(), 2);
+        assert_eq!(batches[0].len(), 1);
+        assert_eq!(batches[1].len(), 2);
+        first.assert_async().await;
+        second.assert_async().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_async_profile_iter_ends_on_missing_token() -> Result<(), CisClientError> {
+        let mut server = mockito::Server::new_async().await;
+        mock_token(&mut server).await;
+        let profile: Value = serde_json::from_str(&profile_body("some-uuid"))?;
+        server
+            .mock("GET", "/v2/users")
+            .match_query(Matcher::Any)
+            .with_body(json!({ "Items": [], "nextPage": { "id": "2" } }).to_string())
+            .expect(1)
+            .create_async()
+            .await;
+        let last = server
+            .mock("GET", "/v2/users")
+            .match_query(Matcher::UrlEncoded(
+                "nextPage".into(),
+                r#"{"id":"2"}"#.into(),
+            ))
+            .with_body(json!({ "Items": [profile, profile] }).to_string())
+            .expect(1)
+            .create_async()
+            .await;
+        let client = CisClient::from_settings(&settings_for(&server)).await?;
+        let batches: Vec<Vec<Profile>> = client.get_users_iter(None).try_collect().await?;
+        assert_eq!(batches.len(), 2);
+        assert!(batches[0].is_empty());
+        assert_eq!(batches[1].len(), 2);
+        last.assert_async().await;
+        Ok(())
+    }
+}
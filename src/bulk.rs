@@ -0,0 +1,60 @@
+use crate::error::CisClientError;
+use cis_profile::schema::Profile;
+use serde_json::Value;
+
+/// Outcome of one chunk of a bulk update.
+#[derive(Debug)]
+pub struct ChunkResult {
+    /// `user_id` of each profile in the chunk, or its `uuid` if no `user_id`
+    /// is set.
+    pub ids: Vec<String>,
+    pub result: Result<Value, CisClientError>,
+}
+
+/// Outcomes of all chunks of a bulk update in submission order. A rejected
+/// chunk does not stop the remaining chunks from being sent, so only the
+/// profiles listed in [UpdateUsersResult::failed_ids] need to be retried.
+#[derive(Debug, Default)]
+pub struct UpdateUsersResult {
+    pub chunks: Vec<ChunkResult>,
+}
+
+impl UpdateUsersResult {
+    /// Whether every chunk was accepted.
+    pub fn is_ok(&self) -> bool {
+        self.chunks.iter().all(|chunk| chunk.result.is_ok())
+    }
+
+    /// Ids of all profiles in rejected chunks.
+    pub fn failed_ids(&self) -> Vec<&str> {
+        self.chunks
+            .iter()
+            .filter(|chunk| chunk.result.is_err())
+            .flat_map(|chunk| chunk.ids.iter().map(String::as_str))
+            .collect()
+    }
+
+    /// Responses of the accepted chunks.
+    pub fn responses(&self) -> Vec<&Value> {
+        self.chunks
+            .iter()
+            .filter_map(|chunk| chunk.result.as_ref().ok())
+            .collect()
+    }
+
+    pub(crate) fn push(&mut self, chunk: &[Profile], result: Result<Value, CisClientError>) {
+        self.chunks.push(ChunkResult {
+            ids: chunk.iter().map(profile_id).collect(),
+            result,
+        });
+    }
+}
+
+fn profile_id(profile: &Profile) -> String {
+    profile
+        .user_id
+        .value
+        .clone()
+        .or_else(|| profile.uuid.value.clone())
+        .unwrap_or_default()
+}
@@ -0,0 +1,69 @@
+use crate::error::CisClientError;
+use serde::Deserialize;
+use serde::Deserializer;
+use serde::Serialize;
+use serde_json::Map;
+use serde_json::Value;
+
+/// Response of Change API to a single profile write.
+///
+/// All fields are optional as Change API deployments differ in what they
+/// report. Fields this crate does not know are kept in `other`.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct ChangeResponse {
+    /// Status reported in the body, e.g. `200` or `created`. Numbers are
+    /// converted to strings.
+    #[serde(default, deserialize_with = "string_or_number")]
+    pub status: Option<String>,
+    /// `uuid` of the written profile.
+    #[serde(default)]
+    pub uuid: Option<String>,
+    #[serde(default)]
+    pub message: Option<String>,
+    #[serde(flatten)]
+    pub other: Map<String, Value>,
+}
+
+impl ChangeResponse {
+    pub(crate) fn from_json(json: Value) -> Result<Self, CisClientError> {
+        serde_json::from_value(json).map_err(Into::into)
+    }
+}
+
+fn string_or_number<'de, D: Deserializer<'de>>(d: D) -> Result<Option<String>, D::Error> {
+    Ok(match Option::<Value>::deserialize(d)? {
+        Some(Value::String(s)) => Some(s),
+        Some(Value::Number(n)) => Some(n.to_string()),
+        Some(other) => {
+            return Err(serde::de::Error::custom(format!(
+                "expected string or number as status, got {}",
+                other
+            )))
+        }
+        None => None,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use anyhow::Error;
+    use serde_json::json;
+
+    #[test]
+    fn deserialize_change_response() -> Result<(), Error> {
+        let res = ChangeResponse::from_json(json!({
+            "status": 200,
+            "message": "Dynamo updated.",
+            "uuid": "some-uuid",
+            "sequence_number": "1234",
+        }))?;
+        assert_eq!(res.status.as_deref(), Some("200"));
+        assert_eq!(res.uuid.as_deref(), Some("some-uuid"));
+        assert_eq!(res.message.as_deref(), Some("Dynamo updated."));
+        assert_eq!(res.other["sequence_number"], "1234");
+        assert_eq!(ChangeResponse::from_json(json!({}))?, Default::default());
+        assert!(ChangeResponse::from_json(json!({ "status": [] })).is_err());
+        Ok(())
+    }
+}
@@ -1,54 +1,171 @@
 use crate::auth::Auth0;
 use crate::auth::BearerBearer;
-use crate::encoding::USERINFO_ENCODE_SET;
+use crate::batch::modified_since_param;
+use crate::batch::AsyncProfileIter;
+use crate::batch::Batch;
+use crate::batch::NextPage;
+use crate::builder::CisClientBuilder;
+use crate::bulk::UpdateUsersResult;
+use crate::change::ChangeResponse;
+use crate::diff;
+use crate::encoding::encode_id;
 use crate::error::CisClientError;
 use crate::error::ProfileError;
+use crate::getby::ActiveFilter;
 use crate::getby::GetBy;
-use crate::secrets::get_store_from_settings;
+use crate::metrics::CisMetrics;
+use crate::retry;
+use crate::retry::RetryConfig;
+use crate::schema::profile_from_json;
+use crate::secrets::get_store_from_keys;
 use crate::settings::CisSettings;
+use crate::settings::Keys;
+use crate::signing;
+use crate::streaming;
+use chrono::DateTime;
+use chrono::Utc;
 use cis_profile::crypto::SecretStore;
 use cis_profile::schema::Profile;
+use cis_profile::schema::PublisherAuthority;
 use futures::future;
-use futures::future::FutureExt;
 use futures::future::TryFutureExt;
+use futures::stream;
+use futures::stream::StreamExt;
+use futures::stream::TryStreamExt;
 use futures::Future;
-use percent_encoding::utf8_percent_encode;
+use reqwest::header::ACCEPT;
 use reqwest::Client;
+use reqwest::Method;
+use reqwest::RequestBuilder;
 use reqwest::Response;
 use reqwest::Url;
 use serde::de::DeserializeOwned;
 use serde::Serialize;
 use serde_json::Value;
 use shared_expiry_get::RemoteStore;
+use std::collections::HashMap;
 use std::pin::Pin;
 use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::RwLock;
+use std::sync::Weak;
+use std::time::Duration;
+use std::time::Instant;
+use tokio::sync::Semaphore;
+use tokio::sync::SemaphorePermit;
 
-static DEFAULT_BATCH_SIZE: usize = 25;
+fn swap_secret_store(current: &RwLock<Arc<SecretStore>>, secret_store: SecretStore) {
+    let mut current = match current.write() {
+        Ok(current) => current,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    *current = Arc::new(secret_store);
+}
+
+/// Rebuild the [SecretStore] behind `current` every `interval` until all
+/// clients sharing it are dropped. Failed reloads keep the current store.
+/// The first reload is due `interval` after this is called, not after the
+/// returned future is first polled.
+pub(crate) fn reload_secret_store_every(
+    current: Weak<RwLock<Arc<SecretStore>>>,
+    sign_keys: Keys,
+    verify_keys: Keys,
+    interval: Duration,
+) -> impl Future<Output = ()> {
+    let mut ticks = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+    async move {
+        loop {
+            ticks.tick().await;
+            if current.strong_count() == 0 {
+                return;
+            }
+            let secret_store = get_store_from_keys(&sign_keys, &verify_keys).await;
+            let current = match current.upgrade() {
+                Some(current) => current,
+                None => return,
+            };
+            match secret_store {
+                Ok(secret_store) => swap_secret_store(&current, secret_store),
+                Err(e) => log::warn!("keeping secret store, reload failed: {}", e),
+            }
+        }
+    }
+}
+
+/// Number of profiles [CisClient::get_users_by_ids] fetches at a time.
+const GET_USERS_BY_IDS_CONCURRENCY: usize = 8;
+
+/// Run `fut` in an info span built from the remaining arguments when the
+/// `tracing` feature is enabled.
+macro_rules! traced {
+    ($fut:expr, $($span:tt)+) => {{
+        #[cfg(feature = "tracing")]
+        let fut = {
+            let span = tracing::info_span!($($span)+);
+            tracing::Instrument::instrument($fut, span)
+        };
+        #[cfg(not(feature = "tracing"))]
+        let fut = $fut;
+        fut
+    }};
+}
+
+type ScopedStores = HashMap<String, (Auth0, RemoteStore<BearerBearer, Auth0>)>;
+
+/// Result of [CisClient::health].
+#[derive(Clone, Debug)]
+pub struct HealthReport {
+    /// A bearer token could be obtained.
+    pub auth_ok: bool,
+    pub auth_latency: Duration,
+    /// Person API answered an authenticated request.
+    pub person_api_ok: bool,
+    /// `None` if Person API was not probed because no token was available.
+    pub person_api_latency: Option<Duration>,
+}
 
 #[derive(Clone)]
 pub struct CisClient {
     pub bearer_store: RemoteStore<BearerBearer, Auth0>,
-    pub person_api_user_endpoint: Url,
-    pub person_api_users_endpoint: Url,
-    pub change_api_user_endpoint: Url,
-    pub change_api_users_endpoint: Url,
+    pub(crate) auth0: Auth0,
+    /// Token stores of [CisClient::scoped] clients by scopes.
+    pub(crate) scoped_stores: Arc<Mutex<ScopedStores>>,
+    pub(crate) token_expiry: Arc<RwLock<Option<DateTime<Utc>>>>,
+    pub(crate) person_api_user_endpoint: Url,
+    pub(crate) person_api_users_endpoint: Url,
+    pub(crate) change_api_user_endpoint: Url,
+    pub(crate) change_api_users_endpoint: Url,
+    /// [SecretStore] the client was built with. Reloads do not replace it,
+    /// use [CisClient::current_secret_store] for the store in use.
     pub secret_store: Arc<SecretStore>,
-    pub batch_size: usize,
+    pub(crate) current_secret_store: Arc<RwLock<Arc<SecretStore>>>,
+    /// Whether the store was loaded from keys and can be reloaded.
+    pub(crate) reloadable_secret_store: bool,
+    pub(crate) sign_keys: Keys,
+    pub(crate) verify_keys: Keys,
+    pub(crate) batch_size: usize,
+    pub(crate) retry_config: RetryConfig,
+    pub(crate) http_client: Client,
+    pub(crate) metrics: Arc<dyn CisMetrics>,
+    pub(crate) limiter: Option<Arc<Semaphore>>,
+    pub(crate) validate_signatures: bool,
+    pub(crate) stream_batches: bool,
+    pub(crate) omit_active_param: bool,
+    pub(crate) strict_profiles: bool,
+    pub(crate) idempotency_keys: bool,
+    pub(crate) schema_version: Option<String>,
+    pub(crate) read_timeout: Option<Duration>,
+    pub(crate) deadline: Option<Duration>,
+    pub(crate) max_response_bytes: Option<usize>,
+    #[cfg(feature = "sync")]
+    pub(crate) sync_http_client: reqwest::blocking::Client,
 }
 
 impl CisClient {
     pub async fn from_settings(settings: &CisSettings) -> Result<Self, CisClientError> {
-        let bearer_store = RemoteStore::new(Auth0::new(settings.client_config.clone()));
-        let secret_store = get_store_from_settings(settings).await?;
-        Ok(CisClient {
-            bearer_store,
-            person_api_user_endpoint: settings.person_api_user_endpoint.clone(),
-            person_api_users_endpoint: settings.person_api_users_endpoint.clone(),
-            change_api_user_endpoint: settings.change_api_user_endpoint.clone(),
-            change_api_users_endpoint: settings.change_api_users_endpoint.clone(),
-            secret_store: Arc::new(secret_store),
-            batch_size: DEFAULT_BATCH_SIZE,
-        })
+        CisClientBuilder::from_settings(settings)
+            .build_async()
+            .await
     }
     #[cfg(feature = "sync")]
     pub fn from_settings_sync(settings: &CisSettings) -> Result<Self, CisClientError> {
@@ -57,17 +174,348 @@ impl CisClient {
         rt.block_on(Self::from_settings(settings))
     }
 
+    /// Create a client using a pre-acquired bearer token. No token is fetched
+    /// from Auth0 until `exp` has passed.
+    pub async fn with_static_token(
+        token: String,
+        exp: DateTime<Utc>,
+        settings: &CisSettings,
+    ) -> Result<Self, CisClientError> {
+        CisClientBuilder::from_settings(settings)
+            .static_token(token, exp)
+            .build_async()
+            .await
+    }
+
+    /// Set the number of profiles per page and per bulk update chunk.
+    pub fn with_batch_size(mut self, batch_size: usize) -> Result<Self, CisClientError> {
+        if batch_size == 0 {
+            return Err(CisClientError::InvalidBatchSize);
+        }
+        self.batch_size = batch_size;
+        Ok(self)
+    }
+
+    /// Use `http_client` for all Person and Change API requests. Settings
+    /// like the builder timeout do not apply to an injected client.
+    pub fn with_http_client(mut self, http_client: Client) -> Self {
+        self.http_client = http_client;
+        self
+    }
+
+    /// Blocking counterpart of [CisClient::with_http_client].
+    #[cfg(feature = "sync")]
+    pub fn with_sync_http_client(mut self, http_client: reqwest::blocking::Client) -> Self {
+        self.sync_http_client = http_client;
+        self
+    }
+
+    /// Person API endpoint for single user lookups, always ending in `/`.
+    pub fn person_api_user_endpoint(&self) -> &Url {
+        &self.person_api_user_endpoint
+    }
+
+    /// Person API endpoint for listing users.
+    pub fn person_api_users_endpoint(&self) -> &Url {
+        &self.person_api_users_endpoint
+    }
+
+    /// Change API endpoint for single user changes.
+    pub fn change_api_user_endpoint(&self) -> &Url {
+        &self.change_api_user_endpoint
+    }
+
+    /// Change API endpoint for bulk changes.
+    pub fn change_api_users_endpoint(&self) -> &Url {
+        &self.change_api_users_endpoint
+    }
+
+    /// Page size for listing users and chunk size for bulk changes.
+    pub fn batch_size(&self) -> usize {
+        self.batch_size
+    }
+
+    /// Retry policy for Person and Change API requests.
+    pub fn retry_config(&self) -> &RetryConfig {
+        &self.retry_config
+    }
+
+    /// Rebuild the [SecretStore] from the configured sign and verify keys and
+    /// swap it in. Requests already holding the previous store keep using it.
+    /// Fails for a store passed to [CisClientBuilder::secret_store].
+    pub async fn reload_secret_store(&self) -> Result<(), CisClientError> {
+        if !self.reloadable_secret_store {
+            return Err(CisClientError::SecretStoreNotReloadable);
+        }
+        let secret_store = get_store_from_keys(&self.sign_keys, &self.verify_keys).await?;
+        swap_secret_store(&self.current_secret_store, secret_store);
+        Ok(())
+    }
+
+    fn request_context(&self) -> RequestContext {
+        RequestContext {
+            http_client: self.http_client.clone(),
+            bearer_store: self.bearer_store.clone(),
+            retry_config: self.retry_config.clone(),
+            metrics: Arc::clone(&self.metrics),
+            limiter: self.limiter.clone(),
+            read_timeout: self.read_timeout,
+            deadline: self.deadline,
+            max_response_bytes: self.max_response_bytes,
+            idempotency_keys: self.idempotency_keys,
+            schema_version: self.schema_version.clone(),
+        }
+    }
+
+    /// Snapshot of the [SecretStore] used for signing and verification,
+    /// including the keys of the last reload.
+    pub fn current_secret_store(&self) -> Arc<SecretStore> {
+        match self.current_secret_store.read() {
+            Ok(secret_store) => Arc::clone(&secret_store),
+            Err(poisoned) => Arc::clone(&poisoned.into_inner()),
+        }
+    }
+
+    /// Check the signatures of `profile` if enabled via
+    /// [CisClientBuilder::validate_signatures].
+    pub(crate) fn check_signatures(&self, profile: &Profile) -> Result<(), CisClientError> {
+        if self.validate_signatures {
+            signing::check_signatures(&self.current_secret_store(), profile)?;
+        }
+        Ok(())
+    }
+
+    /// Sign all attributes of `profile` published by `realm` with the loaded
+    /// sign key for that realm.
+    pub fn sign_profile(
+        &self,
+        profile: &mut Profile,
+        realm: PublisherAuthority,
+    ) -> Result<(), CisClientError> {
+        signing::sign_profile(&self.current_secret_store(), profile, realm).map_err(Into::into)
+    }
+
+    /// Client requesting tokens for `scopes` instead of the configured scopes,
+    /// e.g. to use a read-only token for lookups.
+    ///
+    /// Tokens are cached per scope string and the cache is shared by all
+    /// clones of this client, so repeated calls reuse the same token.
+    pub fn scoped(&self, scopes: &str) -> CisClient {
+        if scopes == self.auth0.config.scopes {
+            return self.clone();
+        }
+        let (auth0, bearer_store) = {
+            let mut stores = match self.scoped_stores.lock() {
+                Ok(stores) => stores,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            stores
+                .entry(scopes.to_owned())
+                .or_insert_with(|| {
+                    let auth0 = self.auth0.with_scopes(scopes);
+                    (auth0.clone(), RemoteStore::new(auth0))
+                })
+                .clone()
+        };
+        CisClient {
+            token_expiry: Arc::clone(&auth0.expiry),
+            auth0,
+            bearer_store,
+            ..self.clone()
+        }
+    }
+
+    /// Fetch the profile with the user id `id`, active or not, set its `active`
+    /// flag and submit it to Change API.
+    ///
+    /// The flag is re-signed with the key of its publisher. Nothing is
+    /// submitted if no such key is loaded.
+    pub fn set_user_active(&self, id: &str, active: bool) -> CisFut<ChangeResponse> {
+        let client = self.clone();
+        let id = id.to_owned();
+        Box::pin(async move {
+            let mut profile = client.get_any_user_by(&id, &GetBy::UserId, None).await?;
+            profile.active.value = Some(active);
+            signing::sign_active(&client.current_secret_store(), &mut profile)?;
+            client.update_user(&id, profile).await
+        })
+    }
+
+    /// Submit only the attributes of `modified` differing from `original`, the
+    /// profile of the user `id` as fetched, to Change API.
+    ///
+    /// Changed attributes are re-signed with the key of their publisher, so
+    /// those keys have to be loaded. Nested attributes such as
+    /// `staff_information.title` are signed individually and submitted with
+    /// their parent. Changes to fields without a signature, such as `schema`,
+    /// fail with [ProfileError::UnsignableAttribute]. Sending only changes
+    /// keeps the payload small and does not overwrite attributes modified
+    /// elsewhere since `original` was fetched. Resolves to an empty [ChangeResponse] without a request if
+    /// nothing changed.
+    pub fn update_user_changes(
+        &self,
+        id: &str,
+        original: &Profile,
+        modified: Profile,
+    ) -> CisFut<ChangeResponse> {
+        self.submit_changes(id, original, modified, None)
+    }
+
+    /// Like [CisClient::update_user_changes] but signs the changed attributes
+    /// with the key of `realm`, making it their publisher. Fails with
+    /// [ProfileError::MissingSignKey] if no key for `realm` is loaded.
+    pub fn update_user_changes_as(
+        &self,
+        id: &str,
+        original: &Profile,
+        modified: Profile,
+        realm: PublisherAuthority,
+    ) -> CisFut<ChangeResponse> {
+        self.submit_changes(id, original, modified, Some(realm))
+    }
+
+    fn submit_changes(
+        &self,
+        id: &str,
+        original: &Profile,
+        mut modified: Profile,
+        realm: Option<PublisherAuthority>,
+    ) -> CisFut<ChangeResponse> {
+        let url = match self.change_api_user_url(id) {
+            Ok(url) => url,
+            Err(e) => return Box::pin(future::err(e)),
+        };
+        let secret_store = self.current_secret_store();
+        let payload = match diff::signed_changes(&secret_store, original, &mut modified, realm) {
+            Ok(Some(payload)) => payload,
+            Ok(None) => return Box::pin(future::ok(ChangeResponse::default())),
+            Err(e) => return Box::pin(future::err(e)),
+        };
+        Box::pin(traced!(
+            post(self.request_context(), "update_user", url.clone(), payload)
+                .and_then(change_response),
+            "update_user_changes",
+            host = url.host_str().unwrap_or_default()
+        ))
+    }
+
+    /// Fetch the profile of `id` looked up by `by`, active or not, and submit
+    /// its deletion to Change API.
+    ///
+    /// The profile is submitted as fetched, so its signatures stay valid and
+    /// nothing needs to be re-signed. A missing profile fails with
+    /// [ProfileError::ProfileDoesNotExist] without a delete request, a profile
+    /// without `user_id` with [CisClientError::MissingUserId].
+    pub fn delete_user_by_id(&self, id: &str, by: &GetBy) -> CisFut<ChangeResponse> {
+        let client = self.clone();
+        let id = id.to_owned();
+        let by = *by;
+        Box::pin(async move {
+            let profile = client.get_any_user_by(&id, &by, None).await?;
+            let user_id = match profile.user_id.value.clone() {
+                Some(user_id) => user_id,
+                None if by == GetBy::UserId => id,
+                None => return Err(CisClientError::MissingUserId { id, by }),
+            };
+            client.delete_user(&user_id, profile).await
+        })
+    }
+
+    /// Expiry of the cached token or `None` if no token was fetched yet.
+    /// This never triggers a token refresh.
+    pub fn token_expiry(&self) -> Option<DateTime<Utc>> {
+        match self.token_expiry.read() {
+            Ok(expiry) => *expiry,
+            Err(poisoned) => *poisoned.into_inner(),
+        }
+    }
+
+    /// Remaining lifetime of the cached token, negative once expired.
+    /// `None` if no token was fetched yet.
+    pub fn token_ttl(&self) -> Option<chrono::Duration> {
+        self.token_expiry().map(|exp| exp - Utc::now())
+    }
+
+    /// Fetch a fresh token from the token endpoint, bypassing the cache, to
+    /// check that the configured credentials are accepted. The cached token
+    /// used for requests and [CisClient::token_expiry] are left untouched.
+    pub fn check_auth(&self) -> CisFut<()> {
+        let auth0 = self.auth0.clone();
+        Box::pin(async move {
+            auth0.fetch_token().await?;
+            Ok(())
+        })
+    }
+
+    /// Probe the token endpoint and Person API for readiness checks. Failures
+    /// are reported in the [HealthReport] instead of as an error. The probe
+    /// uses the cached token and is never retried.
+    pub fn health(&self) -> CisFut<HealthReport> {
+        let ctx = RequestContext {
+            retry_config: RetryConfig::none(),
+            ..self.request_context()
+        };
+        let mut url = self.person_api_users_endpoint.clone();
+        url.query_pairs_mut().append_pair("nextPageSize", "1");
+        Box::pin(async move {
+            let start = Instant::now();
+            let auth_ok = ctx.bearer_store.get().await.is_ok();
+            let auth_latency = start.elapsed();
+            if !auth_ok {
+                return Ok(HealthReport {
+                    auth_ok,
+                    auth_latency,
+                    person_api_ok: false,
+                    person_api_latency: None,
+                });
+            }
+            let start = Instant::now();
+            let person_api_ok = send::<Value>(ctx, "health", url).await.is_ok();
+            Ok(HealthReport {
+                auth_ok,
+                auth_latency,
+                person_api_ok,
+                person_api_latency: Some(start.elapsed()),
+            })
+        })
+    }
+
+    /// The access token used for Person and Change API requests, e.g. to call
+    /// other services sharing the same audience. A cached token is refreshed
+    /// first if it is about to expire.
     pub async fn bearer_token(&self) -> Result<String, CisClientError> {
         let b = self.bearer_store.get().await?;
         Ok((*b.bearer_token_str).to_owned())
     }
 
+    /// Like [CisClient::bearer_token] together with the expiry of the token
+    /// taken from its `exp` claim.
+    pub async fn bearer_token_with_expiry(
+        &self,
+    ) -> Result<(String, DateTime<Utc>), CisClientError> {
+        let b = self.bearer_store.get().await?;
+        Ok(((*b.bearer_token_str).to_owned(), *b.exp))
+    }
+
+    /// Blocking [CisClient::bearer_token].
     #[cfg(feature = "sync")]
     pub fn bearer_token_sync(&self) -> Result<String, CisClientError> {
         use tokio::runtime::Runtime;
         let rt = Runtime::new().map_err(|_| CisClientError::RuntimeError)?;
         rt.block_on(self.bearer_token())
     }
+
+    /// Blocking [CisClient::bearer_token_with_expiry].
+    #[cfg(feature = "sync")]
+    pub fn bearer_token_with_expiry_sync(&self) -> Result<(String, DateTime<Utc>), CisClientError> {
+        use tokio::runtime::Runtime;
+        let rt = Runtime::new().map_err(|_| CisClientError::RuntimeError)?;
+        rt.block_on(self.bearer_token_with_expiry())
+    }
+}
+
+fn change_response(json: Value) -> future::Ready<Result<ChangeResponse, CisClientError>> {
+    future::ready(ChangeResponse::from_json(json))
 }
 
 pub type CisFut<T> = Pin<Box<dyn Future<Output = Result<T, CisClientError>> + Send>>;
@@ -76,135 +524,2655 @@ pub trait AsyncCisClientTrait {
     fn get_user_by(&self, id: &str, by: &GetBy, filter: Option<&str>) -> CisFut<Profile>;
     fn get_inactive_user_by(&self, id: &str, by: &GetBy, filter: Option<&str>) -> CisFut<Profile>;
     fn get_any_user_by(&self, id: &str, by: &GetBy, filter: Option<&str>) -> CisFut<Profile>;
-    fn update_user(&self, id: &str, profile: Profile) -> CisFut<Value>;
-    fn update_users(&self, profiles: &[Profile]) -> CisFut<Value>;
-    fn delete_user(&self, id: &str, profile: Profile) -> CisFut<Value>;
+    /// Like [AsyncCisClientTrait::get_user_by] but returns the untyped JSON and
+    /// does not check whether the profile exists.
+    fn get_user_raw_by(&self, id: &str, by: &GetBy, filter: Option<&str>) -> CisFut<Value>;
+    fn get_users_iter(&self, filter: Option<&str>) -> AsyncProfileIter<Self>
+    where
+        Self: Sized;
+    fn get_batch(&self, next_page: &Option<NextPage>, filter: &Option<String>) -> CisFut<Batch>;
+    /// Like [AsyncCisClientTrait::get_batch] only fetching profiles modified
+    /// at or after `since`. The default implementation ignores `since`.
+    fn get_batch_modified_since(
+        &self,
+        next_page: &Option<NextPage>,
+        filter: &Option<String>,
+        _since: DateTime<Utc>,
+    ) -> CisFut<Batch> {
+        self.get_batch(next_page, filter)
+    }
+    /// Fetch all matching profiles, failing on the first batch error.
+    ///
+    /// This holds every profile in memory at once. Use
+    /// [AsyncCisClientTrait::get_users_iter] to process large exports page by page.
+    fn get_all_users(&self, filter: Option<&str>) -> CisFut<Vec<Profile>>
+    where
+        Self: Sized + Unpin + Send + 'static,
+    {
+        Box::pin(self.get_users_iter(filter).try_concat())
+    }
+    /// Number of users matching `filter` as reported in the `total` field of
+    /// the `/users` response. Fails with [CisClientError::CountUnavailable] if
+    /// the server does not report a total.
+    fn get_user_count(&self, filter: Option<&str>) -> CisFut<usize>;
+    fn create_user(&self, profile: Profile) -> CisFut<ChangeResponse> {
+        Box::pin(self.create_user_raw(profile).and_then(change_response))
+    }
+    /// Like [AsyncCisClientTrait::create_user] but returns the untyped JSON.
+    fn create_user_raw(&self, profile: Profile) -> CisFut<Value>;
+    fn update_user(&self, id: &str, profile: Profile) -> CisFut<ChangeResponse> {
+        Box::pin(self.update_user_raw(id, profile).and_then(change_response))
+    }
+    /// Like [AsyncCisClientTrait::update_user] but returns the untyped JSON.
+    fn update_user_raw(&self, id: &str, profile: Profile) -> CisFut<Value>;
+    /// Update profiles in chunks of `batch_size`. Every chunk is sent, the
+    /// result records which profiles were rejected.
+    fn update_users(&self, profiles: &[Profile]) -> CisFut<UpdateUsersResult>;
+    fn delete_user(&self, id: &str, profile: Profile) -> CisFut<ChangeResponse> {
+        Box::pin(self.delete_user_raw(id, profile).and_then(change_response))
+    }
+    /// Like [AsyncCisClientTrait::delete_user] but returns the untyped JSON.
+    fn delete_user_raw(&self, id: &str, profile: Profile) -> CisFut<Value>;
+    /// Delete profiles in chunks of `batch_size`, stopping at the first failed
+    /// chunk. Resolves to the responses of all chunks.
+    fn delete_users(&self, profiles: &[Profile]) -> CisFut<Value>;
     fn get_secret_store(&self) -> &SecretStore;
 }
 
-async fn send<T: DeserializeOwned>(
+/// Everything a request needs, cloned out of [CisClient] so request futures
+/// don't borrow the client.
+#[derive(Clone)]
+struct RequestContext {
+    http_client: Client,
     bearer_store: RemoteStore<BearerBearer, Auth0>,
+    retry_config: RetryConfig,
+    metrics: Arc<dyn CisMetrics>,
+    limiter: Option<Arc<Semaphore>>,
+    read_timeout: Option<Duration>,
+    deadline: Option<Duration>,
+    max_response_bytes: Option<usize>,
+    idempotency_keys: bool,
+    schema_version: Option<String>,
+}
+
+impl RequestContext {
+    /// Run a whole API call within the configured deadline.
+    async fn within_deadline<T>(
+        &self,
+        call: impl Future<Output = Result<T, CisClientError>>,
+    ) -> Result<T, CisClientError> {
+        match self.deadline {
+            Some(deadline) => tokio::time::timeout(deadline, call)
+                .await
+                .map_err(|_| CisClientError::DeadlineExceeded)?,
+            None => call.await,
+        }
+    }
+
+    /// Read a response body within the configured read timeout.
+    async fn read<T>(
+        &self,
+        body: impl Future<Output = Result<T, reqwest::Error>>,
+    ) -> Result<T, CisClientError> {
+        read_within(self.read_timeout, body).await
+    }
+
+    /// Parse a JSON response body within the configured read timeout and
+    /// size limit.
+    async fn json<T: DeserializeOwned>(&self, mut res: Response) -> Result<T, CisClientError> {
+        let status = res.status().as_u16();
+        let max = match self.max_response_bytes {
+            Some(max) => max,
+            None => return json_body(status, &self.read(res.bytes()).await?),
+        };
+        let mut body = Vec::new();
+        while let Some(chunk) = self.read(res.chunk()).await? {
+            if body.len() + chunk.len() > max {
+                return Err(CisClientError::ResponseTooLarge(max));
+            }
+            body.extend_from_slice(&chunk);
+        }
+        json_body(status, &body)
+    }
+
+    /// Ask for the configured schema version if any.
+    fn api_request(&self, req: RequestBuilder) -> RequestBuilder {
+        match &self.schema_version {
+            Some(version) => req.header(ACCEPT, schema_version_accept(version)),
+            None => req,
+        }
+    }
+
+    /// Add a fresh idempotency key to a write if enabled.
+    fn write_request(&self, req: RequestBuilder) -> RequestBuilder {
+        let req = self.api_request(req);
+        if self.idempotency_keys {
+            return req.header(retry::IDEMPOTENCY_KEY, retry::idempotency_key());
+        }
+        req
+    }
+
+    /// Limits the number of concurrent requests if configured.
+    fn limiter(&self) -> Option<&Semaphore> {
+        self.limiter.as_deref()
+    }
+}
+
+async fn send<T: DeserializeOwned>(
+    ctx: RequestContext,
+    op: &'static str,
     url: Url,
 ) -> Result<T, CisClientError> {
-    log::debug!("getting token");
-    let token = bearer_store.get().await?;
-    log::debug!("got token");
-    let res = Client::new()
-        .get(url)
-        .bearer_auth(token.bearer_token_str)
-        .send()
-        .err_into()
-        .map(flatten_status)
+    ctx.within_deadline(async {
+        log::debug!("getting token");
+        let token = ctx.bearer_store.get().await?;
+        log::debug!("got token");
+        let req = ctx.api_request(ctx.http_client.get(url).bearer_auth(token.bearer_token_str));
+        let (res, _permit) = execute(
+            &ctx.retry_config,
+            ctx.limiter(),
+            &*ctx.metrics,
+            op,
+            Method::GET,
+            req,
+        )
+        .await?;
+        ctx.json(res).await
+    })
+    .await
+}
+
+/// Like [send] but parses the response as `/users` page while it is received.
+async fn send_streaming_batch(
+    ctx: RequestContext,
+    op: &'static str,
+    url: Url,
+    strict: bool,
+) -> Result<Batch, CisClientError> {
+    ctx.within_deadline(async {
+        let token = ctx.bearer_store.get().await?;
+        let req = ctx.api_request(ctx.http_client.get(url).bearer_auth(token.bearer_token_str));
+        let (res, _permit) = execute(
+            &ctx.retry_config,
+            ctx.limiter(),
+            &*ctx.metrics,
+            op,
+            Method::GET,
+            req,
+        )
         .await?;
-    res.json().err_into().await
+        streaming::read_batch(res, ctx.read_timeout, ctx.max_response_bytes, strict).await
+    })
+    .await
 }
 
 async fn post<T: DeserializeOwned>(
-    bearer_store: RemoteStore<BearerBearer, Auth0>,
+    ctx: RequestContext,
+    op: &'static str,
     url: Url,
     payload: impl Serialize,
 ) -> Result<T, CisClientError> {
-    let token = bearer_store.get().await?;
-    let res = Client::new()
-        .post(url.as_str())
-        .json(&payload)
-        .bearer_auth(token.bearer_token_str)
-        .send()
-        .err_into()
-        .map(flatten_status)
+    ctx.within_deadline(async {
+        let token = ctx.bearer_store.get().await?;
+        let req = ctx.write_request(
+            ctx.http_client
+                .post(url.as_str())
+                .json(&payload)
+                .bearer_auth(token.bearer_token_str),
+        );
+        let retry_config = ctx.retry_config.for_write(ctx.idempotency_keys);
+        let (res, _permit) = execute(
+            &retry_config,
+            ctx.limiter(),
+            &*ctx.metrics,
+            op,
+            Method::POST,
+            req,
+        )
         .await?;
-    res.json().err_into().await
+        ctx.json(res).await
+    })
+    .await
 }
 
 async fn delete<T: DeserializeOwned>(
-    bearer_store: RemoteStore<BearerBearer, Auth0>,
+    ctx: RequestContext,
+    op: &'static str,
     url: Url,
     payload: impl Serialize,
 ) -> Result<T, CisClientError> {
-    let token = bearer_store.get().await?;
-    let res = Client::new()
-        .delete(url.as_str())
-        .json(&payload)
-        .bearer_auth(token.bearer_token_str)
-        .send()
-        .err_into()
-        .map(flatten_status)
+    ctx.within_deadline(async {
+        let token = ctx.bearer_store.get().await?;
+        let req = ctx.write_request(
+            ctx.http_client
+                .delete(url.as_str())
+                .json(&payload)
+                .bearer_auth(token.bearer_token_str),
+        );
+        let retry_config = ctx.retry_config.for_write(ctx.idempotency_keys);
+        let (res, _permit) = execute(
+            &retry_config,
+            ctx.limiter(),
+            &*ctx.metrics,
+            op,
+            Method::DELETE,
+            req,
+        )
         .await?;
-    res.json().err_into().await
+        ctx.json(res).await
+    })
+    .await
+}
+
+/// `Accept` header value selecting profile schema `version`.
+pub(crate) fn schema_version_accept(version: &str) -> String {
+    format!("application/json; version={}", version)
+}
+
+/// Send `req` with retries reporting the outcome to `metrics`. A slot of
+/// `limiter` is held per attempt, the one of the final attempt is returned to
+/// be held while the body is read.
+async fn execute<'a>(
+    retry_config: &RetryConfig,
+    limiter: Option<&'a Semaphore>,
+    metrics: &dyn CisMetrics,
+    op: &'static str,
+    method: Method,
+    req: RequestBuilder,
+) -> Result<(Response, Option<SemaphorePermit<'a>>), CisClientError> {
+    let permit = retry::acquire(limiter).await;
+    metrics.on_request(op, &method);
+    let start = Instant::now();
+    let res = match retry::send_limited(retry_config, limiter, permit, req).await {
+        Ok((res, permit)) => check_status(res).await.map(|res| (res, permit)),
+        Err(e) => Err(request_error(e)),
+    };
+    let status = match &res {
+        Ok((res, _)) => Some(res.status().as_u16()),
+        Err(e) => api_status(e),
+    };
+    metrics.on_response(op, status, start.elapsed());
+    res
+}
+
+/// Await `body` failing with [CisClientError::ReadTimeout] after `read_timeout`.
+pub(crate) async fn read_within<T>(
+    read_timeout: Option<Duration>,
+    body: impl Future<Output = Result<T, reqwest::Error>>,
+) -> Result<T, CisClientError> {
+    match read_timeout {
+        Some(read_timeout) => tokio::time::timeout(read_timeout, body)
+            .await
+            .map_err(|_| CisClientError::ReadTimeout)?
+            .map_err(Into::into),
+        None => body.await.map_err(Into::into),
+    }
+}
+
+/// Report connect timeouts as [CisClientError::ConnectTimeout].
+pub(crate) fn request_error(e: reqwest::Error) -> CisClientError {
+    if e.is_connect() && e.is_timeout() {
+        return CisClientError::ConnectTimeout;
+    }
+    e.into()
+}
+
+/// Parse a response body. Bodies that are no JSON at all, e.g. HTML pages
+/// of a gateway, fail with [CisClientError::InvalidResponse] keeping the text.
+pub(crate) fn json_body<T: DeserializeOwned>(
+    status: u16,
+    body: &[u8],
+) -> Result<T, CisClientError> {
+    let json: Value =
+        serde_json::from_slice(body).map_err(|_| CisClientError::InvalidResponse {
+            status,
+            body: String::from_utf8_lossy(body).into_owned(),
+        })?;
+    serde_json::from_value(json).map_err(Into::into)
+}
+
+pub(crate) fn api_status(e: &CisClientError) -> Option<u16> {
+    match e {
+        CisClientError::ApiError { status, .. } => Some(*status),
+        _ => None,
+    }
 }
 
 impl CisClient {
-    fn get_user(
+    pub(crate) fn get_user_url(
         &self,
         id: &str,
         by: &GetBy,
         filter: Option<&str>,
-        active: Option<bool>,
-    ) -> CisFut<Profile> {
-        let active = match active {
-            None => String::from("any"),
-            Some(b) => b.to_string(),
+        active: ActiveFilter,
+    ) -> Result<Url, CisClientError> {
+        let safe_id = encode_id(id)?;
+        // build the path explicitly, joining would drop the last segment of a
+        // base without trailing slash
+        let mut url = self.person_api_user_endpoint.clone();
+        let path = format!(
+            "{}/{}{}",
+            url.path().trim_end_matches('/'),
+            by.as_str(),
+            safe_id
+        );
+        url.set_path(&path);
+        url.set_query(None);
+        if let Some(df) = filter {
+            url.query_pairs_mut().append_pair("filterDisplay", df);
+        }
+        if !self.omit_active_param {
+            url.query_pairs_mut().append_pair("active", active.as_str());
+        }
+        Ok(url)
+    }
+
+    /// Change API endpoint for writes to the user `id`.
+    pub(crate) fn change_api_user_url(&self, id: &str) -> Result<Url, CisClientError> {
+        let mut url = self.change_api_user_endpoint.clone();
+        url.set_query(Some(&format!("user_id={}", encode_id(id)?)));
+        Ok(url)
+    }
+
+    fn get_user_raw(
+        &self,
+        id: &str,
+        by: &GetBy,
+        filter: Option<&str>,
+        active: ActiveFilter,
+    ) -> CisFut<Value> {
+        let url = match self.get_user_url(id, by, filter, active) {
+            Ok(url) => url,
+            Err(e) => return Box::pin(future::err(e)),
         };
-        let safe_id = utf8_percent_encode(id, USERINFO_ENCODE_SET).to_string();
-        let url = match self
-            .person_api_user_endpoint
-            .clone()
-            .join(by.as_str())
-            .and_then(|u| u.join(safe_id.trim_start_matches('.')))
-            .map(|mut u| {
-                if let Some(df) = filter {
-                    u.query_pairs_mut().append_pair("filterDisplay", df);
+        Box::pin(traced!(
+            send(
+                self.request_context(),
+                "get_user_raw",
+                url.clone(),
+            ),
+            "get_user_raw",
+            host = url.host_str().unwrap_or_default(),
+            by = %by,
+            active = active.as_str()
+        ))
+    }
+
+    /// Like [AsyncCisClientTrait::get_user_by] appending `extra_params` to the
+    /// query string, e.g. for Person API parameters this crate does not know.
+    pub fn get_user_by_with_params(
+        &self,
+        id: &str,
+        by: &GetBy,
+        filter: Option<&str>,
+        extra_params: &[(&str, &str)],
+    ) -> CisFut<Profile> {
+        self.get_user(id, by, filter, ActiveFilter::Active, extra_params, true)
+    }
+
+    /// Like [AsyncCisClientTrait::get_user_by] but returns the profile as
+    /// received even if it has no `uuid`.
+    ///
+    /// A display filter may strip `uuid` from a profile that does exist, which
+    /// [AsyncCisClientTrait::get_user_by] reports as
+    /// [ProfileError::ProfileDoesNotExist]. A 404 is still reported as missing
+    /// profile.
+    pub fn get_user_by_unchecked(
+        &self,
+        id: &str,
+        by: &GetBy,
+        filter: Option<&str>,
+    ) -> CisFut<Profile> {
+        self.get_user(id, by, filter, ActiveFilter::Active, &[], false)
+    }
+
+    /// Fetch the active profiles of all `ids`, a few at a time.
+    ///
+    /// Results are returned in the order of `ids`, one per id, so a missing
+    /// profile ([ProfileError::ProfileDoesNotExist]) or a failed request does
+    /// not fail the other lookups.
+    pub fn get_users_by_ids(
+        &self,
+        ids: &[&str],
+        by: &GetBy,
+        filter: Option<&str>,
+    ) -> CisFut<Vec<Result<Profile, CisClientError>>> {
+        let lookups: Vec<CisFut<Profile>> = ids
+            .iter()
+            .map(|id| self.get_user(id, by, filter, ActiveFilter::Active, &[], true))
+            .collect();
+        Box::pin(async move {
+            Ok(stream::iter(lookups)
+                .buffered(GET_USERS_BY_IDS_CONCURRENCY)
+                .collect()
+                .await)
+        })
+    }
+
+    /// Pages of profiles modified at or after `since`.
+    ///
+    /// `since` is sent as `modifiedSince` query parameter so only changed
+    /// profiles are returned. A Person API ignoring the parameter would return
+    /// every profile, the stream fails with
+    /// [CisClientError::ModifiedSinceIgnored] on the first page with a profile
+    /// modified before `since`. Servers rejecting the parameter fail the
+    /// stream with [CisClientError::ApiError].
+    pub fn get_users_modified_since(
+        &self,
+        since: DateTime<Utc>,
+        filter: Option<&str>,
+    ) -> AsyncProfileIter<CisClient> {
+        self.get_users_iter(filter).with_modified_since(since)
+    }
+
+    /// Like [AsyncCisClientTrait::get_batch] appending `extra_params` to the
+    /// query string.
+    pub fn get_batch_with_params(
+        &self,
+        next_page: &Option<NextPage>,
+        filter: &Option<String>,
+        extra_params: &[(&str, &str)],
+    ) -> CisFut<Batch> {
+        let mut url = self.person_api_users_endpoint.clone();
+        if let Some(df) = filter {
+            url.query_pairs_mut().append_pair("filterDisplay", df);
+        }
+        if let Some(next_page_token) = next_page {
+            let next_page_json = match serde_json::to_string(next_page_token) {
+                Ok(next_page_json) => next_page_json,
+                Err(e) => return Box::pin(future::err(e.into())),
+            };
+            url.query_pairs_mut()
+                .append_pair("nextPage", &next_page_json);
+        }
+        url.query_pairs_mut()
+            .append_pair("nextPageSize", &self.batch_size.to_string())
+            .extend_pairs(extra_params);
+        log::trace!("{}", url.as_str());
+        let ctx = self.request_context();
+        let stream_batches = self.stream_batches;
+        let strict = self.strict_profiles;
+        let request_url = url.clone();
+        Box::pin(traced!(
+            async move {
+                if stream_batches {
+                    send_streaming_batch(ctx, "get_batch", request_url, strict).await
+                } else {
+                    send(ctx, "get_batch", request_url)
+                        .await
+                        .and_then(|json| Batch::from_json(json, strict))
                 }
-                u.query_pairs_mut().append_pair("active", &active);
-                u
-            }) {
+            },
+            "get_batch",
+            host = url.host_str().unwrap_or_default(),
+            next_page = next_page
+                .as_ref()
+                .map(|n| n.id.as_str())
+                .unwrap_or_default()
+        ))
+    }
+
+    fn get_user(
+        &self,
+        id: &str,
+        by: &GetBy,
+        filter: Option<&str>,
+        active: ActiveFilter,
+        extra_params: &[(&str, &str)],
+        require_uuid: bool,
+    ) -> CisFut<Profile> {
+        let mut url = match self.get_user_url(id, by, filter, active) {
             Ok(url) => url,
-            Err(e) => return Box::pin(future::err(e.into())),
+            Err(e) => return Box::pin(future::err(e)),
         };
-        Box::pin(
-            send(self.bearer_store.clone(), url).and_then(|profile: Profile| {
-                if profile.uuid.value.is_none() {
+        url.query_pairs_mut().extend_pairs(extra_params);
+        let strict = self.strict_profiles;
+        Box::pin(traced!(
+            send(
+                self.request_context(),
+                "get_user",
+                url.clone(),
+            )
+            .map_err(not_found_as_missing_profile)
+            .and_then(move |json: Value| {
+                let profile = match profile_from_json(json, strict) {
+                    Ok(profile) => profile,
+                    Err(e) => return future::err(e),
+                };
+                if require_uuid && profile.uuid.value.is_none() {
                     return future::err(ProfileError::ProfileDoesNotExist.into());
                 }
                 future::ok(profile)
             }),
-        )
+            "get_user",
+            host = url.host_str().unwrap_or_default(),
+            by = %by,
+            active = active.as_str()
+        ))
     }
 }
 
 impl AsyncCisClientTrait for CisClient {
     fn get_user_by(&self, id: &str, by: &GetBy, filter: Option<&str>) -> CisFut<Profile> {
-        self.get_user(id, by, filter, Some(true))
+        self.get_user(id, by, filter, ActiveFilter::Active, &[], true)
     }
     fn get_inactive_user_by(&self, id: &str, by: &GetBy, filter: Option<&str>) -> CisFut<Profile> {
-        self.get_user(id, by, filter, Some(false))
+        self.get_user(id, by, filter, ActiveFilter::Inactive, &[], true)
     }
     fn get_any_user_by(&self, id: &str, by: &GetBy, filter: Option<&str>) -> CisFut<Profile> {
-        self.get_user(id, by, filter, None)
+        self.get_user(id, by, filter, ActiveFilter::Any, &[], true)
     }
-    fn update_user(&self, id: &str, profile: Profile) -> CisFut<Value> {
-        let safe_id = utf8_percent_encode(id, USERINFO_ENCODE_SET).to_string();
-        let mut url = self.change_api_user_endpoint.clone();
-        url.set_query(Some(&format!("user_id={}", safe_id)));
-        Box::pin(post(self.bearer_store.clone(), url, profile))
+    fn get_user_raw_by(&self, id: &str, by: &GetBy, filter: Option<&str>) -> CisFut<Value> {
+        self.get_user_raw(id, by, filter, ActiveFilter::Active)
     }
-    fn update_users(&self, _profiles: &[Profile]) -> CisFut<Value> {
-        unimplemented!()
+    fn get_users_iter(&self, filter: Option<&str>) -> AsyncProfileIter<Self> {
+        AsyncProfileIter::new(self.clone(), filter.map(String::from))
     }
-    fn delete_user(&self, id: &str, profile: Profile) -> CisFut<Value> {
-        let safe_id = utf8_percent_encode(id, USERINFO_ENCODE_SET).to_string();
-        let mut url = self.change_api_user_endpoint.clone();
-        url.set_query(Some(&format!("user_id={}", safe_id)));
-        Box::pin(delete(self.bearer_store.clone(), url, profile))
+    fn get_batch(&self, next_page: &Option<NextPage>, filter: &Option<String>) -> CisFut<Batch> {
+        self.get_batch_with_params(next_page, filter, &[])
+    }
+    fn get_batch_modified_since(
+        &self,
+        next_page: &Option<NextPage>,
+        filter: &Option<String>,
+        since: DateTime<Utc>,
+    ) -> CisFut<Batch> {
+        let since = modified_since_param(since);
+        self.get_batch_with_params(next_page, filter, &[("modifiedSince", &since)])
+    }
+    fn get_user_count(&self, filter: Option<&str>) -> CisFut<usize> {
+        let mut url = self.person_api_users_endpoint.clone();
+        if let Some(df) = filter {
+            url.query_pairs_mut().append_pair("filterDisplay", df);
+        }
+        url.query_pairs_mut().append_pair("nextPageSize", "1");
+        Box::pin(traced!(
+            send(self.request_context(), "get_user_count", url.clone()).and_then(|json: Value| {
+                future::ready(
+                    json["total"]
+                        .as_u64()
+                        .map(|total| total as usize)
+                        .ok_or(CisClientError::CountUnavailable),
+                )
+            }),
+            "get_user_count",
+            host = url.host_str().unwrap_or_default()
+        ))
+    }
+    fn create_user_raw(&self, profile: Profile) -> CisFut<Value> {
+        Box::pin(traced!(
+            post(
+                self.request_context(),
+                "create_user",
+                self.change_api_user_endpoint.clone(),
+                profile,
+            )
+            .map_err(conflict_as_existing_profile),
+            "create_user",
+            host = self.change_api_user_endpoint.host_str().unwrap_or_default()
+        ))
+    }
+    fn update_user_raw(&self, id: &str, profile: Profile) -> CisFut<Value> {
+        if let Err(e) = self.check_signatures(&profile) {
+            return Box::pin(future::err(e));
+        }
+        let url = match self.change_api_user_url(id) {
+            Ok(url) => url,
+            Err(e) => return Box::pin(future::err(e)),
+        };
+        Box::pin(traced!(
+            post(self.request_context(), "update_user", url.clone(), profile),
+            "update_user",
+            host = url.host_str().unwrap_or_default()
+        ))
+    }
+    fn update_users(&self, profiles: &[Profile]) -> CisFut<UpdateUsersResult> {
+        for profile in profiles {
+            if let Err(e) = self.check_signatures(profile) {
+                return Box::pin(future::err(e));
+            }
+        }
+        let chunks: Vec<Vec<Profile>> = profiles
+            .chunks(self.batch_size)
+            .map(<[Profile]>::to_vec)
+            .collect();
+        let ctx = self.request_context();
+        let url = self.change_api_users_endpoint.clone();
+        Box::pin(traced!(
+            async move {
+                let mut result = UpdateUsersResult::default();
+                for chunk in chunks {
+                    let res = post(ctx.clone(), "update_users", url.clone(), &chunk).await;
+                    result.push(&chunk, res);
+                }
+                Ok(result)
+            },
+            "update_users",
+            host = url.host_str().unwrap_or_default(),
+            profiles = profiles.len()
+        ))
+    }
+    fn delete_user_raw(&self, id: &str, profile: Profile) -> CisFut<Value> {
+        let url = match self.change_api_user_url(id) {
+            Ok(url) => url,
+            Err(e) => return Box::pin(future::err(e)),
+        };
+        Box::pin(traced!(
+            delete(self.request_context(), "delete_user", url.clone(), profile),
+            "delete_user",
+            host = url.host_str().unwrap_or_default()
+        ))
+    }
+    fn delete_users(&self, profiles: &[Profile]) -> CisFut<Value> {
+        let chunks: Vec<Vec<Profile>> = profiles
+            .chunks(self.batch_size)
+            .map(<[Profile]>::to_vec)
+            .collect();
+        let ctx = self.request_context();
+        let url = self.change_api_users_endpoint.clone();
+        Box::pin(traced!(
+            async move {
+                let mut responses = Vec::with_capacity(chunks.len());
+                for (i, chunk) in chunks.into_iter().enumerate() {
+                    let res = delete(ctx.clone(), "delete_users", url.clone(), chunk)
+                        .await
+                        .map_err(|e| CisClientError::ChunkFailed {
+                            chunk: i,
+                            source: Box::new(e),
+                        })?;
+                    responses.push(res);
+                }
+                Ok(Value::Array(responses))
+            },
+            "delete_users",
+            host = url.host_str().unwrap_or_default(),
+            profiles = profiles.len()
+        ))
     }
     fn get_secret_store(&self) -> &SecretStore {
         &self.secret_store
     }
 }
 
-fn flatten_status(result: Result<Response, CisClientError>) -> Result<Response, CisClientError> {
-    match result {
-        Ok(res) => res.error_for_status().map_err(Into::into),
-        Err(e) => Err(e),
+/// A 404 from Person API and a profile without uuid both mean the profile
+/// does not exist.
+pub(crate) fn not_found_as_missing_profile(e: CisClientError) -> CisClientError {
+    match e {
+        CisClientError::ApiError { status: 404, .. } => ProfileError::ProfileDoesNotExist.into(),
+        e => e,
+    }
+}
+
+/// A 409 from Person API on creation means the profile already exists.
+pub(crate) fn conflict_as_existing_profile(e: CisClientError) -> CisClientError {
+    match e {
+        CisClientError::ApiError { status: 409, .. } => ProfileError::ProfileAlreadyExists.into(),
+        e => e,
+    }
+}
+
+/// Turns non-2xx responses into [CisClientError::ApiError] keeping the response body.
+async fn check_status(res: Response) -> Result<Response, CisClientError> {
+    let status = res.status();
+    if status.is_client_error() || status.is_server_error() {
+        let body = res.text().await.unwrap_or_default();
+        return Err(CisClientError::ApiError {
+            status: status.as_u16(),
+            body,
+        });
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::TokenError;
+    use crate::test_util::*;
+    use anyhow::Error;
+
+    #[tokio::test]
+    async fn check_auth_fetches_fresh_token() -> Result<(), Error> {
+        let mut server = mockito::Server::new_async().await;
+        let token = server
+            .mock("POST", "/oauth/token")
+            .with_header("content-type", "application/json")
+            .with_body(token_body())
+            .expect(3)
+            .create_async()
+            .await;
+        let client = CisClient::from_settings(&settings_for(&server)).await?;
+        client.bearer_token().await?;
+        let expiry = client.token_expiry();
+        assert!(expiry.is_some());
+        client.check_auth().await?;
+        client.check_auth().await?;
+        assert_eq!(client.token_expiry(), expiry);
+        token.assert_async().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn check_auth_unauthorized() -> Result<(), Error> {
+        let mut server = mockito::Server::new_async().await;
+        server
+            .mock("POST", "/oauth/token")
+            .with_status(401)
+            .with_header("content-type", "application/json")
+            .with_body(r#"{"error":"access_denied","error_description":"Unauthorized"}"#)
+            .create_async()
+            .await;
+        let client = CisClient::from_settings(&settings_for(&server)).await?;
+        match client.check_auth().await {
+            Err(CisClientError::TokenError(TokenError::Auth0Error { error, .. })) => {
+                assert_eq!(error, "access_denied")
+            }
+            r => panic!("unexpected result: {:?}", r),
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn health_all_ok() -> Result<(), Error> {
+        let mut server = mockito::Server::new_async().await;
+        mock_token(&mut server).await;
+        server
+            .mock("GET", "/v2/users")
+            .match_query(mockito::Matcher::UrlEncoded(
+                "nextPageSize".into(),
+                "1".into(),
+            ))
+            .with_body(r#"{"Items":[]}"#)
+            .create_async()
+            .await;
+        let client = CisClient::from_settings(&settings_for(&server)).await?;
+        let report = client.health().await?;
+        assert!(report.auth_ok);
+        assert!(report.person_api_ok);
+        assert!(report.person_api_latency.is_some());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn health_person_api_down() -> Result<(), Error> {
+        let mut server = mockito::Server::new_async().await;
+        mock_token(&mut server).await;
+        let users = server
+            .mock("GET", "/v2/users")
+            .match_query(mockito::Matcher::Any)
+            .with_status(503)
+            .expect(1)
+            .create_async()
+            .await;
+        let client = CisClient::from_settings(&settings_for(&server)).await?;
+        let report = client.health().await?;
+        assert!(report.auth_ok);
+        assert!(!report.person_api_ok);
+        users.assert_async().await;
+        Ok(())
+    }
+
+    #[cfg(feature = "tracing")]
+    #[tokio::test]
+    async fn operations_emit_spans() -> Result<(), Error> {
+        let mut server = mockito::Server::new_async().await;
+        mock_token(&mut server).await;
+        server
+            .mock("GET", "/v2/user/uuid/some-uuid")
+            .match_query(mockito::Matcher::Any)
+            .with_body(profile_body("some-uuid"))
+            .create_async()
+            .await;
+        server
+            .mock("GET", "/v2/users")
+            .match_query(mockito::Matcher::Any)
+            .with_body(r#"{"Items":[]}"#)
+            .create_async()
+            .await;
+        let recorder = SpanRecorder::default();
+        let spans = std::sync::Arc::clone(&recorder.0);
+        let _guard = tracing::subscriber::set_default(recorder);
+        let client = CisClient::from_settings(&settings_for(&server)).await?;
+        client.get_user_by("some-uuid", &GetBy::Uuid, None).await?;
+        let next_page = Some(NextPage {
+            id: String::from("page-2"),
+        });
+        client.get_batch(&next_page, &None).await?;
+        let spans = spans.lock().unwrap();
+        let field = |name: &str, field: &str| {
+            spans
+                .iter()
+                .find(|(n, _)| *n == name)
+                .and_then(|(_, fields)| fields.iter().find(|(f, _)| *f == field))
+                .map(|(_, v)| v.clone())
+        };
+        assert_eq!(field("get_user", "by").as_deref(), Some("uuid"));
+        assert_eq!(field("get_user", "host").as_deref(), Some("127.0.0.1"));
+        assert_eq!(field("get_batch", "next_page").as_deref(), Some("page-2"));
+        assert_eq!(spans.iter().filter(|(n, _)| *n == "get_user").count(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn extra_params_are_encoded() -> Result<(), Error> {
+        let mut server = mockito::Server::new_async().await;
+        mock_token(&mut server).await;
+        let user = server
+            .mock("GET", "/v2/user/uuid/some-uuid")
+            .match_query(mockito::Matcher::Regex(String::from(
+                "^active=true&schema=v2%2B1&q=a\\+b%26c$",
+            )))
+            .with_body(profile_body("some-uuid"))
+            .create_async()
+            .await;
+        let users = server
+            .mock("GET", "/v2/users")
+            .match_query(mockito::Matcher::Regex(String::from(
+                "^nextPageSize=25&schema=v2%2B1$",
+            )))
+            .with_body(r#"{"Items":[]}"#)
+            .create_async()
+            .await;
+        let client = CisClient::from_settings(&settings_for(&server)).await?;
+        let params = [("schema", "v2+1"), ("q", "a b&c")];
+        client
+            .get_user_by_with_params("some-uuid", &GetBy::Uuid, None, &params)
+            .await?;
+        client
+            .get_batch_with_params(&None, &None, &params[..1])
+            .await?;
+        user.assert_async().await;
+        users.assert_async().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn update_users_reports_rejected_profiles() -> Result<(), Error> {
+        let mut server = mockito::Server::new_async().await;
+        mock_token(&mut server).await;
+        server
+            .mock("POST", "/v2/users")
+            .match_body(mockito::Matcher::Regex(String::from("ad\\|alice")))
+            .with_body(r#"{"accepted":2}"#)
+            .create_async()
+            .await;
+        server
+            .mock("POST", "/v2/users")
+            .match_body(mockito::Matcher::Regex(String::from("ad\\|carol")))
+            .with_status(400)
+            .with_body(r#"{"error":"invalid profile"}"#)
+            .create_async()
+            .await;
+        let client = CisClient::from_settings(&settings_for(&server))
+            .await?
+            .with_batch_size(2)?;
+        let profiles: Vec<Profile> = ["alice", "bob", "carol", "dave"]
+            .iter()
+            .map(|name| {
+                let mut profile = Profile::default();
+                profile.user_id.value = Some(format!("ad|{}", name));
+                profile
+            })
+            .collect();
+        let res = client.update_users(&profiles).await?;
+        assert!(!res.is_ok());
+        assert_eq!(res.chunks.len(), 2);
+        assert_eq!(res.failed_ids(), vec!["ad|carol", "ad|dave"]);
+        assert_eq!(res.responses(), vec![&serde_json::json!({ "accepted": 2 })]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn get_users_by_ids_keeps_order() -> Result<(), Error> {
+        let mut server = mockito::Server::new_async().await;
+        mock_token(&mut server).await;
+        for id in &["first", "third"] {
+            server
+                .mock("GET", format!("/v2/user/uuid/{}", id).as_str())
+                .match_query(mockito::Matcher::Any)
+                .with_body(profile_body(id))
+                .create_async()
+                .await;
+        }
+        server
+            .mock("GET", "/v2/user/uuid/second")
+            .match_query(mockito::Matcher::Any)
+            .with_status(404)
+            .create_async()
+            .await;
+        let client = CisClient::from_settings(&settings_for(&server)).await?;
+        let res = client
+            .get_users_by_ids(&["first", "second", "third"], &GetBy::Uuid, None)
+            .await?;
+        assert_eq!(res.len(), 3);
+        assert_eq!(
+            res[0].as_ref().unwrap().uuid.value.as_deref(),
+            Some("first")
+        );
+        assert!(matches!(
+            res[1],
+            Err(CisClientError::ProfileError(
+                ProfileError::ProfileDoesNotExist
+            ))
+        ));
+        assert_eq!(
+            res[2].as_ref().unwrap().uuid.value.as_deref(),
+            Some("third")
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn streamed_and_buffered_batches_are_equal() -> Result<(), Error> {
+        let mut server = mockito::Server::new_async().await;
+        mock_token(&mut server).await;
+        let profiles: Vec<Profile> = (0..50)
+            .map(|i| {
+                let mut profile = Profile::default();
+                profile.uuid.value = Some(format!("uuid-{}", i));
+                profile.primary_email.value = Some(format!("user{}@example.com", i));
+                profile
+            })
+            .collect();
+        server
+            .mock("GET", "/v2/users")
+            .match_query(mockito::Matcher::Any)
+            .with_body(
+                serde_json::json!({ "Items": profiles, "nextPage": { "id": "next" } }).to_string(),
+            )
+            .create_async()
+            .await;
+        let buffered = CisClient::from_settings(&settings_for(&server))
+            .await?
+            .get_batch(&None, &None)
+            .await?;
+        let streamed = CisClientBuilder::from_settings(&settings_for(&server))
+            .stream_batches()
+            .build_async()
+            .await?
+            .get_batch(&None, &None)
+            .await?;
+        assert_eq!(
+            serde_json::to_value(&streamed.items)?,
+            serde_json::to_value(&buffered.items)?
+        );
+        assert_eq!(streamed.items.map(|items| items.len()), Some(50));
+        assert_eq!(streamed.next_page.map(|n| n.id).as_deref(), Some("next"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn oversized_responses_are_rejected() -> Result<(), Error> {
+        let mut server = mockito::Server::new_async().await;
+        mock_token(&mut server).await;
+        let profile: Value = serde_json::from_str(&profile_body("some-uuid"))?;
+        let page = serde_json::json!({ "Items": vec![profile; 20] }).to_string();
+        server
+            .mock("GET", "/v2/user/uuid/some-uuid")
+            .match_query(mockito::Matcher::Any)
+            .with_body(profile_body("some-uuid"))
+            .create_async()
+            .await;
+        server
+            .mock("GET", "/v2/users")
+            .match_query(mockito::Matcher::Any)
+            .with_body(&page)
+            .create_async()
+            .await;
+        let max = profile_body("some-uuid").len() + 1;
+        assert!(page.len() > max);
+        for stream_batches in &[false, true] {
+            let mut builder =
+                CisClientBuilder::from_settings(&settings_for(&server)).max_response_bytes(max);
+            if *stream_batches {
+                builder = builder.stream_batches();
+            }
+            let client = builder.build()?;
+            client.get_user_by("some-uuid", &GetBy::Uuid, None).await?;
+            assert!(matches!(
+                client.get_batch(&None, &None).await,
+                Err(CisClientError::ResponseTooLarge(m)) if m == max
+            ));
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn get_users_modified_since_sends_since() -> Result<(), Error> {
+        let mut server = mockito::Server::new_async().await;
+        mock_token(&mut server).await;
+        let mut changed = Profile::default();
+        changed.uuid.value = Some(String::from("changed"));
+        changed.last_modified.value = Some(String::from("2020-06-01T00:00:00.000Z"));
+        let mut unknown = Profile::default();
+        unknown.uuid.value = Some(String::from("unknown"));
+        let users = server
+            .mock("GET", "/v2/users")
+            .match_query(mockito::Matcher::UrlEncoded(
+                "modifiedSince".into(),
+                "2020-01-01T00:00:00Z".into(),
+            ))
+            .with_body(serde_json::json!({ "Items": [changed, unknown] }).to_string())
+            .expect(1)
+            .create_async()
+            .await;
+        let client = CisClient::from_settings(&settings_for(&server)).await?;
+        let since = "2020-01-01T00:00:00Z".parse::<DateTime<Utc>>()?;
+        let pages: Vec<Vec<Profile>> = client
+            .get_users_modified_since(since, None)
+            .try_collect()
+            .await?;
+        assert_eq!(pages.len(), 1);
+        let uuids: Vec<_> = pages[0].iter().map(|p| p.uuid.value.as_deref()).collect();
+        assert_eq!(uuids, vec![Some("changed"), Some("unknown")]);
+        users.assert_async().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn get_users_modified_since_detects_ignored_param() -> Result<(), Error> {
+        let mut server = mockito::Server::new_async().await;
+        mock_token(&mut server).await;
+        let mut unchanged = Profile::default();
+        unchanged.uuid.value = Some(String::from("unchanged"));
+        unchanged.last_modified.value = Some(String::from("2019-06-01T00:00:00.000Z"));
+        server
+            .mock("GET", "/v2/users")
+            .match_query(mockito::Matcher::Any)
+            .with_body(
+                serde_json::json!({ "Items": [unchanged], "nextPage": { "id": "page-2" } })
+                    .to_string(),
+            )
+            .create_async()
+            .await;
+        let client = CisClient::from_settings(&settings_for(&server)).await?;
+        let since = "2020-01-01T00:00:00Z".parse::<DateTime<Utc>>()?;
+        let pages: Vec<Result<Vec<Profile>, CisClientError>> =
+            client.get_users_modified_since(since, None).collect().await;
+        assert_eq!(pages.len(), 1);
+        assert!(matches!(
+            &pages[0],
+            Err(CisClientError::ModifiedSinceIgnored { since: s, .. }) if *s == since
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sequential_get_user_by_share_http_client() -> Result<(), Error> {
+        use std::sync::atomic::Ordering;
+
+        let (addr, connections) = counting_server(profile_body("some-uuid"));
+        let settings = settings_for_url(&format!("http://{}", addr));
+        let exp = Utc::now() + chrono::Duration::hours(1);
+        let client = CisClient::with_static_token(FAKE_TOKEN.to_owned(), exp, &settings).await?;
+        let cloned = client.clone();
+        client.get_user_by("some-uuid", &GetBy::Uuid, None).await?;
+        cloned.get_user_by("some-uuid", &GetBy::Uuid, None).await?;
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn get_user_by_retries_on_503() -> Result<(), Error> {
+        let mut server = mockito::Server::new_async().await;
+        mock_token(&mut server).await;
+        let unavailable = server
+            .mock("GET", "/v2/user/uuid/some-uuid")
+            .match_query(mockito::Matcher::Any)
+            .with_status(503)
+            .expect(2)
+            .create_async()
+            .await;
+        let user = server
+            .mock("GET", "/v2/user/uuid/some-uuid")
+            .match_query(mockito::Matcher::Any)
+            .with_body(profile_body("some-uuid"))
+            .expect(1)
+            .create_async()
+            .await;
+        let mut client = CisClient::from_settings(&settings_for(&server)).await?;
+        client.retry_config.base_delay = std::time::Duration::from_millis(1);
+        let profile = client.get_user_by("some-uuid", &GetBy::Uuid, None).await?;
+        assert_eq!(profile.uuid.value.as_deref(), Some("some-uuid"));
+        unavailable.assert_async().await;
+        user.assert_async().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn writes_without_idempotency_keys_are_not_retried() -> Result<(), Error> {
+        let mut server = mockito::Server::new_async().await;
+        mock_token(&mut server).await;
+        let unavailable = server
+            .mock("POST", "/v2/user")
+            .match_query(mockito::Matcher::Any)
+            .with_status(503)
+            .expect(1)
+            .create_async()
+            .await;
+        let mut client = CisClient::from_settings(&settings_for(&server)).await?;
+        client.retry_config.base_delay = std::time::Duration::from_millis(1);
+        let res = client.update_user("some-id", Profile::default()).await;
+        assert!(matches!(
+            res,
+            Err(CisClientError::ApiError { status: 503, .. })
+        ));
+        unavailable.assert_async().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn retried_update_user_keeps_idempotency_key() -> Result<(), Error> {
+        let mut server = mockito::Server::new_async().await;
+        mock_token(&mut server).await;
+        let keys = Arc::new(Mutex::new(Vec::new()));
+        for status in &[503, 200] {
+            let keys = Arc::clone(&keys);
+            server
+                .mock("POST", "/v2/user")
+                .match_query(mockito::Matcher::Any)
+                .with_status(*status)
+                .with_body_from_request(move |req| {
+                    let key = req.header("idempotency-key")[0]
+                        .to_str()
+                        .unwrap()
+                        .to_owned();
+                    keys.lock().unwrap().push(key);
+                    b"{}".to_vec()
+                })
+                .expect(1)
+                .create_async()
+                .await;
+        }
+        let mut client = CisClientBuilder::from_settings(&settings_for(&server))
+            .idempotency_keys()
+            .build()?;
+        client.retry_config.base_delay = std::time::Duration::from_millis(1);
+        client.update_user("some-id", Profile::default()).await?;
+        let keys = keys.lock().unwrap();
+        assert_eq!(keys.len(), 2);
+        assert_eq!(keys[0], keys[1]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn requests_send_schema_version() -> Result<(), Error> {
+        let mut server = mockito::Server::new_async().await;
+        mock_token(&mut server).await;
+        let accept = "application/json; version=2";
+        let user = server
+            .mock("GET", "/v2/user/uuid/some-uuid")
+            .match_query(mockito::Matcher::Any)
+            .match_header("accept", accept)
+            .with_body(profile_body("some-uuid"))
+            .create_async()
+            .await;
+        let update = server
+            .mock("POST", "/v2/user")
+            .match_query(mockito::Matcher::Any)
+            .match_header("accept", accept)
+            .with_body("{}")
+            .create_async()
+            .await;
+        let client = CisClientBuilder::from_settings(&settings_for(&server))
+            .schema_version(String::from("2"))
+            .build()?;
+        client.get_user_by("some-uuid", &GetBy::Uuid, None).await?;
+        client.update_user("some-id", Profile::default()).await?;
+        user.assert_async().await;
+        update.assert_async().await;
+
+        let unversioned = server
+            .mock("GET", "/v2/user/uuid/other-uuid")
+            .match_query(mockito::Matcher::Any)
+            .match_header("accept", "*/*")
+            .with_body(profile_body("other-uuid"))
+            .create_async()
+            .await;
+        let client = CisClient::from_settings(&settings_for(&server)).await?;
+        client.get_user_by("other-uuid", &GetBy::Uuid, None).await?;
+        unversioned.assert_async().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn update_user_keeps_error_body() -> Result<(), Error> {
+        let mut server = mockito::Server::new_async().await;
+        mock_token(&mut server).await;
+        server
+            .mock("POST", "/v2/user")
+            .match_query(mockito::Matcher::Any)
+            .with_status(422)
+            .with_body(r#"{"error":"invalid signature"}"#)
+            .create_async()
+            .await;
+        let client = CisClient::from_settings(&settings_for(&server)).await?;
+        match client.update_user("some-id", Profile::default()).await {
+            Err(CisClientError::ApiError { status, body }) => {
+                assert_eq!(status, 422);
+                assert_eq!(body, r#"{"error":"invalid signature"}"#);
+            }
+            r => panic!("unexpected result: {:?}", r),
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn html_bodies_surface_status_and_text() -> Result<(), Error> {
+        let mut server = mockito::Server::new_async().await;
+        mock_token(&mut server).await;
+        let page = format!(
+            "<html><head><title>503 Service Temporarily Unavailable</title></head><body>{}</body></html>",
+            "<p>padding</p>".repeat(100)
+        );
+        server
+            .mock("GET", "/v2/user/uuid/down")
+            .match_query(mockito::Matcher::Any)
+            .with_status(503)
+            .with_header("content-type", "text/html")
+            .with_body(&page)
+            .create_async()
+            .await;
+        server
+            .mock("GET", "/v2/user/uuid/html")
+            .match_query(mockito::Matcher::Any)
+            .with_header("content-type", "text/html")
+            .with_body("<html><body>Request blocked</body></html>")
+            .create_async()
+            .await;
+        let mut client = CisClient::from_settings(&settings_for(&server)).await?;
+        client.retry_config.base_delay = std::time::Duration::from_millis(1);
+
+        let e = client
+            .get_user_by("down", &GetBy::Uuid, None)
+            .await
+            .unwrap_err();
+        assert!(matches!(&e, CisClientError::ApiError { status: 503, body } if *body == page));
+        let message = e.to_string();
+        assert!(message.starts_with("api error (503): <html><head><title>503 Service"));
+        assert!(message.ends_with("..."));
+        assert!(message.len() < 250);
+
+        let e = client
+            .get_user_by("html", &GetBy::Uuid, None)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "response is not json (200): <html><body>Request blocked</body></html>"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn update_user_rejects_unsigned_profile() -> Result<(), Error> {
+        let mut server = mockito::Server::new_async().await;
+        let update = server
+            .mock("POST", "/v2/user")
+            .match_query(mockito::Matcher::Any)
+            .expect(0)
+            .create_async()
+            .await;
+        let client = CisClientBuilder::from_settings(&settings_for(&server))
+            .validate_signatures()
+            .build()?;
+        let mut profile = Profile::default();
+        profile.primary_email.value = Some(String::from("hknall@mozilla.com"));
+        match client.update_user("some-id", profile).await {
+            Err(CisClientError::ProfileError(ProfileError::InvalidSignature {
+                attribute, ..
+            })) => assert_eq!(attribute, "primary_email"),
+            r => panic!("unexpected result: {:?}", r),
+        }
+        let mut profile = Profile::default();
+        profile.staff_information.title.value = Some(String::from("Staff Engineer"));
+        match client.update_user("some-id", profile).await {
+            Err(CisClientError::ProfileError(ProfileError::InvalidSignature {
+                attribute, ..
+            })) => assert_eq!(attribute, "staff_information.title"),
+            r => panic!("unexpected result: {:?}", r),
+        }
+        update.assert_async().await;
+        Ok(())
+    }
+
+    #[test]
+    fn sign_profile_populates_signatures() -> Result<(), Error> {
+        let key = std::fs::read_to_string("tests/data/fake_key.json")?;
+        let secret_store = SecretStore::default()
+            .with_sign_keys_from_inline_iter(vec![(String::from("cis"), key)])?;
+        let client = CisClientBuilder::from_settings(&CisSettings::default())
+            .secret_store(secret_store)
+            .build()?;
+        let mut profile = Profile::default();
+        profile.uuid.signature.publisher.name = PublisherAuthority::Cis;
+        profile.staff_information.title.signature.publisher.name = PublisherAuthority::Cis;
+        profile.usernames.signature.publisher.name = PublisherAuthority::Cis;
+        profile.access_information.hris.signature.publisher.name = PublisherAuthority::Hris;
+        client.sign_profile(&mut profile, PublisherAuthority::Cis)?;
+        assert_ne!(profile.uuid.signature.publisher.value, Default::default());
+        assert_ne!(
+            profile.staff_information.title.signature.publisher.value,
+            Default::default()
+        );
+        assert_ne!(
+            profile.usernames.signature.publisher.value,
+            Default::default()
+        );
+        assert_eq!(
+            profile.access_information.hris.signature.publisher.value,
+            Default::default()
+        );
+        match client.sign_profile(&mut profile, PublisherAuthority::Ldap) {
+            Err(CisClientError::ProfileError(ProfileError::MissingSignKey("ldap"))) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn set_user_active_signs_and_submits() -> Result<(), Error> {
+        let mut server = mockito::Server::new_async().await;
+        mock_token(&mut server).await;
+        let mut profile = Profile::default();
+        profile.uuid.value = Some(String::from("some-uuid"));
+        profile.user_id.value = Some(String::from("ad|alice"));
+        profile.active.value = Some(false);
+        server
+            .mock("GET", "/v2/user/user_id/ad%7Calice")
+            .match_query(mockito::Matcher::UrlEncoded("active".into(), "any".into()))
+            .with_body(serde_json::to_string(&profile)?)
+            .create_async()
+            .await;
+        let update = server
+            .mock("POST", "/v2/user")
+            .match_query(mockito::Matcher::UrlEncoded(
+                "user_id".into(),
+                "ad|alice".into(),
+            ))
+            .match_body(mockito::Matcher::PartialJson(
+                serde_json::json!({ "active": { "value": true } }),
+            ))
+            .with_body("{}")
+            .expect(1)
+            .create_async()
+            .await;
+        let unsigned = CisClient::from_settings(&settings_for(&server)).await?;
+        match unsigned.set_user_active("ad|alice", true).await {
+            Err(CisClientError::ProfileError(ProfileError::MissingSignKey("cis"))) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+        let key = std::fs::read_to_string("tests/data/fake_key.json")?;
+        let secret_store = SecretStore::default()
+            .with_sign_keys_from_inline_iter(vec![(String::from("cis"), key)])?;
+        let client = CisClientBuilder::from_settings(&settings_for(&server))
+            .secret_store(secret_store)
+            .build()?;
+        client.set_user_active("ad|alice", true).await?;
+        update.assert_async().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn update_user_changes_submits_changed_attributes() -> Result<(), Error> {
+        let mut server = mockito::Server::new_async().await;
+        mock_token(&mut server).await;
+        let submitted = Arc::new(Mutex::new(Value::Null));
+        let recorded = Arc::clone(&submitted);
+        let update = server
+            .mock("POST", "/v2/user")
+            .match_query(mockito::Matcher::UrlEncoded(
+                "user_id".into(),
+                "ad|alice".into(),
+            ))
+            .with_body_from_request(move |req| {
+                *recorded.lock().unwrap() = serde_json::from_slice(req.body().unwrap()).unwrap();
+                b"{}".to_vec()
+            })
+            .expect(1)
+            .create_async()
+            .await;
+        let key = std::fs::read_to_string("tests/data/fake_key.json")?;
+        let secret_store = SecretStore::default()
+            .with_sign_keys_from_inline_iter(vec![(String::from("cis"), key)])?;
+        let client = CisClientBuilder::from_settings(&settings_for(&server))
+            .secret_store(secret_store)
+            .build()?;
+        let mut original = Profile::default();
+        original.uuid.value = Some(String::from("some-uuid"));
+        original.user_id.value = Some(String::from("ad|alice"));
+        original.primary_email.value = Some(String::from("alice@example.com"));
+        original.primary_email.signature.publisher.name = PublisherAuthority::Cis;
+        client
+            .update_user_changes("ad|alice", &original, original.clone())
+            .await?;
+        let mut modified = original.clone();
+        modified.primary_email.value = Some(String::from("alice@example.org"));
+        client
+            .update_user_changes("ad|alice", &original, modified)
+            .await?;
+        update.assert_async().await;
+        let submitted = submitted.lock().unwrap();
+        let attributes: Vec<&String> = submitted.as_object().unwrap().keys().collect();
+        assert_eq!(attributes, vec!["primary_email"]);
+        assert_eq!(submitted["primary_email"]["value"], "alice@example.org");
+        assert_ne!(
+            submitted["primary_email"]["signature"]["publisher"]["value"],
+            Value::Null
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn update_user_changes_signs_nested_attributes() -> Result<(), Error> {
+        let mut server = mockito::Server::new_async().await;
+        mock_token(&mut server).await;
+        let submitted = Arc::new(Mutex::new(Value::Null));
+        let recorded = Arc::clone(&submitted);
+        let update = server
+            .mock("POST", "/v2/user")
+            .match_query(mockito::Matcher::Any)
+            .with_body_from_request(move |req| {
+                *recorded.lock().unwrap() = serde_json::from_slice(req.body().unwrap()).unwrap();
+                b"{}".to_vec()
+            })
+            .expect(1)
+            .create_async()
+            .await;
+        let key = std::fs::read_to_string("tests/data/fake_key.json")?;
+        let secret_store = SecretStore::default()
+            .with_sign_keys_from_inline_iter(vec![(String::from("cis"), key)])?;
+        let client = CisClientBuilder::from_settings(&settings_for(&server))
+            .secret_store(secret_store)
+            .build()?;
+        let original = Profile::default();
+        let mut modified = original.clone();
+        modified.staff_information.title.value = Some(String::from("Engineer"));
+        modified.usernames.values = Some(Default::default());
+        client
+            .update_user_changes("ad|alice", &original, modified.clone())
+            .await?;
+        modified.schema = String::from("https://example.com/schema");
+        match client
+            .update_user_changes("ad|alice", &original, modified)
+            .await
+        {
+            Err(CisClientError::ProfileError(ProfileError::UnsignableAttribute(name)))
+                if name == "schema" => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+        update.assert_async().await;
+        let submitted = submitted.lock().unwrap();
+        let attributes: Vec<&String> = submitted.as_object().unwrap().keys().collect();
+        assert_eq!(attributes, vec!["staff_information", "usernames"]);
+        let staff_information = &submitted["staff_information"];
+        assert_eq!(staff_information["title"]["value"], "Engineer");
+        assert_ne!(
+            staff_information["title"]["signature"]["publisher"]["value"],
+            Value::Null
+        );
+        assert_eq!(
+            staff_information["team"]["signature"]["publisher"]["value"],
+            Value::Null
+        );
+        assert_ne!(
+            submitted["usernames"]["signature"]["publisher"]["value"],
+            Value::Null
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn update_user_changes_as_signs_with_realm_key() -> Result<(), Error> {
+        let mut server = mockito::Server::new_async().await;
+        mock_token(&mut server).await;
+        let submitted = Arc::new(Mutex::new(Vec::new()));
+        let recorded = Arc::clone(&submitted);
+        let update = server
+            .mock("POST", "/v2/user")
+            .match_query(mockito::Matcher::Any)
+            .with_body_from_request(move |req| {
+                let payload: Value = serde_json::from_slice(req.body().unwrap()).unwrap();
+                recorded.lock().unwrap().push(payload);
+                b"{}".to_vec()
+            })
+            .expect(2)
+            .create_async()
+            .await;
+        let key = std::fs::read_to_string("tests/data/fake_key.json")?;
+        let secret_store = SecretStore::default().with_sign_keys_from_inline_iter(vec![
+            (String::from("cis"), key.clone()),
+            (String::from("ldap"), key),
+        ])?;
+        let client = CisClientBuilder::from_settings(&settings_for(&server))
+            .secret_store(secret_store)
+            .build()?;
+        let original = Profile::default();
+        let mut modified = original.clone();
+        modified.primary_email.value = Some(String::from("alice@example.com"));
+        modified.identities.github_id_v3.value = Some(String::from("1234"));
+        modified.staff_information.title.value = Some(String::from("Engineer"));
+        modified.usernames.values = Some(Default::default());
+        for realm in &[PublisherAuthority::Ldap, PublisherAuthority::Cis] {
+            client
+                .update_user_changes_as("ad|alice", &original, modified.clone(), *realm)
+                .await?;
+        }
+        match client
+            .update_user_changes_as("ad|alice", &original, modified, PublisherAuthority::Hris)
+            .await
+        {
+            Err(CisClientError::ProfileError(ProfileError::MissingSignKey("hris"))) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+        update.assert_async().await;
+        let submitted = submitted.lock().unwrap();
+        for pointer in &[
+            "/primary_email",
+            "/identities/github_id_v3",
+            "/staff_information/title",
+            "/usernames",
+        ] {
+            let publishers: Vec<&Value> = submitted
+                .iter()
+                .map(|payload| &payload.pointer(pointer).unwrap()["signature"]["publisher"])
+                .collect();
+            assert_eq!(publishers[0]["name"], "ldap", "{}", pointer);
+            assert_eq!(publishers[1]["name"], "cis", "{}", pointer);
+            assert_ne!(publishers[0]["value"], Value::Null, "{}", pointer);
+            assert_ne!(
+                publishers[0]["value"], publishers[1]["value"],
+                "{}",
+                pointer
+            );
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn delete_user_by_id_deletes_fetched_profile() -> Result<(), Error> {
+        let mut server = mockito::Server::new_async().await;
+        mock_token(&mut server).await;
+        let mut profile = Profile::default();
+        profile.uuid.value = Some(String::from("some-uuid"));
+        profile.user_id.value = Some(String::from("ad|alice"));
+        server
+            .mock("GET", "/v2/user/uuid/some-uuid")
+            .match_query(mockito::Matcher::UrlEncoded("active".into(), "any".into()))
+            .with_body(serde_json::to_string(&profile)?)
+            .create_async()
+            .await;
+        server
+            .mock("GET", "/v2/user/uuid/missing")
+            .match_query(mockito::Matcher::Any)
+            .with_status(404)
+            .create_async()
+            .await;
+        profile.uuid.value = Some(String::from("anonymous"));
+        profile.user_id.value = None;
+        server
+            .mock("GET", "/v2/user/uuid/anonymous")
+            .match_query(mockito::Matcher::Any)
+            .with_body(serde_json::to_string(&profile)?)
+            .create_async()
+            .await;
+        let delete = server
+            .mock("DELETE", "/v2/user")
+            .match_query(mockito::Matcher::UrlEncoded(
+                "user_id".into(),
+                "ad|alice".into(),
+            ))
+            .match_body(mockito::Matcher::PartialJson(serde_json::json!({
+                "uuid": { "value": "some-uuid" },
+                "user_id": { "value": "ad|alice" },
+            })))
+            .with_body("{}")
+            .expect(1)
+            .create_async()
+            .await;
+        let client = CisClient::from_settings(&settings_for(&server)).await?;
+        client.delete_user_by_id("some-uuid", &GetBy::Uuid).await?;
+        assert!(matches!(
+            client.delete_user_by_id("missing", &GetBy::Uuid).await,
+            Err(CisClientError::ProfileError(
+                ProfileError::ProfileDoesNotExist
+            ))
+        ));
+        match client.delete_user_by_id("anonymous", &GetBy::Uuid).await {
+            Err(CisClientError::MissingUserId { id, by }) => {
+                assert_eq!(id, "anonymous");
+                assert_eq!(by, GetBy::Uuid);
+            }
+            r => panic!("unexpected result: {:?}", r),
+        }
+        delete.assert_async().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn strict_profiles_reject_unknown_fields() -> Result<(), Error> {
+        let mut server = mockito::Server::new_async().await;
+        mock_token(&mut server).await;
+        let mut profile: Value = serde_json::from_str(&profile_body("some-uuid"))?;
+        profile["new_attribute"] = serde_json::json!({ "value": "x" });
+        profile["uuid"]["signature"]["kid"] = serde_json::json!("k");
+        server
+            .mock("GET", "/v2/user/uuid/some-uuid")
+            .match_query(mockito::Matcher::Any)
+            .with_body(profile.to_string())
+            .create_async()
+            .await;
+        let lenient = CisClient::from_settings(&settings_for(&server)).await?;
+        lenient.get_user_by("some-uuid", &GetBy::Uuid, None).await?;
+        let strict = CisClientBuilder::from_settings(&settings_for(&server))
+            .strict_profiles()
+            .build()?;
+        match strict.get_user_by("some-uuid", &GetBy::Uuid, None).await {
+            Err(CisClientError::ProfileError(ProfileError::UnknownFields(fields))) => {
+                assert_eq!(fields, vec!["new_attribute", "uuid.signature.kid"])
+            }
+            r => panic!("unexpected result: {:?}", r),
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn strict_profiles_reject_unknown_fields_in_pages() -> Result<(), Error> {
+        let mut server = mockito::Server::new_async().await;
+        mock_token(&mut server).await;
+        let mut profile: Value = serde_json::from_str(&profile_body("some-uuid"))?;
+        profile["new_attribute"] = serde_json::json!({ "value": "x" });
+        server
+            .mock("GET", "/v2/users")
+            .match_query(mockito::Matcher::Any)
+            .with_body(serde_json::json!({ "Items": [profile] }).to_string())
+            .create_async()
+            .await;
+        let lenient = CisClient::from_settings(&settings_for(&server)).await?;
+        assert_eq!(lenient.get_all_users(None).await?.len(), 1);
+        for stream_batches in &[false, true] {
+            let mut builder =
+                CisClientBuilder::from_settings(&settings_for(&server)).strict_profiles();
+            if *stream_batches {
+                builder = builder.stream_batches();
+            }
+            let strict = builder.build()?;
+            match strict.get_all_users(None).await {
+                Err(CisClientError::ProfileError(ProfileError::UnknownFields(fields))) => {
+                    assert_eq!(fields, vec!["new_attribute"])
+                }
+                r => panic!("unexpected result: {:?}", r),
+            }
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn create_user_posts_without_user_id() -> Result<(), Error> {
+        let mut server = mockito::Server::new_async().await;
+        mock_token(&mut server).await;
+        let create = server
+            .mock("POST", "/v2/user")
+            .match_query(mockito::Matcher::Missing)
+            .with_body(r#"{"status":"created"}"#)
+            .create_async()
+            .await;
+        let client = CisClient::from_settings(&settings_for(&server)).await?;
+        let res = client.create_user(Profile::default()).await?;
+        assert_eq!(res.status.as_deref(), Some("created"));
+        create.assert_async().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn create_user_conflict_is_already_exists() -> Result<(), Error> {
+        let mut server = mockito::Server::new_async().await;
+        mock_token(&mut server).await;
+        server
+            .mock("POST", "/v2/user")
+            .with_status(409)
+            .create_async()
+            .await;
+        let client = CisClient::from_settings(&settings_for(&server)).await?;
+        assert!(matches!(
+            client.create_user(Profile::default()).await,
+            Err(CisClientError::ProfileError(
+                ProfileError::ProfileAlreadyExists
+            ))
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn get_batch_sends_page_size() -> Result<(), Error> {
+        let mut server = mockito::Server::new_async().await;
+        mock_token(&mut server).await;
+        let users = server
+            .mock("GET", "/v2/users")
+            .match_query(mockito::Matcher::UrlEncoded(
+                "nextPageSize".into(),
+                "10".into(),
+            ))
+            .with_body(r#"{"Items":[]}"#)
+            .create_async()
+            .await;
+        let client = CisClient::from_settings(&settings_for(&server))
+            .await?
+            .with_batch_size(10)?;
+        client.get_batch(&None, &None).await?;
+        users.assert_async().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn get_user_url_per_get_by() -> Result<(), Error> {
+        let server = mockito::Server::new_async().await;
+        let client = CisClient::from_settings(&settings_for(&server)).await?;
+        for (by, path) in &[
+            (GetBy::Uuid, "/v2/user/uuid/some%7Cid"),
+            (GetBy::UserId, "/v2/user/user_id/some%7Cid"),
+            (GetBy::PrimaryEmail, "/v2/user/primary_email/some%7Cid"),
+            (
+                GetBy::PrimaryUsername,
+                "/v2/user/primary_username/some%7Cid",
+            ),
+            (GetBy::HrisEmail, "/v2/user/hris_email/some%7Cid"),
+            (GetBy::LdapUsername, "/v2/user/ldap_username/some%7Cid"),
+        ] {
+            let url = client.get_user_url("some|id", by, None, ActiveFilter::Any)?;
+            assert_eq!(url.path(), *path);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn get_user_by_hris_email_and_ldap_username() -> Result<(), Error> {
+        let mut server = mockito::Server::new_async().await;
+        mock_token(&mut server).await;
+        let hris = server
+            .mock("GET", "/v2/user/hris_email/hknall%40mozilla.com")
+            .match_query(mockito::Matcher::Any)
+            .with_body(profile_body("hris-uuid"))
+            .create_async()
+            .await;
+        let ldap = server
+            .mock("GET", "/v2/user/ldap_username/hknall")
+            .match_query(mockito::Matcher::Any)
+            .with_body(profile_body("ldap-uuid"))
+            .create_async()
+            .await;
+        let client = CisClient::from_settings(&settings_for(&server)).await?;
+        let profile = client
+            .get_user_by("hknall@mozilla.com", &GetBy::HrisEmail, None)
+            .await?;
+        assert_eq!(profile.uuid.value.as_deref(), Some("hris-uuid"));
+        let profile = client
+            .get_user_by("hknall", &GetBy::LdapUsername, None)
+            .await?;
+        assert_eq!(profile.uuid.value.as_deref(), Some("ldap-uuid"));
+        hris.assert_async().await;
+        ldap.assert_async().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn get_user_url_with_and_without_trailing_slash() -> Result<(), Error> {
+        let server = mockito::Server::new_async().await;
+        let mut client = CisClient::from_settings(&settings_for(&server)).await?;
+        for base in &[
+            "https://person.example.com/v2/user/",
+            "https://person.example.com/v2/user",
+        ] {
+            client.person_api_user_endpoint = Url::parse(base)?;
+            let url = client.get_user_url("some-id", &GetBy::UserId, None, ActiveFilter::Active)?;
+            assert_eq!(
+                url.as_str(),
+                "https://person.example.com/v2/user/user_id/some-id?active=true"
+            );
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn get_user_without_active_param() -> Result<(), Error> {
+        let mut server = mockito::Server::new_async().await;
+        mock_token(&mut server).await;
+        let user = server
+            .mock("GET", "/v2/user/uuid/some-uuid")
+            .match_query(mockito::Matcher::Regex(String::from("^$")))
+            .with_body(profile_body("some-uuid"))
+            .create_async()
+            .await;
+        let client = CisClientBuilder::from_settings(&settings_for(&server))
+            .omit_active_param()
+            .build()?;
+        let url =
+            client.get_user_url("some-uuid", &GetBy::Uuid, Some("staff"), ActiveFilter::Any)?;
+        assert!(url.query_pairs().all(|(key, _)| key != "active"));
+        client.get_user_by("some-uuid", &GetBy::Uuid, None).await?;
+        user.assert_async().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn get_user_url_targets_requested_id() -> Result<(), Error> {
+        let server = mockito::Server::new_async().await;
+        let client = CisClient::from_settings(&settings_for(&server)).await?;
+        for id in &[
+            "ad|a/b",
+            "a#b",
+            "a?active=false",
+            "a%2F..",
+            ".hidden",
+            "../a",
+            "jürgen",
+        ] {
+            let url = client.get_user_url(id, &GetBy::UserId, None, ActiveFilter::Active)?;
+            let segments: Vec<&str> = url.path_segments().into_iter().flatten().collect();
+            assert_eq!(segments.len(), 4, "{}", url);
+            assert_eq!(segments[2], "user_id");
+            assert_eq!(
+                percent_encoding::percent_decode_str(segments[3]).decode_utf8()?,
+                *id
+            );
+            assert_eq!(url.fragment(), None);
+            assert_eq!(url.query(), Some("active=true"));
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn get_user_url_rejects_empty_id() -> Result<(), Error> {
+        let server = mockito::Server::new_async().await;
+        let client = CisClient::from_settings(&settings_for(&server)).await?;
+        for id in &["", ".", ".."] {
+            assert!(matches!(
+                client.get_user_url(id, &GetBy::Uuid, None, ActiveFilter::Any),
+                Err(CisClientError::InvalidUserId(_))
+            ));
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn writes_reject_empty_id() -> Result<(), Error> {
+        let mut server = mockito::Server::new_async().await;
+        mock_token(&mut server).await;
+        let mut writes = Vec::new();
+        for method in &["POST", "DELETE"] {
+            let write = server
+                .mock(method, "/v2/user")
+                .match_query(mockito::Matcher::Any)
+                .expect(0)
+                .create_async()
+                .await;
+            writes.push(write);
+        }
+        let client = CisClient::from_settings(&settings_for(&server)).await?;
+        let original = Profile::default();
+        let mut modified = original.clone();
+        modified.primary_email.value = Some(String::from("alice@example.com"));
+        let results = vec![
+            client
+                .update_user_raw("", Profile::default())
+                .await
+                .map(drop),
+            client
+                .delete_user_raw("", Profile::default())
+                .await
+                .map(drop),
+            client
+                .update_user_changes("", &original, modified)
+                .await
+                .map(drop),
+        ];
+        for result in results {
+            assert!(matches!(result, Err(CisClientError::InvalidUserId(_))));
+        }
+        for write in writes {
+            write.assert_async().await;
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn with_http_client_is_used() -> Result<(), Error> {
+        let mut server = mockito::Server::new_async().await;
+        mock_token(&mut server).await;
+        let user = server
+            .mock("GET", "/v2/user/uuid/some-uuid")
+            .match_query(mockito::Matcher::Any)
+            .match_header("x-injected", "yes")
+            .with_body(profile_body("some-uuid"))
+            .create_async()
+            .await;
+        let mut headers = reqwest::header::HeaderMap::new();
+        headers.insert("x-injected", "yes".parse()?);
+        let http_client = Client::builder().default_headers(headers).build()?;
+        let client = CisClient::from_settings(&settings_for(&server))
+            .await?
+            .with_http_client(http_client);
+        client.get_user_by("some-uuid", &GetBy::Uuid, None).await?;
+        user.assert_async().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn delete_users_reports_failed_chunk() -> Result<(), Error> {
+        let mut server = mockito::Server::new_async().await;
+        mock_token(&mut server).await;
+        let ok = server
+            .mock("DELETE", "/v2/users")
+            .with_body(r#"{"status":"deleted"}"#)
+            .expect(1)
+            .create_async()
+            .await;
+        let failed = server
+            .mock("DELETE", "/v2/users")
+            .with_status(500)
+            .expect(1)
+            .create_async()
+            .await;
+        let client = CisClient::from_settings(&settings_for(&server))
+            .await?
+            .with_batch_size(2)?;
+        match client.delete_users(&vec![Profile::default(); 3]).await {
+            Err(CisClientError::ChunkFailed { chunk, source }) => {
+                assert_eq!(chunk, 1);
+                assert!(matches!(
+                    *source,
+                    CisClientError::ApiError { status: 500, .. }
+                ));
+            }
+            r => panic!("unexpected result: {:?}", r),
+        }
+        ok.assert_async().await;
+        failed.assert_async().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn get_user_by_active_query() -> Result<(), Error> {
+        let mut server = mockito::Server::new_async().await;
+        mock_token(&mut server).await;
+        let mut mocks = Vec::new();
+        for active in &["true", "false", "any"] {
+            let mock = server
+                .mock("GET", "/v2/user/uuid/some-uuid")
+                .match_query(mockito::Matcher::UrlEncoded(
+                    "active".into(),
+                    active.to_string(),
+                ))
+                .with_body(profile_body("some-uuid"))
+                .expect(1)
+                .create_async()
+                .await;
+            mocks.push(mock);
+        }
+        let client = CisClient::from_settings(&settings_for(&server)).await?;
+        client.get_user_by("some-uuid", &GetBy::Uuid, None).await?;
+        client
+            .get_inactive_user_by("some-uuid", &GetBy::Uuid, None)
+            .await?;
+        client
+            .get_any_user_by("some-uuid", &GetBy::Uuid, None)
+            .await?;
+        for mock in mocks {
+            mock.assert_async().await;
+        }
+        Ok(())
+    }
+
+    #[derive(Default)]
+    struct RecordingMetrics {
+        events: std::sync::Mutex<Vec<String>>,
+    }
+
+    impl CisMetrics for RecordingMetrics {
+        fn on_request(&self, op: &'static str, method: &Method) {
+            self.events
+                .lock()
+                .unwrap()
+                .push(format!("begin {} {}", op, method));
+        }
+        fn on_response(&self, op: &'static str, status: Option<u16>, _: std::time::Duration) {
+            self.events
+                .lock()
+                .unwrap()
+                .push(format!("end {} {:?}", op, status));
+        }
+    }
+
+    #[derive(Default)]
+    struct InFlightMetrics {
+        current: std::sync::atomic::AtomicUsize,
+        max: std::sync::atomic::AtomicUsize,
+    }
+
+    impl CisMetrics for InFlightMetrics {
+        fn on_request(&self, _: &'static str, _: &Method) {
+            use std::sync::atomic::Ordering;
+            let current = self.current.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max.fetch_max(current, Ordering::SeqCst);
+        }
+        fn on_response(&self, _: &'static str, _: Option<u16>, _: std::time::Duration) {
+            self.current
+                .fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
+        }
+    }
+
+    #[tokio::test]
+    async fn max_concurrent_requests_limits_in_flight() -> Result<(), Error> {
+        let mut server = mockito::Server::new_async().await;
+        mock_token(&mut server).await;
+        let user = server
+            .mock("GET", "/v2/user/uuid/some-uuid")
+            .match_query(mockito::Matcher::Any)
+            .with_body(profile_body("some-uuid"))
+            .expect(50)
+            .create_async()
+            .await;
+        let metrics = Arc::new(InFlightMetrics::default());
+        let client = CisClientBuilder::from_settings(&settings_for(&server))
+            .metrics(Arc::clone(&metrics) as Arc<dyn CisMetrics>)
+            .max_concurrent_requests(4)
+            .build()?;
+        let calls = (0..50).map(|_| client.get_user_by("some-uuid", &GetBy::Uuid, None));
+        for res in future::join_all(calls).await {
+            res?;
+        }
+        user.assert_async().await;
+        let max = metrics.max.load(std::sync::atomic::Ordering::SeqCst);
+        assert!(max <= 4, "{} requests in flight", max);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn max_concurrent_requests_frees_slot_during_backoff() -> Result<(), Error> {
+        let mut server = mockito::Server::new_async().await;
+        mock_token(&mut server).await;
+        server
+            .mock("GET", "/v2/user/uuid/busy")
+            .match_query(mockito::Matcher::Any)
+            .with_status(503)
+            .expect(2)
+            .create_async()
+            .await;
+        server
+            .mock("GET", "/v2/user/uuid/idle")
+            .match_query(mockito::Matcher::Any)
+            .with_body(profile_body("idle"))
+            .create_async()
+            .await;
+        let client = CisClientBuilder::from_settings(&settings_for(&server))
+            .retry_config(RetryConfig {
+                max_attempts: 2,
+                base_delay: Duration::from_secs(5),
+                max_delay: Duration::from_secs(5),
+                jitter: retry::Jitter::None,
+            })
+            .max_concurrent_requests(1)
+            .build()?;
+        let busy = client.get_user_by("busy", &GetBy::Uuid, None);
+        let idle = client.get_user_by("idle", &GetBy::Uuid, None);
+        match future::select(busy, idle).await {
+            future::Either::Right((res, _)) => {
+                res?;
+            }
+            future::Either::Left(_) => panic!("slot held during backoff"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn max_concurrent_requests_rejects_zero() {
+        assert!(matches!(
+            CisClientBuilder::from_settings(&CisSettings::default())
+                .max_concurrent_requests(0)
+                .build(),
+            Err(CisClientError::InvalidConcurrencyLimit)
+        ));
+    }
+
+    #[tokio::test]
+    async fn metrics_record_one_request() -> Result<(), Error> {
+        let mut server = mockito::Server::new_async().await;
+        mock_token(&mut server).await;
+        server
+            .mock("POST", "/v2/user")
+            .match_query(mockito::Matcher::Any)
+            .with_status(422)
+            .create_async()
+            .await;
+        let metrics = Arc::new(RecordingMetrics::default());
+        let client = CisClientBuilder::from_settings(&settings_for(&server))
+            .metrics(Arc::clone(&metrics) as Arc<dyn CisMetrics>)
+            .build()?;
+        assert!(client
+            .update_user("some-id", Profile::default())
+            .await
+            .is_err());
+        assert_eq!(
+            *metrics.events.lock().unwrap(),
+            vec!["begin update_user POST", "end update_user Some(422)"]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn get_batch_does_not_log_at_info() -> Result<(), Error> {
+        let logs = LogCapture::start();
+        let mut server = mockito::Server::new_async().await;
+        mock_token(&mut server).await;
+        server
+            .mock("GET", "/v2/users")
+            .match_query(mockito::Matcher::Any)
+            .with_body(r#"{"Items":[]}"#)
+            .create_async()
+            .await;
+        let client = CisClient::from_settings(&settings_for(&server)).await?;
+        client
+            .get_batch(&None, &Some(String::from("staff")))
+            .await?;
+        assert!(logs.logged());
+        assert!(!logs.logged_info());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn get_user_count_reads_total() -> Result<(), Error> {
+        let mut server = mockito::Server::new_async().await;
+        mock_token(&mut server).await;
+        server
+            .mock("GET", "/v2/users")
+            .match_query(mockito::Matcher::UrlEncoded(
+                "filterDisplay".into(),
+                "staff".into(),
+            ))
+            .with_body(r#"{"Items":[],"total":1234}"#)
+            .create_async()
+            .await;
+        server
+            .mock("GET", "/v2/users")
+            .match_query(mockito::Matcher::UrlEncoded(
+                "filterDisplay".into(),
+                "public".into(),
+            ))
+            .with_body(r#"{"Items":[]}"#)
+            .create_async()
+            .await;
+        let client = CisClient::from_settings(&settings_for(&server)).await?;
+        assert_eq!(client.get_user_count(Some("staff")).await?, 1234);
+        assert!(matches!(
+            client.get_user_count(Some("public")).await,
+            Err(CisClientError::CountUnavailable)
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn with_batch_size_rejects_zero() -> Result<(), Error> {
+        let client = CisClient::from_settings(&Default::default()).await?;
+        assert!(matches!(
+            client.clone().with_batch_size(0),
+            Err(CisClientError::InvalidBatchSize)
+        ));
+        assert_eq!(client.with_batch_size(2)?.batch_size, 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reload_secret_store_signs_with_rotated_key() -> Result<(), Error> {
+        fn sign(client: &CisClient) -> Result<Option<String>, Error> {
+            let mut profile = Profile::default();
+            profile.uuid.value = Some(String::from("some-uuid"));
+            profile.uuid.signature.publisher.name = PublisherAuthority::Cis;
+            client.sign_profile(&mut profile, PublisherAuthority::Cis)?;
+            Ok(profile.uuid.signature.publisher.value)
+        }
+        let key_file =
+            std::env::temp_dir().join(format!("cis_client_reload_{}.json", std::process::id()));
+        std::fs::copy("tests/data/fake_key.json", &key_file)?;
+        let mut settings = CisSettings::default();
+        settings.sign_keys.source = crate::settings::KeySource::File;
+        settings.sign_keys.cis_key = Some(key_file.display().to_string());
+        let client = CisClient::from_settings(&settings).await?;
+        let before = sign(&client)?;
+        let in_flight = client.current_secret_store();
+
+        std::fs::copy("tests/data/fake_key_rotated.json", &key_file)?;
+        let reloaded = client.reload_secret_store().await;
+        std::fs::remove_file(&key_file)?;
+        reloaded?;
+        assert!(!Arc::ptr_eq(&in_flight, &client.current_secret_store()));
+        assert!(Arc::ptr_eq(
+            &client.current_secret_store(),
+            &client.clone().current_secret_store()
+        ));
+        // the store the client was built with stays available
+        assert!(std::ptr::eq(
+            client.get_secret_store(),
+            &*client.secret_store
+        ));
+
+        // signatures are deterministic, expect the one of the rotated key
+        let key = std::fs::read_to_string("tests/data/fake_key_rotated.json")?;
+        let rotated = CisClientBuilder::from_settings(&CisSettings::default())
+            .secret_store(
+                SecretStore::default()
+                    .with_sign_keys_from_inline_iter(vec![(String::from("cis"), key)])?,
+            )
+            .build()?;
+        let after = sign(&client)?;
+        assert_ne!(before, after);
+        assert_eq!(after, sign(&rotated)?);
+        assert!(matches!(
+            rotated.reload_secret_store().await,
+            Err(CisClientError::SecretStoreNotReloadable)
+        ));
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn secret_store_reloads_rotated_well_known_keys() -> Result<(), Error> {
+        fn well_known(private_key: &str) -> Result<String, Error> {
+            let key: Value = serde_json::from_str(&std::fs::read_to_string(private_key)?)?;
+            let public_key = serde_json::json!({
+                "kty": key["kty"],
+                "e": key["e"],
+                "n": key["n"],
+                "kid": key["kid"],
+                "use": key["use"],
+            });
+            let doc = serde_json::json!({
+                "api": { "publishers_jwks": { "cis": { "keys": [public_key] } } }
+            });
+            Ok(doc.to_string())
+        }
+        let mut server = mockito::Server::new_async().await;
+        let original = server
+            .mock("GET", "/.well-known/mozilla-iam")
+            .with_body(well_known("tests/data/fake_key.json")?)
+            .create_async()
+            .await;
+        let mut settings = CisSettings::default();
+        settings.verify_keys.source = crate::settings::KeySource::WellKnown;
+        settings.verify_keys.well_known_iam_endpoint =
+            Some(format!("{}/.well-known/mozilla-iam", server.url()).parse()?);
+        let interval = Duration::from_secs(600);
+        let client = CisClientBuilder::from_settings(&settings)
+            .secret_store_reload_interval(interval)
+            .build_async()
+            .await?;
+
+        let key = std::fs::read_to_string("tests/data/fake_key_rotated.json")?;
+        let signer = CisClientBuilder::from_settings(&CisSettings::default())
+            .secret_store(
+                SecretStore::default()
+                    .with_sign_keys_from_inline_iter(vec![(String::from("cis"), key)])?,
+            )
+            .build()?;
+        let mut profile = Profile::default();
+        profile.uuid.value = Some(String::from("some-uuid"));
+        profile.uuid.signature.publisher.name = PublisherAuthority::Cis;
+        signer.sign_profile(&mut profile, PublisherAuthority::Cis)?;
+        let verifies = |client: &CisClient| {
+            signing::check_signatures(&client.current_secret_store(), &profile)
+        };
+        assert!(verifies(&client).is_err());
+
+        original.remove_async().await;
+        let rotated = server
+            .mock("GET", "/.well-known/mozilla-iam")
+            .with_body(well_known("tests/data/fake_key_rotated.json")?)
+            .create_async()
+            .await;
+        let initial = client.current_secret_store();
+        tokio::time::advance(interval).await;
+        // the reload fetches in the background, let it complete
+        while Arc::ptr_eq(&initial, &client.current_secret_store()) {
+            tokio::task::yield_now().await;
+        }
+        rotated.assert_async().await;
+        verifies(&client)?;
+
+        // failing reloads keep the last store
+        rotated.remove_async().await;
+        let failing = server
+            .mock("GET", "/.well-known/mozilla-iam")
+            .with_status(503)
+            .create_async()
+            .await;
+        tokio::time::advance(interval).await;
+        while !failing.matched_async().await {
+            tokio::task::yield_now().await;
+        }
+        verifies(&client)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn bearer_token_retries_on_503() -> Result<(), Error> {
+        let mut server = mockito::Server::new_async().await;
+        let unavailable = server
+            .mock("POST", "/oauth/token")
+            .with_status(503)
+            .expect(1)
+            .create_async()
+            .await;
+        let token = mock_token(&mut server).await;
+        let client = CisClientBuilder::from_settings(&settings_for(&server))
+            .token_retry_config(RetryConfig {
+                base_delay: std::time::Duration::from_millis(1),
+                ..Default::default()
+            })
+            .build()?;
+        assert_eq!(client.bearer_token().await?, FAKE_TOKEN);
+        unavailable.assert_async().await;
+        token.assert_async().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn concurrent_bearer_token_refreshes_once() -> Result<(), Error> {
+        let mut server = mockito::Server::new_async().await;
+        let token = server
+            .mock("POST", "/oauth/token")
+            .with_body_from_request(|_| {
+                std::thread::sleep(Duration::from_millis(50));
+                token_body().into()
+            })
+            .expect(1)
+            .create_async()
+            .await;
+        let mut settings = settings_for(&server);
+        settings.client_config.client_id = String::from("some-client");
+        let exp = Utc::now() - chrono::Duration::minutes(1);
+        let client = CisClient::with_static_token(String::from("expired"), exp, &settings).await?;
+        let tokens = future::try_join_all((0..20).map(|_| {
+            tokio::spawn({
+                let client = client.clone();
+                async move { client.bearer_token().await }
+            })
+        }))
+        .await?;
+        for bearer in tokens {
+            assert_eq!(bearer?, FAKE_TOKEN);
+        }
+        token.assert_async().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn scoped_tokens_are_cached_per_scopes() -> Result<(), Error> {
+        let mut server = mockito::Server::new_async().await;
+        let mut tokens = Vec::new();
+        for scopes in &["read:profile", "write:profile"] {
+            let token = server
+                .mock("POST", "/oauth/token")
+                .match_body(mockito::Matcher::UrlEncoded(
+                    "scope".into(),
+                    scopes.to_string(),
+                ))
+                .with_body(token_body())
+                .expect(1)
+                .create_async()
+                .await;
+            tokens.push(token);
+        }
+        server
+            .mock("GET", "/v2/user/uuid/some-uuid")
+            .match_query(mockito::Matcher::Any)
+            .with_body(profile_body("some-uuid"))
+            .create_async()
+            .await;
+        let client = CisClient::from_settings(&settings_for(&server)).await?;
+        for _ in 0..2 {
+            for scopes in &["read:profile", "write:profile"] {
+                client
+                    .scoped(scopes)
+                    .get_user_by("some-uuid", &GetBy::Uuid, None)
+                    .await?;
+            }
+        }
+        assert!(client.token_expiry().is_none());
+        assert!(client.scoped("read:profile").token_expiry().is_some());
+        for token in tokens {
+            token.assert_async().await;
+        }
+        Ok(())
+    }
+
+    fn stalling_client(scheme: &str, addr: std::net::SocketAddr) -> CisClientBuilder {
+        let url = Url::parse(&format!("{}://{}/v2/users", scheme, addr)).unwrap();
+        CisClientBuilder::new()
+            .person_api_user_endpoint(url.clone())
+            .person_api_users_endpoint(url.clone())
+            .change_api_user_endpoint(url.clone())
+            .change_api_users_endpoint(url)
+            .static_token(
+                String::from(FAKE_TOKEN),
+                Utc::now() + chrono::Duration::hours(1),
+            )
+            .retry_config(RetryConfig::none())
+    }
+
+    #[tokio::test]
+    async fn stalled_handshake_is_connect_timeout() -> Result<(), Error> {
+        let client = stalling_client("https", stalling_server(b""))
+            .connect_timeout(Duration::from_millis(200))
+            .build_async()
+            .await?;
+        match client.get_batch(&None, &None).await {
+            Err(CisClientError::ConnectTimeout) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn stalled_body_is_read_timeout() -> Result<(), Error> {
+        let response = b"HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: 100\r\n\r\n{\"Items\": [";
+        let addr = stalling_server(response);
+        for stream_batches in &[false, true] {
+            let mut builder = stalling_client("http", addr)
+                .connect_timeout(Duration::from_secs(5))
+                .read_timeout(Duration::from_millis(200));
+            if *stream_batches {
+                builder = builder.stream_batches();
+            }
+            let client = builder.build_async().await?;
+            match client.get_batch(&None, &None).await {
+                Err(CisClientError::ReadTimeout) => {}
+                r => panic!("unexpected result: {:?}", r),
+            }
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn stalled_response_exceeds_deadline() -> Result<(), Error> {
+        let client = stalling_client("http", stalling_server(b""))
+            .read_timeout(Duration::from_secs(5))
+            .deadline(Duration::from_millis(200))
+            .build_async()
+            .await?;
+        match client.get_user_count(None).await {
+            Err(CisClientError::DeadlineExceeded) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn static_token_skips_token_endpoint() -> Result<(), Error> {
+        let mut server = mockito::Server::new_async().await;
+        let token = server
+            .mock("POST", "/oauth/token")
+            .expect(0)
+            .create_async()
+            .await;
+        let user = server
+            .mock("GET", "/v2/user/uuid/some-uuid")
+            .match_query(mockito::Matcher::Any)
+            .match_header("authorization", "Bearer static-token")
+            .with_body(profile_body("some-uuid"))
+            .create_async()
+            .await;
+        let exp = Utc::now() + chrono::Duration::hours(1);
+        let client =
+            CisClient::with_static_token(String::from("static-token"), exp, &settings_for(&server))
+                .await?;
+        client.get_user_by("some-uuid", &GetBy::Uuid, None).await?;
+        user.assert_async().await;
+        token.assert_async().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn expired_static_token_without_credentials_fails() -> Result<(), Error> {
+        let exp = Utc::now() - chrono::Duration::hours(1);
+        let client =
+            CisClient::with_static_token(String::from("static-token"), exp, &Default::default())
+                .await?;
+        assert!(client.bearer_token().await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn static_token_within_leeway_is_replaced() -> Result<(), Error> {
+        let mut server = mockito::Server::new_async().await;
+        let token = mock_token(&mut server).await;
+        let mut settings = settings_for(&server);
+        settings.client_config.client_id = String::from("client-id");
+        settings.client_config.clock_skew_leeway_secs = 60;
+        let exp = Utc::now() + chrono::Duration::seconds(30);
+        let client =
+            CisClient::with_static_token(String::from("static-token"), exp, &settings).await?;
+        assert_ne!(client.bearer_token().await?, "static-token");
+        token.assert_async().await;
+
+        settings.client_config.client_id = String::new();
+        let client =
+            CisClient::with_static_token(String::from("static-token"), exp, &settings).await?;
+        assert!(client.bearer_token().await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn token_expiry_is_none_before_fetch() -> Result<(), Error> {
+        let client = CisClient::from_settings(&Default::default()).await?;
+        assert!(client.token_expiry().is_none());
+        assert!(client.token_ttl().is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn token_expiry_after_fetch() -> Result<(), Error> {
+        let mut server = mockito::Server::new_async().await;
+        mock_token(&mut server).await;
+        let client = CisClient::from_settings(&settings_for(&server)).await?;
+        client.bearer_token().await?;
+        let exp = client.token_expiry().expect("token expiry");
+        assert_eq!(exp.timestamp(), 4102444800);
+        assert!(client.token_ttl().expect("token ttl") > chrono::Duration::zero());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn bearer_token_with_expiry_refreshes_expired_token() -> Result<(), Error> {
+        let mut server = mockito::Server::new_async().await;
+        let token = mock_token(&mut server).await;
+        let mut settings = settings_for(&server);
+        settings.client_config.client_id = String::from("client");
+        let client = CisClientBuilder::from_settings(&settings)
+            .static_token(
+                String::from("expired-token"),
+                Utc::now() - chrono::Duration::minutes(1),
+            )
+            .build()?;
+        let (bearer, exp) = client.bearer_token_with_expiry().await?;
+        assert_eq!(bearer, FAKE_TOKEN);
+        // exp claim of FAKE_TOKEN
+        assert_eq!(exp.timestamp(), 4102444800);
+        token.assert_async().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn get_user_raw_by_keeps_unknown_fields() -> Result<(), Error> {
+        let mut server = mockito::Server::new_async().await;
+        mock_token(&mut server).await;
+        server
+            .mock("GET", "/v2/user/uuid/some-uuid")
+            .match_query(mockito::Matcher::UrlEncoded("active".into(), "true".into()))
+            .with_body(r#"{"uuid":{"value":"some-uuid"},"not_in_schema":{"value":42}}"#)
+            .create_async()
+            .await;
+        let client = CisClient::from_settings(&settings_for(&server)).await?;
+        let raw = client
+            .get_user_raw_by("some-uuid", &GetBy::Uuid, None)
+            .await?;
+        assert_eq!(raw["not_in_schema"]["value"], 42);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn get_user_by_unchecked_accepts_filtered_profile() -> Result<(), Error> {
+        let mut server = mockito::Server::new_async().await;
+        mock_token(&mut server).await;
+        let mut profile = Profile::default();
+        profile.primary_email.value = Some(String::from("alice@example.com"));
+        server
+            .mock("GET", "/v2/user/user_id/ad%7Calice")
+            .match_query(mockito::Matcher::Any)
+            .with_body(serde_json::to_string(&profile)?)
+            .create_async()
+            .await;
+        let client = CisClient::from_settings(&settings_for(&server)).await?;
+        assert!(matches!(
+            client
+                .get_user_by("ad|alice", &GetBy::UserId, Some("public"))
+                .await,
+            Err(CisClientError::ProfileError(
+                ProfileError::ProfileDoesNotExist
+            ))
+        ));
+        let profile = client
+            .get_user_by_unchecked("ad|alice", &GetBy::UserId, Some("public"))
+            .await?;
+        assert_eq!(
+            profile.primary_email.value.as_deref(),
+            Some("alice@example.com")
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn get_user_by_404_and_empty_profile_are_missing() -> Result<(), Error> {
+        let mut server = mockito::Server::new_async().await;
+        mock_token(&mut server).await;
+        server
+            .mock("GET", "/v2/user/uuid/not-found")
+            .match_query(mockito::Matcher::Any)
+            .with_status(404)
+            .create_async()
+            .await;
+        server
+            .mock("GET", "/v2/user/uuid/empty")
+            .match_query(mockito::Matcher::Any)
+            .with_body(serde_json::to_string(&Profile::default())?)
+            .create_async()
+            .await;
+        let client = CisClient::from_settings(&settings_for(&server)).await?;
+        for id in &["not-found", "empty"] {
+            assert!(matches!(
+                client.get_user_by(id, &GetBy::Uuid, None).await,
+                Err(CisClientError::ProfileError(
+                    ProfileError::ProfileDoesNotExist
+                ))
+            ));
+        }
+        Ok(())
     }
 }
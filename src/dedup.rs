@@ -0,0 +1,102 @@
+use cis_profile::schema::Profile;
+use std::collections::HashSet;
+use std::collections::VecDeque;
+
+/// Remembers the keys of yielded profiles to drop repeated ones. Profiles
+/// are keyed on `uuid`, falling back to `user_id`. Profiles with neither are
+/// always kept.
+pub(crate) struct Dedup {
+    window: Option<usize>,
+    seen: HashSet<String>,
+    order: VecDeque<String>,
+}
+
+impl Dedup {
+    /// Remember the last `window` keys or all keys for `None`.
+    pub(crate) fn new(window: Option<usize>) -> Self {
+        Dedup {
+            window,
+            seen: HashSet::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// Remove profiles seen before from `profiles`.
+    pub(crate) fn retain_new(&mut self, profiles: &mut Vec<Profile>) {
+        profiles.retain(|profile| match key(profile) {
+            Some(key) => self.insert(key),
+            None => true,
+        });
+    }
+
+    /// Whether `key` is new.
+    fn insert(&mut self, key: String) -> bool {
+        let window = match self.window {
+            Some(window) => window,
+            None => return self.seen.insert(key),
+        };
+        if !self.seen.insert(key.clone()) {
+            return false;
+        }
+        self.order.push_back(key);
+        if self.order.len() > window {
+            if let Some(oldest) = self.order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+        true
+    }
+}
+
+fn key(profile: &Profile) -> Option<String> {
+    match (&profile.uuid.value, &profile.user_id.value) {
+        (Some(uuid), _) => Some(format!("uuid:{}", uuid)),
+        (None, Some(user_id)) => Some(format!("user_id:{}", user_id)),
+        (None, None) => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn profiles(uuids: &[&str]) -> Vec<Profile> {
+        uuids
+            .iter()
+            .map(|uuid| {
+                let mut profile = Profile::default();
+                profile.uuid.value = Some((*uuid).to_owned());
+                profile
+            })
+            .collect()
+    }
+
+    fn uuids(profiles: &[Profile]) -> Vec<&str> {
+        profiles
+            .iter()
+            .filter_map(|p| p.uuid.value.as_deref())
+            .collect()
+    }
+
+    #[test]
+    fn window_forgets_oldest_keys() {
+        let mut dedup = Dedup::new(Some(2));
+        let mut page = profiles(&["a", "b", "c"]);
+        dedup.retain_new(&mut page);
+        assert_eq!(uuids(&page), vec!["a", "b", "c"]);
+        // only b and c are remembered
+        let mut page = profiles(&["a", "c"]);
+        dedup.retain_new(&mut page);
+        assert_eq!(uuids(&page), vec!["a"]);
+
+        let mut dedup = Dedup::new(None);
+        let mut page = profiles(&["a", "b", "c"]);
+        dedup.retain_new(&mut page);
+        let mut page = profiles(&["a", "c", "d"]);
+        dedup.retain_new(&mut page);
+        assert_eq!(uuids(&page), vec!["d"]);
+        let mut anonymous = vec![Profile::default(), Profile::default()];
+        dedup.retain_new(&mut anonymous);
+        assert_eq!(anonymous.len(), 2);
+    }
+}
@@ -0,0 +1,71 @@
+use crate::error::CisClientError;
+use crate::signing;
+use cis_profile::crypto::SecretStore;
+use cis_profile::schema::Profile;
+use cis_profile::schema::PublisherAuthority;
+use serde_json::Map;
+use serde_json::Value;
+
+/// The attributes of `modified` differing from `original`, re-signed with the
+/// key of `realm` or else of their publisher, or `None` if nothing changed.
+pub(crate) fn signed_changes(
+    secret_store: &SecretStore,
+    original: &Profile,
+    modified: &mut Profile,
+    realm: Option<PublisherAuthority>,
+) -> Result<Option<Map<String, Value>>, CisClientError> {
+    let changed = changed_attributes(original, modified)?;
+    if changed.is_empty() {
+        return Ok(None);
+    }
+    signing::sign_attributes(secret_store, modified, &changed, realm)?;
+    Ok(Some(partial_profile(modified, &changed)?))
+}
+
+/// Dotted names of the signed attributes differing between `original` and
+/// `modified`, including their signatures and metadata. Top level keys that
+/// differ without a differing signed attribute, such as `schema`, are named
+/// as is and rejected by [signing::sign_attributes].
+fn changed_attributes(
+    original: &Profile,
+    modified: &Profile,
+) -> Result<Vec<String>, serde_json::Error> {
+    let original = serde_json::to_value(original)?;
+    let modified = serde_json::to_value(modified)?;
+    let mut changed: Vec<String> = signing::attribute_names()
+        .into_iter()
+        .filter(|name| attribute(&original, name) != attribute(&modified, name))
+        .map(String::from)
+        .collect();
+    if let (Value::Object(original), Value::Object(modified)) = (&original, &modified) {
+        let unsigned: Vec<String> = modified
+            .iter()
+            .filter(|(name, attr)| original.get(*name) != Some(attr))
+            .filter(|(name, _)| !changed.iter().any(|c| top_level(c) == name.as_str()))
+            .map(|(name, _)| name.clone())
+            .collect();
+        changed.extend(unsigned);
+    }
+    Ok(changed)
+}
+
+fn attribute<'a>(profile: &'a Value, name: &str) -> Option<&'a Value> {
+    profile.pointer(&format!("/{}", name.replace('.', "/")))
+}
+
+fn top_level(name: &str) -> &str {
+    name.split('.').next().unwrap_or(name)
+}
+
+/// `profile` reduced to the top level keys holding `attributes`.
+fn partial_profile(
+    profile: &Profile,
+    attributes: &[String],
+) -> Result<Map<String, Value>, serde_json::Error> {
+    let mut profile = match serde_json::to_value(profile)? {
+        Value::Object(profile) => profile,
+        _ => return Ok(Map::new()),
+    };
+    profile.retain(|name, _| attributes.iter().any(|a| top_level(a) == name));
+    Ok(profile)
+}
@@ -1,10 +1,19 @@
+use crate::error::CisClientError;
+use percent_encoding::utf8_percent_encode;
 use percent_encoding::AsciiSet;
 use percent_encoding::CONTROLS;
 
+/// Characters encoded in ids used as path segment or query value. Besides
+/// delimiters this includes `%` so ids containing escape sequences reach the
+/// server unchanged instead of being decoded into a different id, and `&` and
+/// `+` which would split or alter query values.
 pub const USERINFO_ENCODE_SET: &AsciiSet = &CONTROLS
     .add(b' ')
     .add(b'"')
     .add(b'#')
+    .add(b'%')
+    .add(b'&')
+    .add(b'+')
     .add(b'<')
     .add(b'>')
     .add(b'`')
@@ -21,3 +30,47 @@ pub const USERINFO_ENCODE_SET: &AsciiSet = &CONTROLS
     .add(b']')
     .add(b'^')
     .add(b'|');
+
+/// Encode `id` as a single path segment or query value. Every user id put
+/// into a Person or Change API URL goes through here.
+///
+/// Empty ids and the dot segments `.` and `..` are rejected: URL parsing
+/// resolves dot segments, so they would address the lookup collection or its
+/// parent instead of a user. Other ids starting with dots are plain segments
+/// and kept as they are.
+pub(crate) fn encode_id(id: &str) -> Result<String, CisClientError> {
+    match id {
+        "" | "." | ".." => Err(CisClientError::InvalidUserId(id.to_owned())),
+        _ => Ok(utf8_percent_encode(id, USERINFO_ENCODE_SET).to_string()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn encode_id_edge_cases() -> Result<(), CisClientError> {
+        for (id, encoded) in &[
+            ("ad|some-user", "ad%7Csome-user"),
+            ("a/b", "a%2Fb"),
+            ("a#b", "a%23b"),
+            ("a?b=c", "a%3Fb%3Dc"),
+            ("a%2Fb", "a%252Fb"),
+            ("a&b+c d", "a%26b%2Bc%20d"),
+            (".hidden", ".hidden"),
+            ("...", "..."),
+            ("../a", "..%2Fa"),
+            ("jürgen", "j%C3%BCrgen"),
+        ] {
+            assert_eq!(encode_id(id)?, *encoded);
+        }
+        for id in &["", ".", ".."] {
+            assert!(matches!(
+                encode_id(id),
+                Err(CisClientError::InvalidUserId(_))
+            ));
+        }
+        Ok(())
+    }
+}
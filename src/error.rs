@@ -1,5 +1,22 @@
+use crate::getby::GetBy;
+use chrono::DateTime;
+use chrono::Utc;
 use shared_expiry_get::ExpiryGetError;
 use thiserror::Error;
+use url::Url;
+
+/// Characters of a response body shown in error messages.
+const BODY_SNIPPET_CHARS: usize = 200;
+
+/// Start of `body` for error messages, bodies of gateway error pages can be
+/// long HTML documents.
+fn snippet(body: &str) -> String {
+    let body = body.trim();
+    match body.char_indices().nth(BODY_SNIPPET_CHARS) {
+        Some((end, _)) => format!("{}...", &body[..end]),
+        None => body.to_owned(),
+    }
+}
 
 #[derive(Debug, Error)]
 pub enum CisClientError {
@@ -13,24 +30,136 @@ pub enum CisClientError {
     RemoteError(#[from] ExpiryGetError),
     #[error("request error: {0}")]
     RequestError(#[from] reqwest::Error),
+    #[error("api error ({status}): {}", snippet(body))]
+    ApiError { status: u16, body: String },
+    #[error("response is not json ({status}): {}", snippet(body))]
+    InvalidResponse { status: u16, body: String },
     #[error("url parse error: {0}")]
     UrlParseError(#[from] url::ParseError),
+    #[error("missing endpoint: {0}")]
+    MissingEndpoint(&'static str),
+    #[error("discovery document is missing {0}")]
+    MissingDiscoveryField(&'static str),
+    #[error("invalid endpoint {0}: {1} is not an absolute http(s) url")]
+    InvalidEndpoint(&'static str, Url),
+    #[error("batch size must be greater than 0")]
+    InvalidBatchSize,
+    #[error("max concurrent requests must be greater than 0")]
+    InvalidConcurrencyLimit,
+    #[error("sign or verify keys configured, use build_async")]
+    KeysRequireAsyncBuild,
+    #[error("secret store was passed in and cannot be reloaded")]
+    SecretStoreNotReloadable,
+    #[error("user count not available")]
+    CountUnavailable,
+    #[error("deadline exceeded")]
+    DeadlineExceeded,
+    #[error("timed out connecting")]
+    ConnectTimeout,
+    #[error("timed out reading the response body")]
+    ReadTimeout,
+    #[error("response body exceeds {0} bytes")]
+    ResponseTooLarge(usize),
+    #[error("io error: {0}")]
+    IoError(#[from] std::io::Error),
+    #[cfg(feature = "csv")]
+    #[error("csv error: {0}")]
+    CsvError(#[from] csv::Error),
     #[error("unable to create tokio runtime")]
     RuntimeError,
+    #[error("background task failed: {0}")]
+    TaskFailed(String),
     #[error("invalid next page token: {0}")]
     InvalidNextPage(#[from] serde_json::Error),
+    #[error("resume token was issued for filter {token:?}, not {filter:?}")]
+    ResumeFilterMismatch {
+        token: Option<String>,
+        filter: Option<String>,
+    },
+    #[error("server ignored modifiedSince {since}, got a profile modified {last_modified}")]
+    ModifiedSinceIgnored {
+        since: DateTime<Utc>,
+        last_modified: DateTime<Utc>,
+    },
+    #[error("unknown display level: {0}")]
+    UnknownDisplayLevel(String),
+    #[error("unknown get by: {0}")]
+    UnknownGetBy(String),
+    #[error("invalid user id: {0:?}")]
+    InvalidUserId(String),
+    #[error("profile with {by} {id:?} has no user_id")]
+    MissingUserId { id: String, by: GetBy },
+    #[error("chunk {chunk} failed: {source}")]
+    ChunkFailed {
+        chunk: usize,
+        source: Box<CisClientError>,
+    },
+}
+
+impl CisClientError {
+    /// Whether a timeout or deadline was hit, including [reqwest] timeouts.
+    pub fn is_timeout(&self) -> bool {
+        match self {
+            CisClientError::ConnectTimeout
+            | CisClientError::ReadTimeout
+            | CisClientError::DeadlineExceeded => true,
+            CisClientError::RequestError(e) => e.is_timeout(),
+            CisClientError::ChunkFailed { source, .. } => source.is_timeout(),
+            _ => false,
+        }
+    }
+
+    /// Whether no connection could be established.
+    pub fn is_connect(&self) -> bool {
+        match self {
+            CisClientError::ConnectTimeout => true,
+            CisClientError::RequestError(e) => e.is_connect(),
+            CisClientError::ChunkFailed { source, .. } => source.is_connect(),
+            _ => false,
+        }
+    }
+
+    /// Whether the server answered with an error status.
+    pub fn is_status(&self) -> bool {
+        match self {
+            CisClientError::ApiError { .. } => true,
+            CisClientError::RequestError(e) => e.is_status(),
+            CisClientError::ChunkFailed { source, .. } => source.is_status(),
+            _ => false,
+        }
+    }
 }
 
 #[derive(Debug, Error)]
 pub enum SecretsError {
-    #[error("invalid sign key source: use 'none', 'file' or 'ssm'")]
+    #[error("invalid sign key source: use 'none', 'file', 'file_bundle', 'env', 'ssm' or 'vault'")]
     UseNoneFileSsm,
-    #[error("invalid sign key source: use 'none', 'file' or 'ssm'")]
+    #[error("invalid verify key source: use 'none', 'file', 'file_bundle', 'env', 'ssm', 'vault' or 'wellknown'")]
     UseNoneFileSsmWellKnonw,
     #[error("key error: {0}")]
     KeyError(#[from] cis_profile::error::KeyError),
     #[error("unable to read key from file")]
     FileReadError,
+    #[error("{0} keys from {1} require at least one key field")]
+    KeyFieldsMissing(&'static str, &'static str),
+    #[error("wellknown verify key source requires well_known_iam_endpoint")]
+    WellKnownEndpointMissing,
+    #[error("file_bundle source requires bundle_file")]
+    BundleFileMissing,
+    #[error("invalid key bundle: {0}")]
+    InvalidBundle(#[from] serde_json::Error),
+    #[error("key bundle has no key for realm {0}")]
+    BundleRealmMissing(String),
+    #[error("environment variable {0} not set")]
+    EnvVarMissing(String),
+    #[error("vault source requires vault settings with a token or role_id and secret_id")]
+    VaultConfigMissing,
+    #[error("vault authentication failed ({0})")]
+    VaultAuthFailed(u16),
+    #[error("unable to read {path} from vault ({status})")]
+    VaultReadFailed { path: String, status: u16 },
+    #[error("vault request error: {0}")]
+    VaultRequestError(#[from] reqwest::Error),
 }
 
 #[derive(Debug, Error)]
@@ -39,6 +168,18 @@ pub enum TokenError {
     NoExpiry,
     #[error("no token :/")]
     NoToken,
+    #[error("static token expired and no client credentials configured")]
+    StaticTokenExpired,
+    #[error("auth0 error {error}: {description}")]
+    Auth0Error { error: String, description: String },
+    #[error("unable to load client certificate: {0}")]
+    ClientIdentity(String),
+    #[error("token endpoint returned {status}: {}", snippet(body))]
+    EndpointError { status: u16, body: String },
+    #[error("invalid token signature: {0}")]
+    InvalidSignature(String),
+    #[error("token audience does not match {0}")]
+    AudienceMismatch(String),
     #[error("error fetching token: {0}")]
     FetchError(#[from] reqwest::Error),
     #[error("error parsing token: {0}")]
@@ -49,6 +190,97 @@ pub enum TokenError {
 pub enum ProfileError {
     #[error("profile does not exist")]
     ProfileDoesNotExist,
+    #[error("profile already exists")]
+    ProfileAlreadyExists,
+    #[error("{attribute} is unsigned or has an invalid signature: {source}")]
+    InvalidSignature {
+        attribute: &'static str,
+        source: cis_profile::error::SignerVerifierError,
+    },
+    #[error("unable to sign {attribute}: {source}")]
+    SigningFailed {
+        attribute: &'static str,
+        source: cis_profile::error::SignerVerifierError,
+    },
+    #[error("{0} changed but carries no signature and cannot be re-signed")]
+    UnsignableAttribute(String),
+    #[error("no sign key loaded for {0}")]
+    MissingSignKey(&'static str),
     #[error("invalid profile iter state")]
     InvalidIterState,
+    #[error("profile has fields unknown to cis_profile: {}", .0.join(", "))]
+    UnknownFields(Vec<String>),
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::stalling_server;
+    use std::net::TcpListener;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn classify_timeout() {
+        let url = format!("http://{}/", stalling_server(b""));
+        let e: CisClientError = reqwest::Client::builder()
+            .timeout(Duration::from_millis(100))
+            .build()
+            .unwrap()
+            .get(url)
+            .send()
+            .await
+            .unwrap_err()
+            .into();
+        assert!(e.is_timeout());
+        assert!(!e.is_connect());
+        assert!(!e.is_status());
+        assert!(CisClientError::ReadTimeout.is_timeout());
+        assert!(CisClientError::DeadlineExceeded.is_timeout());
+    }
+
+    #[tokio::test]
+    async fn classify_connect() {
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let e: CisClientError = reqwest::get(format!("http://{}/", addr))
+            .await
+            .unwrap_err()
+            .into();
+        assert!(e.is_connect());
+        assert!(!e.is_timeout());
+        assert!(!e.is_status());
+        assert!(CisClientError::ConnectTimeout.is_connect());
+        assert!(CisClientError::ConnectTimeout.is_timeout());
+    }
+
+    #[tokio::test]
+    async fn classify_status() {
+        let mut server = mockito::Server::new_async().await;
+        server
+            .mock("GET", "/")
+            .with_status(500)
+            .create_async()
+            .await;
+        let e: CisClientError = reqwest::get(server.url())
+            .await
+            .unwrap()
+            .error_for_status()
+            .unwrap_err()
+            .into();
+        assert!(e.is_status());
+        assert!(!e.is_timeout());
+        assert!(!e.is_connect());
+        let api_error = CisClientError::ApiError {
+            status: 404,
+            body: String::new(),
+        };
+        assert!(api_error.is_status());
+        assert!(CisClientError::ChunkFailed {
+            chunk: 0,
+            source: Box::new(api_error),
+        }
+        .is_status());
+    }
 }
@@ -0,0 +1,212 @@
+use crate::client::AsyncCisClientTrait;
+use crate::error::CisClientError;
+use cis_profile::schema::Profile;
+use futures::io::AsyncWrite;
+use futures::io::AsyncWriteExt;
+use futures::TryStreamExt;
+#[cfg(feature = "csv")]
+use serde_json::Value;
+
+/// Outcome of an export. Profiles failing to serialize are skipped and their
+/// errors collected.
+#[derive(Debug, Default)]
+pub struct ExportReport {
+    /// Number of records written.
+    pub written: usize,
+    pub errors: Vec<serde_json::Error>,
+}
+
+impl ExportReport {
+    /// Append one JSON line per profile of `page` to `buf`.
+    pub(crate) fn ndjson_page(&mut self, page: &[Profile], buf: &mut Vec<u8>) {
+        for profile in page {
+            match serde_json::to_vec(profile) {
+                Ok(line) => {
+                    buf.extend_from_slice(&line);
+                    buf.push(b'\n');
+                    self.written += 1;
+                }
+                Err(e) => self.errors.push(e),
+            }
+        }
+    }
+
+    /// Append one CSV row per profile of `page` to `buf` holding the values of
+    /// `fields`. Unset values are written as empty cells.
+    #[cfg(feature = "csv")]
+    pub(crate) fn csv_page(
+        &mut self,
+        fields: &[&str],
+        page: &[Profile],
+        buf: &mut Vec<u8>,
+    ) -> Result<(), CisClientError> {
+        let mut writer = csv::Writer::from_writer(buf);
+        for profile in page {
+            let profile = match serde_json::to_value(profile) {
+                Ok(profile) => profile,
+                Err(e) => {
+                    self.errors.push(e);
+                    continue;
+                }
+            };
+            let row = fields.iter().map(|field| match &profile[field]["value"] {
+                Value::Null => String::new(),
+                Value::String(s) => s.clone(),
+                v => v.to_string(),
+            });
+            writer.write_record(row)?;
+            self.written += 1;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Header row for [export_csv].
+#[cfg(feature = "csv")]
+pub(crate) fn csv_header(fields: &[&str]) -> Result<Vec<u8>, CisClientError> {
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    writer.write_record(fields)?;
+    writer
+        .into_inner()
+        .map_err(|e| CisClientError::IoError(e.into_error()))
+}
+
+/// Write all profiles matching `filter` to `writer` as newline delimited
+/// JSON, one page at a time. `writer` is flushed after every page. Failing
+/// page fetches and writes abort the export.
+pub async fn export_ndjson<T, W>(
+    client: &T,
+    filter: Option<&str>,
+    writer: &mut W,
+) -> Result<ExportReport, CisClientError>
+where
+    T: AsyncCisClientTrait + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut report = ExportReport::default();
+    let mut pages = client.get_users_iter(filter);
+    let mut buf = Vec::new();
+    while let Some(page) = pages.try_next().await? {
+        buf.clear();
+        report.ndjson_page(&page, &mut buf);
+        writer.write_all(&buf).await?;
+        writer.flush().await?;
+    }
+    Ok(report)
+}
+
+/// Write the top level attributes `fields` (e.g. `user_id`, `primary_email`)
+/// of all profiles matching `filter` to `writer` as CSV with a header row.
+/// `writer` is flushed after every page.
+#[cfg(feature = "csv")]
+pub async fn export_csv<T, W>(
+    client: &T,
+    fields: &[&str],
+    filter: Option<&str>,
+    writer: &mut W,
+) -> Result<ExportReport, CisClientError>
+where
+    T: AsyncCisClientTrait + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut report = ExportReport::default();
+    writer.write_all(&csv_header(fields)?).await?;
+    let mut pages = client.get_users_iter(filter);
+    let mut buf = Vec::new();
+    while let Some(page) = pages.try_next().await? {
+        buf.clear();
+        report.csv_page(fields, &page, &mut buf)?;
+        writer.write_all(&buf).await?;
+        writer.flush().await?;
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::client::CisClient;
+    use crate::test_util::*;
+    use anyhow::Error;
+    use mockito::Matcher;
+    use serde_json::json;
+    use serde_json::Value;
+
+    #[tokio::test]
+    async fn export_ndjson_three_pages() -> Result<(), Error> {
+        let mut server = mockito::Server::new_async().await;
+        mock_token(&mut server).await;
+        let profile: Value = serde_json::from_str(&profile_body("some-uuid"))?;
+        server
+            .mock("GET", "/v2/users")
+            .match_query(Matcher::Any)
+            .with_body(json!({ "Items": [profile], "nextPage": { "id": "2" } }).to_string())
+            .expect(1)
+            .create_async()
+            .await;
+        server
+            .mock("GET", "/v2/users")
+            .match_query(Matcher::UrlEncoded(
+                "nextPage".into(),
+                r#"{"id":"2"}"#.into(),
+            ))
+            .with_body(
+                json!({ "Items": [profile, profile], "nextPage": { "id": "3" } }).to_string(),
+            )
+            .expect(1)
+            .create_async()
+            .await;
+        server
+            .mock("GET", "/v2/users")
+            .match_query(Matcher::UrlEncoded(
+                "nextPage".into(),
+                r#"{"id":"3"}"#.into(),
+            ))
+            .with_body(json!({ "Items": [profile] }).to_string())
+            .expect(1)
+            .create_async()
+            .await;
+        let client = CisClient::from_settings(&settings_for(&server)).await?;
+        let mut out = Vec::new();
+        let report = export_ndjson(&client, None, &mut out).await?;
+        assert_eq!(report.written, 4);
+        assert!(report.errors.is_empty());
+        let lines: Vec<&str> = std::str::from_utf8(&out)?.lines().collect();
+        assert_eq!(lines.len(), 4);
+        for line in lines {
+            let profile: Profile = serde_json::from_str(line)?;
+            assert_eq!(profile.uuid.value.as_deref(), Some("some-uuid"));
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "csv")]
+    #[tokio::test]
+    async fn export_csv_selected_fields() -> Result<(), Error> {
+        let mut server = mockito::Server::new_async().await;
+        mock_token(&mut server).await;
+        let mut alice = Profile::default();
+        alice.user_id.value = Some(String::from("ad|alice"));
+        alice.primary_email.value = Some(String::from("alice@example.com"));
+        alice.active.value = Some(true);
+        let mut bob = Profile::default();
+        bob.user_id.value = Some(String::from("ad|bob, jr"));
+        server
+            .mock("GET", "/v2/users")
+            .match_query(Matcher::Any)
+            .with_body(json!({ "Items": [alice, bob] }).to_string())
+            .create_async()
+            .await;
+        let client = CisClient::from_settings(&settings_for(&server)).await?;
+        let mut out = Vec::new();
+        let fields = ["user_id", "primary_email", "active"];
+        let report = export_csv(&client, &fields, None, &mut out).await?;
+        assert_eq!(report.written, 2);
+        assert_eq!(
+            std::str::from_utf8(&out)?,
+            "user_id,primary_email,active\nad|alice,alice@example.com,true\n\"ad|bob, jr\",,\n"
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,71 @@
+use crate::error::CisClientError;
+
+/// Display levels known to Person API.
+const DISPLAY_LEVELS: &[&str] = &[
+    "public",
+    "authenticated",
+    "vouched",
+    "ndaed",
+    "staff",
+    "private",
+];
+
+/// Builder for the `filterDisplay` query value passed to user lookups and
+/// [crate::AsyncProfileIter].
+#[derive(Clone, Debug, Default)]
+pub struct ProfileFilter {
+    fields: Vec<String>,
+}
+
+impl ProfileFilter {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Include attributes of the given display level.
+    pub fn field(mut self, display_level: &str) -> Self {
+        self.fields.push(display_level.to_owned());
+        self
+    }
+
+    /// Serialize to the `filterDisplay` value, failing on unknown display levels.
+    pub fn build(&self) -> Result<String, CisClientError> {
+        if let Some(unknown) = self
+            .fields
+            .iter()
+            .find(|f| !DISPLAY_LEVELS.contains(&f.as_str()))
+        {
+            return Err(CisClientError::UnknownDisplayLevel(unknown.clone()));
+        }
+        Ok(self.fields.join(","))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn single_field() -> Result<(), CisClientError> {
+        assert_eq!(ProfileFilter::new().field("public").build()?, "public");
+        Ok(())
+    }
+
+    #[test]
+    fn multiple_fields() -> Result<(), CisClientError> {
+        let filter = ProfileFilter::new()
+            .field("staff")
+            .field("ndaed")
+            .field("public");
+        assert_eq!(filter.build()?, "staff,ndaed,public");
+        Ok(())
+    }
+
+    #[test]
+    fn unknown_field() {
+        assert!(matches!(
+            ProfileFilter::new().field("public").field("stuff").build(),
+            Err(CisClientError::UnknownDisplayLevel(f)) if f == "stuff"
+        ));
+    }
+}
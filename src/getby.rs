@@ -1,18 +1,104 @@
+use crate::error::CisClientError;
+use std::fmt;
+use std::str::FromStr;
+
 #[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum GetBy {
     Uuid,
     UserId,
     PrimaryEmail,
     PrimaryUsername,
+    /// Email address from the HRIS (Workday) record.
+    HrisEmail,
+    /// Username of the LDAP account.
+    LdapUsername,
 }
 
 impl GetBy {
+    /// All lookups in declaration order, e.g. to offer them as choices in a
+    /// CLI or UI. Their [fmt::Display] forms parse back via [FromStr].
+    pub fn all() -> &'static [GetBy] {
+        &[
+            GetBy::Uuid,
+            GetBy::UserId,
+            GetBy::PrimaryEmail,
+            GetBy::PrimaryUsername,
+            GetBy::HrisEmail,
+            GetBy::LdapUsername,
+        ]
+    }
+
     pub fn as_str(self: &GetBy) -> &'static str {
         match self {
             GetBy::Uuid => "uuid/",
             GetBy::UserId => "user_id/",
             GetBy::PrimaryEmail => "primary_email/",
             GetBy::PrimaryUsername => "primary_username/",
+            GetBy::HrisEmail => "hris_email/",
+            GetBy::LdapUsername => "ldap_username/",
         }
     }
 }
+
+/// Which profiles a single user lookup matches based on their `active` flag.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ActiveFilter {
+    Active,
+    Inactive,
+    Any,
+}
+
+impl ActiveFilter {
+    pub fn as_str(self: &ActiveFilter) -> &'static str {
+        match self {
+            ActiveFilter::Active => "true",
+            ActiveFilter::Inactive => "false",
+            ActiveFilter::Any => "any",
+        }
+    }
+}
+
+impl fmt::Display for GetBy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str().trim_end_matches('/'))
+    }
+}
+
+impl FromStr for GetBy {
+    type Err = CisClientError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "uuid" => Ok(GetBy::Uuid),
+            "user_id" => Ok(GetBy::UserId),
+            "primary_email" => Ok(GetBy::PrimaryEmail),
+            "primary_username" => Ok(GetBy::PrimaryUsername),
+            "hris_email" => Ok(GetBy::HrisEmail),
+            "ldap_username" => Ok(GetBy::LdapUsername),
+            _ => Err(CisClientError::UnknownGetBy(s.to_owned())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trip() -> Result<(), CisClientError> {
+        assert_eq!(GetBy::all().len(), 6);
+        for by in GetBy::all() {
+            assert_eq!(by.to_string().parse::<GetBy>()?, *by);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn unknown_errors() {
+        assert!(matches!(
+            "nickname".parse::<GetBy>(),
+            Err(CisClientError::UnknownGetBy(s)) if s == "nickname"
+        ));
+    }
+}
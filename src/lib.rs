@@ -1,13 +1,50 @@
 mod auth;
+mod batch;
+mod builder;
+mod bulk;
+mod change;
 mod client;
+mod dedup;
+mod diff;
 mod encoding;
 pub mod error;
+pub mod export;
+mod filter;
 pub mod getby;
+mod local;
+mod metrics;
+#[cfg(feature = "mock")]
+pub mod mock;
+mod retry;
+mod schema;
 mod secrets;
 pub mod settings;
+mod signing;
+mod streaming;
 #[cfg(feature = "sync")]
 pub mod sync;
+#[cfg(test)]
+mod test_util;
 
+pub use batch::AsyncBatchStream;
+pub use batch::AsyncBatchTokenStream;
+pub use batch::AsyncProfileIter;
+pub use batch::AsyncProfileStream;
+pub use batch::Batch;
+pub use batch::NextPage;
+pub use batch::ResumeToken;
+pub use builder::CisClientBuilder;
+pub use bulk::ChunkResult;
+pub use bulk::UpdateUsersResult;
+pub use change::ChangeResponse;
 pub use client::AsyncCisClientTrait;
 pub use client::CisClient;
 pub use client::CisFut;
+pub use client::HealthReport;
+pub use filter::ProfileFilter;
+pub use local::CisLocalFut;
+pub use local::LocalCisClientTrait;
+pub use metrics::CisMetrics;
+pub use metrics::NoopMetrics;
+pub use retry::Jitter;
+pub use retry::RetryConfig;
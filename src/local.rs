@@ -0,0 +1,148 @@
+use crate::batch::Batch;
+use crate::batch::NextPage;
+use crate::bulk::UpdateUsersResult;
+use crate::change::ChangeResponse;
+use crate::client::AsyncCisClientTrait;
+use crate::error::CisClientError;
+use crate::getby::GetBy;
+use cis_profile::crypto::SecretStore;
+use cis_profile::schema::Profile;
+use futures::Future;
+use serde_json::Value;
+use std::pin::Pin;
+
+/// Like [crate::CisFut] but without the `Send` bound.
+pub type CisLocalFut<T> = Pin<Box<dyn Future<Output = Result<T, CisClientError>>>>;
+
+/// Variant of [AsyncCisClientTrait] returning [CisLocalFut]s.
+///
+/// Implementations may hold `!Send` data like `Rc` or `RefCell` across awaits,
+/// which makes them usable on single-threaded executors such as a tokio
+/// `LocalSet` but not with `tokio::spawn`. Every [AsyncCisClientTrait]
+/// implementation, including [crate::CisClient], implements this trait, so
+/// code written against it accepts both. Profile iteration is only available
+/// via [AsyncCisClientTrait].
+pub trait LocalCisClientTrait {
+    fn get_user_by(&self, id: &str, by: &GetBy, filter: Option<&str>) -> CisLocalFut<Profile>;
+    fn get_inactive_user_by(
+        &self,
+        id: &str,
+        by: &GetBy,
+        filter: Option<&str>,
+    ) -> CisLocalFut<Profile>;
+    fn get_any_user_by(&self, id: &str, by: &GetBy, filter: Option<&str>) -> CisLocalFut<Profile>;
+    fn get_user_raw_by(&self, id: &str, by: &GetBy, filter: Option<&str>) -> CisLocalFut<Value>;
+    fn get_batch(
+        &self,
+        next_page: &Option<NextPage>,
+        filter: &Option<String>,
+    ) -> CisLocalFut<Batch>;
+    fn get_user_count(&self, filter: Option<&str>) -> CisLocalFut<usize>;
+    fn create_user(&self, profile: Profile) -> CisLocalFut<ChangeResponse>;
+    fn create_user_raw(&self, profile: Profile) -> CisLocalFut<Value>;
+    fn update_user(&self, id: &str, profile: Profile) -> CisLocalFut<ChangeResponse>;
+    fn update_user_raw(&self, id: &str, profile: Profile) -> CisLocalFut<Value>;
+    fn update_users(&self, profiles: &[Profile]) -> CisLocalFut<UpdateUsersResult>;
+    fn delete_user(&self, id: &str, profile: Profile) -> CisLocalFut<ChangeResponse>;
+    fn delete_user_raw(&self, id: &str, profile: Profile) -> CisLocalFut<Value>;
+    fn delete_users(&self, profiles: &[Profile]) -> CisLocalFut<Value>;
+    fn get_secret_store(&self) -> &SecretStore;
+}
+
+impl<T: AsyncCisClientTrait> LocalCisClientTrait for T {
+    fn get_user_by(&self, id: &str, by: &GetBy, filter: Option<&str>) -> CisLocalFut<Profile> {
+        AsyncCisClientTrait::get_user_by(self, id, by, filter)
+    }
+    fn get_inactive_user_by(
+        &self,
+        id: &str,
+        by: &GetBy,
+        filter: Option<&str>,
+    ) -> CisLocalFut<Profile> {
+        AsyncCisClientTrait::get_inactive_user_by(self, id, by, filter)
+    }
+    fn get_any_user_by(&self, id: &str, by: &GetBy, filter: Option<&str>) -> CisLocalFut<Profile> {
+        AsyncCisClientTrait::get_any_user_by(self, id, by, filter)
+    }
+    fn get_user_raw_by(&self, id: &str, by: &GetBy, filter: Option<&str>) -> CisLocalFut<Value> {
+        AsyncCisClientTrait::get_user_raw_by(self, id, by, filter)
+    }
+    fn get_batch(
+        &self,
+        next_page: &Option<NextPage>,
+        filter: &Option<String>,
+    ) -> CisLocalFut<Batch> {
+        AsyncCisClientTrait::get_batch(self, next_page, filter)
+    }
+    fn get_user_count(&self, filter: Option<&str>) -> CisLocalFut<usize> {
+        AsyncCisClientTrait::get_user_count(self, filter)
+    }
+    fn create_user(&self, profile: Profile) -> CisLocalFut<ChangeResponse> {
+        AsyncCisClientTrait::create_user(self, profile)
+    }
+    fn create_user_raw(&self, profile: Profile) -> CisLocalFut<Value> {
+        AsyncCisClientTrait::create_user_raw(self, profile)
+    }
+    fn update_user(&self, id: &str, profile: Profile) -> CisLocalFut<ChangeResponse> {
+        AsyncCisClientTrait::update_user(self, id, profile)
+    }
+    fn update_user_raw(&self, id: &str, profile: Profile) -> CisLocalFut<Value> {
+        AsyncCisClientTrait::update_user_raw(self, id, profile)
+    }
+    fn update_users(&self, profiles: &[Profile]) -> CisLocalFut<UpdateUsersResult> {
+        AsyncCisClientTrait::update_users(self, profiles)
+    }
+    fn delete_user(&self, id: &str, profile: Profile) -> CisLocalFut<ChangeResponse> {
+        AsyncCisClientTrait::delete_user(self, id, profile)
+    }
+    fn delete_user_raw(&self, id: &str, profile: Profile) -> CisLocalFut<Value> {
+        AsyncCisClientTrait::delete_user_raw(self, id, profile)
+    }
+    fn delete_users(&self, profiles: &[Profile]) -> CisLocalFut<Value> {
+        AsyncCisClientTrait::delete_users(self, profiles)
+    }
+    fn get_secret_store(&self) -> &SecretStore {
+        AsyncCisClientTrait::get_secret_store(self)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::client::CisClient;
+    use crate::test_util::*;
+    use anyhow::Error;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    async fn fetch_uuids(
+        client: &impl LocalCisClientTrait,
+        seen: Rc<RefCell<Vec<String>>>,
+    ) -> Result<(), CisClientError> {
+        let profile = client.get_user_by("some-uuid", &GetBy::Uuid, None).await?;
+        seen.borrow_mut().extend(profile.uuid.value);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn local_set_get_user_by() -> Result<(), Error> {
+        let mut server = mockito::Server::new_async().await;
+        mock_token(&mut server).await;
+        server
+            .mock("GET", "/v2/user/uuid/some-uuid")
+            .match_query(mockito::Matcher::Any)
+            .with_body(profile_body("some-uuid"))
+            .create_async()
+            .await;
+        let client = CisClient::from_settings(&settings_for(&server)).await?;
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let local = tokio::task::LocalSet::new();
+        let task = local.spawn_local({
+            let seen = Rc::clone(&seen);
+            async move { fetch_uuids(&client, seen).await }
+        });
+        local.run_until(task).await??;
+        assert_eq!(*seen.borrow(), vec![String::from("some-uuid")]);
+        Ok(())
+    }
+}
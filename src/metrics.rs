@@ -0,0 +1,18 @@
+use reqwest::Method;
+use std::time::Duration;
+
+/// Hooks to observe every Person and Change API request.
+///
+/// `op` names the client operation, e.g. `get_user`, `get_batch`,
+/// `create_user`, `update_user`, `update_users`, `delete_user` or
+/// `delete_users`. Both callbacks are invoked once per operation, retries
+/// included. `status` is `None` if no response was received.
+pub trait CisMetrics: Send + Sync {
+    fn on_request(&self, _op: &'static str, _method: &Method) {}
+    fn on_response(&self, _op: &'static str, _status: Option<u16>, _elapsed: Duration) {}
+}
+
+/// Default [CisMetrics] discarding all events.
+pub struct NoopMetrics;
+
+impl CisMetrics for NoopMetrics {}
@@ -0,0 +1,340 @@
+//! In-memory [AsyncCisClientTrait] implementation for testing code using this
+//! crate without a Person API.
+use crate::batch::AsyncProfileIter;
+use crate::batch::Batch;
+use crate::batch::NextPage;
+use crate::bulk::UpdateUsersResult;
+use crate::client::AsyncCisClientTrait;
+use crate::client::CisFut;
+use crate::error::CisClientError;
+use crate::error::ProfileError;
+use crate::getby::GetBy;
+use cis_profile::crypto::SecretStore;
+use cis_profile::schema::Profile;
+use futures::future;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::MutexGuard;
+
+/// A call made to a [MockCisClient].
+#[derive(Clone, Debug, PartialEq)]
+pub enum MockCall {
+    GetUserBy { id: String, by: GetBy },
+    GetInactiveUserBy { id: String, by: GetBy },
+    GetAnyUserBy { id: String, by: GetBy },
+    GetUserRawBy { id: String, by: GetBy },
+    GetBatch { next_page: Option<String> },
+    GetUserCount,
+    CreateUser(Profile),
+    UpdateUser { id: String, profile: Profile },
+    UpdateUsers(Vec<Profile>),
+    DeleteUser { id: String, profile: Profile },
+    DeleteUsers(Vec<Profile>),
+}
+
+#[derive(Default)]
+struct MockState {
+    users: HashMap<(String, GetBy), Profile>,
+    pages: Vec<Vec<Profile>>,
+    calls: Vec<MockCall>,
+}
+
+/// Client serving canned profiles and recording all calls.
+///
+/// Lookups of ids without a programmed profile fail with
+/// [ProfileError::ProfileDoesNotExist]. Like Person API, `get_user_by` only
+/// returns profiles not marked inactive and `get_inactive_user_by` only those
+/// marked inactive. Writes are accepted and answered with an empty JSON object.
+/// Clones share their profiles and recorded calls.
+#[derive(Clone, Default)]
+pub struct MockCisClient {
+    state: Arc<Mutex<MockState>>,
+    secret_store: Arc<SecretStore>,
+}
+
+impl MockCisClient {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Serve `profile` for lookups of `id` by `by`.
+    pub fn with_user(self, id: &str, by: GetBy, profile: Profile) -> Self {
+        self.state().users.insert((id.to_owned(), by), profile);
+        self
+    }
+
+    /// Append a page of profiles to the pages served by `get_batch`.
+    pub fn with_page(self, profiles: Vec<Profile>) -> Self {
+        self.state().pages.push(profiles);
+        self
+    }
+
+    /// Use `secret_store` for signing and verifying profiles.
+    pub fn with_secret_store(mut self, secret_store: SecretStore) -> Self {
+        self.secret_store = Arc::new(secret_store);
+        self
+    }
+
+    /// All calls made so far in order.
+    pub fn calls(&self) -> Vec<MockCall> {
+        self.state().calls.clone()
+    }
+
+    /// Panics if `call` was not made.
+    pub fn assert_called(&self, call: &MockCall) {
+        let calls = self.calls();
+        assert!(
+            calls.contains(call),
+            "expected call {:?}, got {:?}",
+            call,
+            calls
+        );
+    }
+
+    fn state(&self) -> MutexGuard<'_, MockState> {
+        match self.state.lock() {
+            Ok(state) => state,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    fn record(&self, call: MockCall) {
+        self.state().calls.push(call);
+    }
+
+    pub(crate) fn user(
+        &self,
+        call: MockCall,
+        id: &str,
+        by: &GetBy,
+        active: Option<bool>,
+    ) -> Result<Profile, CisClientError> {
+        self.record(call);
+        self.state()
+            .users
+            .get(&(id.to_owned(), *by))
+            .filter(|profile| match active {
+                Some(active) => profile.active.value.unwrap_or(true) == active,
+                None => true,
+            })
+            .cloned()
+            .ok_or_else(|| ProfileError::ProfileDoesNotExist.into())
+    }
+
+    pub(crate) fn user_raw(&self, id: &str, by: &GetBy) -> Result<Value, CisClientError> {
+        let call = MockCall::GetUserRawBy {
+            id: id.to_owned(),
+            by: *by,
+        };
+        let profile = self.user(call, id, by, None)?;
+        serde_json::to_value(profile).map_err(Into::into)
+    }
+
+    /// Pages are addressed by their index, the first page has none.
+    pub(crate) fn batch(&self, next_page: &Option<NextPage>) -> Result<Batch, CisClientError> {
+        self.record(MockCall::GetBatch {
+            next_page: next_page.as_ref().map(|next_page| next_page.id.clone()),
+        });
+        let index = match next_page {
+            Some(next_page) => next_page.id.parse().map_err(|_| CisClientError::ApiError {
+                status: 400,
+                body: format!("unknown page {}", next_page.id),
+            })?,
+            None => 0,
+        };
+        let state = self.state();
+        let items = match state.pages.get(index) {
+            Some(profiles) => profiles.clone(),
+            None if index == 0 => {
+                return Ok(Batch {
+                    items: None,
+                    next_page: None,
+                    skipped: 0,
+                })
+            }
+            None => {
+                return Err(CisClientError::ApiError {
+                    status: 400,
+                    body: format!("unknown page {}", index),
+                })
+            }
+        };
+        let next_page = if index + 1 < state.pages.len() {
+            Some(NextPage {
+                id: (index + 1).to_string(),
+            })
+        } else {
+            None
+        };
+        Ok(Batch {
+            items: Some(items),
+            next_page,
+            skipped: 0,
+        })
+    }
+
+    pub(crate) fn user_count(&self) -> usize {
+        self.record(MockCall::GetUserCount);
+        self.state().pages.iter().map(Vec::len).sum()
+    }
+
+    pub(crate) fn write(&self, call: MockCall) -> Value {
+        self.record(call);
+        Value::Object(Default::default())
+    }
+
+    pub(crate) fn write_users(&self, profiles: &[Profile]) -> UpdateUsersResult {
+        let response = self.write(MockCall::UpdateUsers(profiles.to_vec()));
+        let mut result = UpdateUsersResult::default();
+        result.push(profiles, Ok(response));
+        result
+    }
+}
+
+impl AsyncCisClientTrait for MockCisClient {
+    fn get_user_by(&self, id: &str, by: &GetBy, _: Option<&str>) -> CisFut<Profile> {
+        let call = MockCall::GetUserBy {
+            id: id.to_owned(),
+            by: *by,
+        };
+        Box::pin(future::ready(self.user(call, id, by, Some(true))))
+    }
+    fn get_inactive_user_by(&self, id: &str, by: &GetBy, _: Option<&str>) -> CisFut<Profile> {
+        let call = MockCall::GetInactiveUserBy {
+            id: id.to_owned(),
+            by: *by,
+        };
+        Box::pin(future::ready(self.user(call, id, by, Some(false))))
+    }
+    fn get_any_user_by(&self, id: &str, by: &GetBy, _: Option<&str>) -> CisFut<Profile> {
+        let call = MockCall::GetAnyUserBy {
+            id: id.to_owned(),
+            by: *by,
+        };
+        Box::pin(future::ready(self.user(call, id, by, None)))
+    }
+    fn get_user_raw_by(&self, id: &str, by: &GetBy, _: Option<&str>) -> CisFut<Value> {
+        Box::pin(future::ready(self.user_raw(id, by)))
+    }
+    fn get_users_iter(&self, filter: Option<&str>) -> AsyncProfileIter<Self> {
+        AsyncProfileIter::new(self.clone(), filter.map(String::from))
+    }
+    fn get_batch(&self, next_page: &Option<NextPage>, _: &Option<String>) -> CisFut<Batch> {
+        Box::pin(future::ready(self.batch(next_page)))
+    }
+    fn get_user_count(&self, _: Option<&str>) -> CisFut<usize> {
+        Box::pin(future::ok(self.user_count()))
+    }
+    fn create_user_raw(&self, profile: Profile) -> CisFut<Value> {
+        Box::pin(future::ok(self.write(MockCall::CreateUser(profile))))
+    }
+    fn update_user_raw(&self, id: &str, profile: Profile) -> CisFut<Value> {
+        let call = MockCall::UpdateUser {
+            id: id.to_owned(),
+            profile,
+        };
+        Box::pin(future::ok(self.write(call)))
+    }
+    fn update_users(&self, profiles: &[Profile]) -> CisFut<UpdateUsersResult> {
+        Box::pin(future::ok(self.write_users(profiles)))
+    }
+    fn delete_user_raw(&self, id: &str, profile: Profile) -> CisFut<Value> {
+        let call = MockCall::DeleteUser {
+            id: id.to_owned(),
+            profile,
+        };
+        Box::pin(future::ok(self.write(call)))
+    }
+    fn delete_users(&self, profiles: &[Profile]) -> CisFut<Value> {
+        Box::pin(future::ok(
+            self.write(MockCall::DeleteUsers(profiles.to_vec())),
+        ))
+    }
+    fn get_secret_store(&self) -> &SecretStore {
+        &self.secret_store
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use anyhow::Error;
+    use futures::TryStreamExt;
+
+    fn profile(uuid: &str) -> Profile {
+        let mut profile = Profile::default();
+        profile.uuid.value = Some(uuid.to_owned());
+        profile
+    }
+
+    #[tokio::test]
+    async fn programmed_get_user_by() -> Result<(), Error> {
+        let client = MockCisClient::new().with_user("ad|alice", GetBy::UserId, profile("alice"));
+        let alice = client.get_user_by("ad|alice", &GetBy::UserId, None).await?;
+        assert_eq!(alice.uuid.value.as_deref(), Some("alice"));
+        assert!(matches!(
+            client.get_user_by("alice", &GetBy::Uuid, None).await,
+            Err(CisClientError::ProfileError(
+                ProfileError::ProfileDoesNotExist
+            ))
+        ));
+        assert_eq!(
+            client.calls(),
+            vec![
+                MockCall::GetUserBy {
+                    id: String::from("ad|alice"),
+                    by: GetBy::UserId
+                },
+                MockCall::GetUserBy {
+                    id: String::from("alice"),
+                    by: GetBy::Uuid
+                },
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dedup_drops_profile_repeated_across_pages() -> Result<(), Error> {
+        let client = MockCisClient::new()
+            .with_page(vec![profile("1"), profile("2")])
+            .with_page(vec![profile("2"), profile("3")]);
+        let uuids = |profiles: Vec<Profile>| -> Vec<String> {
+            profiles.into_iter().filter_map(|p| p.uuid.value).collect()
+        };
+        let all: Vec<Profile> = client
+            .get_users_iter(None)
+            .profiles_stream()
+            .try_collect()
+            .await?;
+        assert_eq!(uuids(all), vec!["1", "2", "2", "3"]);
+        for window in &[None, Some(1)] {
+            let deduped: Vec<Profile> = client
+                .get_users_iter(None)
+                .with_dedup(*window)
+                .profiles_stream()
+                .try_collect()
+                .await?;
+            assert_eq!(uuids(deduped), vec!["1", "2", "3"]);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn two_page_batch() -> Result<(), Error> {
+        let client = MockCisClient::new()
+            .with_page(vec![profile("1"), profile("2")])
+            .with_page(vec![profile("3")]);
+        let pages: Vec<Vec<Profile>> = client.get_users_iter(None).try_collect().await?;
+        assert_eq!(pages.len(), 2);
+        assert_eq!(pages[0].len(), 2);
+        assert_eq!(pages[1][0].uuid.value.as_deref(), Some("3"));
+        client.assert_called(&MockCall::GetBatch { next_page: None });
+        client.assert_called(&MockCall::GetBatch {
+            next_page: Some(String::from("1")),
+        });
+        Ok(())
+    }
+}
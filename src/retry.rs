@@ -0,0 +1,283 @@
+use chrono::DateTime;
+use chrono::Utc;
+use rand::Rng;
+use reqwest::header::HeaderMap;
+use reqwest::header::RETRY_AFTER;
+use reqwest::RequestBuilder;
+use reqwest::Response;
+use reqwest::StatusCode;
+use std::time::Duration;
+use tokio::sync::Semaphore;
+use tokio::sync::SemaphorePermit;
+
+/// Retry policy for requests against Person and Change API.
+///
+/// Only `429`, `502`, `503` and `504` responses are retried. A `Retry-After`
+/// header takes precedence over the computed backoff. Writes are only retried
+/// when they carry an idempotency key (see
+/// [CisClientBuilder::idempotency_keys](crate::CisClientBuilder::idempotency_keys)).
+#[derive(Clone, Debug)]
+pub struct RetryConfig {
+    /// Total number of attempts including the first one.
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    pub jitter: Jitter,
+}
+
+/// How the computed backoff is randomized so clients failing at the same time
+/// do not retry in lockstep.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Jitter {
+    /// Wait a random time between zero and the backoff.
+    Full,
+    /// Wait half the backoff plus a random time up to the other half.
+    Equal,
+    /// Wait exactly the backoff.
+    None,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        RetryConfig {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(200),
+            max_delay: Duration::from_secs(10),
+            jitter: Jitter::Full,
+        }
+    }
+}
+
+impl RetryConfig {
+    /// A policy that never retries.
+    pub fn none() -> Self {
+        RetryConfig {
+            max_attempts: 1,
+            ..Default::default()
+        }
+    }
+
+    /// Policy for a write which may only be retried with an idempotency key.
+    pub(crate) fn for_write(&self, idempotency_keys: bool) -> RetryConfig {
+        if idempotency_keys {
+            self.clone()
+        } else {
+            RetryConfig::none()
+        }
+    }
+
+    pub(crate) fn delay(&self, attempt: u32, retry_after: Option<Duration>) -> Duration {
+        self.delay_with_rng(attempt, retry_after, &mut rand::thread_rng())
+    }
+
+    fn delay_with_rng(
+        &self,
+        attempt: u32,
+        retry_after: Option<Duration>,
+        rng: &mut impl Rng,
+    ) -> Duration {
+        if let Some(retry_after) = retry_after {
+            return retry_after.min(self.max_delay);
+        }
+        let backoff = self
+            .base_delay
+            .checked_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .unwrap_or(self.max_delay)
+            .min(self.max_delay);
+        match self.jitter {
+            Jitter::Full => backoff.mul_f64(rng.gen_range(0.0..=1.0)),
+            Jitter::Equal => backoff / 2 + (backoff / 2).mul_f64(rng.gen_range(0.0..=1.0)),
+            Jitter::None => backoff,
+        }
+    }
+}
+
+/// Header identifying a write so the server can ignore retried duplicates.
+pub(crate) const IDEMPOTENCY_KEY: &str = "Idempotency-Key";
+
+/// Random UUID (v4) used as [IDEMPOTENCY_KEY] of one write.
+pub(crate) fn idempotency_key() -> String {
+    let mut bytes: [u8; 16] = rand::random();
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+pub(crate) fn is_retryable(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::TOO_MANY_REQUESTS
+            | StatusCode::BAD_GATEWAY
+            | StatusCode::SERVICE_UNAVAILABLE
+            | StatusCode::GATEWAY_TIMEOUT
+    )
+}
+
+pub(crate) fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?;
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    (date.with_timezone(&Utc) - Utc::now()).to_std().ok()
+}
+
+pub(crate) async fn send(
+    retry_config: &RetryConfig,
+    request: RequestBuilder,
+) -> Result<Response, reqwest::Error> {
+    send_limited(retry_config, None, None, request)
+        .await
+        .map(|(res, _)| res)
+}
+
+/// Wait for a free slot of `limiter` if any.
+pub(crate) async fn acquire(limiter: Option<&Semaphore>) -> Option<SemaphorePermit<'_>> {
+    match limiter {
+        Some(limiter) => limiter.acquire().await.ok(),
+        None => None,
+    }
+}
+
+/// Like [send] but holds a permit of `limiter` only while an attempt is in
+/// flight. `permit` is used for the first attempt, the permit is released
+/// during backoff and the one of the final attempt is returned with its
+/// response.
+pub(crate) async fn send_limited<'a>(
+    retry_config: &RetryConfig,
+    limiter: Option<&'a Semaphore>,
+    mut permit: Option<SemaphorePermit<'a>>,
+    request: RequestBuilder,
+) -> Result<(Response, Option<SemaphorePermit<'a>>), reqwest::Error> {
+    let mut attempt = 1;
+    loop {
+        if attempt > 1 {
+            permit = acquire(limiter).await;
+        }
+        let req = match request.try_clone() {
+            Some(req) if attempt < retry_config.max_attempts => req,
+            _ => return Ok((request.send().await?, permit)),
+        };
+        let res = req.send().await?;
+        if !is_retryable(res.status()) {
+            return Ok((res, permit));
+        }
+        drop(permit.take());
+        let delay = retry_config.delay(attempt, retry_after(res.headers()));
+        log::debug!(
+            "got {} (attempt {}), retrying in {:?}",
+            res.status(),
+            attempt,
+            delay
+        );
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+#[cfg(feature = "sync")]
+pub(crate) fn send_sync(
+    retry_config: &RetryConfig,
+    request: reqwest::blocking::RequestBuilder,
+) -> Result<reqwest::blocking::Response, reqwest::Error> {
+    let mut attempt = 1;
+    loop {
+        let req = match request.try_clone() {
+            Some(req) if attempt < retry_config.max_attempts => req,
+            _ => return request.send(),
+        };
+        let res = req.send()?;
+        if !is_retryable(res.status()) {
+            return Ok(res);
+        }
+        let delay = retry_config.delay(attempt, retry_after(res.headers()));
+        log::debug!(
+            "got {} (attempt {}), retrying in {:?}",
+            res.status(),
+            attempt,
+            delay
+        );
+        std::thread::sleep(delay);
+        attempt += 1;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn delay_is_exponential_and_capped() {
+        let retry_config = RetryConfig {
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(300),
+            jitter: Jitter::None,
+            ..Default::default()
+        };
+        assert_eq!(retry_config.delay(1, None), Duration::from_millis(100));
+        assert_eq!(retry_config.delay(2, None), Duration::from_millis(200));
+        assert_eq!(retry_config.delay(3, None), Duration::from_millis(300));
+        assert_eq!(retry_config.delay(40, None), Duration::from_millis(300));
+    }
+
+    #[test]
+    fn jittered_delays_stay_within_bounds() {
+        use rand::rngs::StdRng;
+        use rand::SeedableRng;
+
+        let mut rng = StdRng::seed_from_u64(7);
+        for jitter in &[Jitter::Full, Jitter::Equal] {
+            let retry_config = RetryConfig {
+                base_delay: Duration::from_millis(100),
+                max_delay: Duration::from_millis(1000),
+                jitter: *jitter,
+                ..Default::default()
+            };
+            for (attempt, backoff) in [(1, 100), (2, 200), (3, 400), (4, 800), (5, 1000)].iter() {
+                let backoff = Duration::from_millis(*backoff);
+                let min = match jitter {
+                    Jitter::Equal => backoff / 2,
+                    _ => Duration::from_millis(0),
+                };
+                for _ in 0..100 {
+                    let delay = retry_config.delay_with_rng(*attempt, None, &mut rng);
+                    assert!(
+                        min <= delay && delay <= backoff,
+                        "{:?} attempt {}: {:?}",
+                        jitter,
+                        attempt,
+                        delay
+                    );
+                }
+            }
+        }
+        assert_eq!(RetryConfig::default().jitter, Jitter::Full);
+    }
+
+    #[test]
+    fn idempotency_keys_are_v4_uuids() {
+        let key = idempotency_key();
+        assert_eq!(key.len(), 36);
+        assert_eq!(key.as_bytes()[14], b'4');
+        assert!(matches!(key.as_bytes()[19], b'8' | b'9' | b'a' | b'b'));
+        assert_ne!(key, idempotency_key());
+    }
+
+    #[test]
+    fn retry_after_takes_precedence() {
+        let retry_config = RetryConfig::default();
+        let mut headers = HeaderMap::new();
+        headers.insert(RETRY_AFTER, "2".parse().unwrap());
+        let retry_after = retry_after(&headers);
+        assert_eq!(retry_after, Some(Duration::from_secs(2)));
+        assert_eq!(retry_config.delay(1, retry_after), Duration::from_secs(2));
+    }
+}
@@ -0,0 +1,78 @@
+use crate::error::CisClientError;
+use crate::error::ProfileError;
+use cis_profile::schema::Profile;
+use serde_json::Value;
+
+/// Deserialize `json` as [Profile]. If `strict`, fields [Profile] does not
+/// know fail with [ProfileError::UnknownFields] instead of being dropped.
+///
+/// [Profile] does not deny unknown fields itself, so they are collected while
+/// deserializing, including those nested in objects and arrays.
+pub(crate) fn profile_from_json(json: Value, strict: bool) -> Result<Profile, CisClientError> {
+    if !strict {
+        return serde_json::from_value(json).map_err(Into::into);
+    }
+    let mut unknown = Vec::new();
+    let profile: Profile =
+        serde_ignored::deserialize(json, |path| unknown.push(field_path(&path)))?;
+    if !unknown.is_empty() {
+        unknown.sort();
+        return Err(ProfileError::UnknownFields(unknown).into());
+    }
+    Ok(profile)
+}
+
+/// Dotted path of an ignored field without the `?` segments `serde_ignored`
+/// adds for options and newtypes, e.g. `uuid.signature.additional.0.key`.
+fn field_path(path: &serde_ignored::Path) -> String {
+    path.to_string()
+        .split('.')
+        .filter(|segment| *segment != "?")
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use anyhow::Error;
+
+    fn full_profile() -> Result<Value, Error> {
+        let json = std::fs::read_to_string("tests/data/profile_full.json")?;
+        Ok(serde_json::from_str(&json)?)
+    }
+
+    #[test]
+    fn complete_profile_is_accepted_in_strict_mode() -> Result<(), Error> {
+        let profile = profile_from_json(full_profile()?, true)?;
+        assert_eq!(
+            profile.staff_information.title.value.as_deref(),
+            Some("Staff Engineer")
+        );
+        assert!(profile.pgp_public_keys.values.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn unknown_fields_are_found_in_nested_objects_and_arrays() -> Result<(), Error> {
+        let mut json = full_profile()?;
+        json["staff_information"]["desk"] = Value::from("4.2");
+        json["identities"]["github_id_v3"]["metadata"]["source"] = Value::from("x");
+        json["uuid"]["signature"]["additional"] = serde_json::json!([
+            { "alg": "RS256", "typ": "JWS", "name": "cis", "value": "sig", "kid": "k" }
+        ]);
+        match profile_from_json(json.clone(), true) {
+            Err(CisClientError::ProfileError(ProfileError::UnknownFields(fields))) => assert_eq!(
+                fields,
+                vec![
+                    "identities.github_id_v3.metadata.source",
+                    "staff_information.desk",
+                    "uuid.signature.additional.0.kid",
+                ]
+            ),
+            r => panic!("unexpected result: {:?}", r),
+        }
+        assert!(profile_from_json(json, false).is_ok());
+        Ok(())
+    }
+}
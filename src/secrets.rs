@@ -1,85 +1,279 @@
 use crate::error::SecretsError;
-use crate::settings::CisSettings;
 use crate::settings::KeySource;
 use crate::settings::Keys;
+use crate::settings::VaultConfig;
 use cis_profile::crypto::SecretStore;
+use futures::Future;
+use reqwest::Client;
+use reqwest::StatusCode;
+use serde_json::json;
+use serde_json::Value;
 use std::fs::File;
 use std::io::prelude::*;
 use std::io::BufReader;
+use url::Url;
 
-pub async fn get_store_from_settings(settings: &CisSettings) -> Result<SecretStore, SecretsError> {
-    let mut store = SecretStore::default();
-    store = match settings.sign_keys.source {
-        KeySource::None => store,
-        KeySource::File => add_sign_keys_from_files(&settings.sign_keys, store)?,
-        KeySource::Ssm => add_sign_keys_from_ssm(&settings.sign_keys, store).await?,
-        _ => return Err(SecretsError::UseNoneFileSsm),
-    };
-    store = match (
-        &settings.verify_keys.source,
-        &settings.verify_keys.well_known_iam_endpoint,
-    ) {
-        (KeySource::None, _) => store,
-        (KeySource::File, _) => add_verify_keys_from_files(&settings.verify_keys, store)?,
-        (KeySource::Ssm, _) => add_verify_keys_from_ssm(&settings.verify_keys, store).await?,
-        (KeySource::WellKnown, Some(url)) => {
-            store.with_verify_keys_from_well_known(url.as_str()).await?
+/// Sign and verify keys are loaded concurrently so startup is bounded by the
+/// slowest source rather than the sum of both.
+pub async fn get_store_from_keys(
+    sign_keys: &Keys,
+    verify_keys: &Keys,
+) -> Result<SecretStore, SecretsError> {
+    validate_keys(sign_keys, verify_keys)?;
+    merge_stores(read_sign_keys(sign_keys), load_verify_keys(verify_keys)).await
+}
+
+/// Check that each key source has the fields it needs, so misconfigured
+/// settings fail before any key is loaded.
+pub fn validate_keys(sign_keys: &Keys, verify_keys: &Keys) -> Result<(), SecretsError> {
+    if let KeySource::WellKnown = sign_keys.source {
+        return Err(SecretsError::UseNoneFileSsm);
+    }
+    validate_source(sign_keys, "sign")?;
+    validate_source(verify_keys, "verify")
+}
+
+fn validate_source(keys: &Keys, usage: &'static str) -> Result<(), SecretsError> {
+    let source = match keys.source {
+        KeySource::None => return Ok(()),
+        KeySource::WellKnown if keys.well_known_iam_endpoint.is_none() => {
+            return Err(SecretsError::WellKnownEndpointMissing)
         }
-        _ => {
-            return Err(SecretsError::UseNoneFileSsmWellKnonw);
+        KeySource::WellKnown => return Ok(()),
+        KeySource::File => "file",
+        KeySource::Ssm => "ssm",
+        KeySource::Env => "env",
+        KeySource::FileBundle if keys.bundle_file.is_none() => {
+            return Err(SecretsError::BundleFileMissing)
         }
+        KeySource::FileBundle => "file_bundle",
+        KeySource::Vault => match &keys.vault {
+            Some(VaultConfig { token: Some(_), .. })
+            | Some(VaultConfig {
+                role_id: Some(_),
+                secret_id: Some(_),
+                ..
+            }) => "vault",
+            _ => return Err(SecretsError::VaultConfigMissing),
+        },
     };
-    Ok(store)
+    if get_key_tuples(keys).is_empty() {
+        return Err(SecretsError::KeyFieldsMissing(usage, source));
+    }
+    Ok(())
+}
+
+/// Sign keys to add to the [SecretStore] holding the verify keys.
+enum SignKeys {
+    /// Keys read by this crate.
+    Inline(Vec<(String, String)>),
+    /// SSM parameters, read by [SecretStore] itself.
+    Ssm(Vec<(String, String)>),
 }
 
-pub async fn add_sign_keys_from_ssm(
+/// Add the sign keys to the store loaded with the verify keys. Both are read
+/// concurrently, only SSM sign keys are read after the verify keys.
+async fn merge_stores(
+    sign_keys: impl Future<Output = Result<SignKeys, SecretsError>>,
+    verify_store: impl Future<Output = Result<SecretStore, SecretsError>>,
+) -> Result<SecretStore, SecretsError> {
+    let (sign_keys, store) = futures::try_join!(sign_keys, verify_store)?;
+    match sign_keys {
+        SignKeys::Inline(key_tuples) if key_tuples.is_empty() => Ok(store),
+        SignKeys::Inline(key_tuples) => store
+            .with_sign_keys_from_inline_iter(key_tuples)
+            .map_err(Into::into),
+        SignKeys::Ssm(key_tuples) => store
+            .with_sign_keys_from_ssm_iter(key_tuples)
+            .await
+            .map_err(Into::into),
+    }
+}
+
+async fn read_sign_keys(keys: &Keys) -> Result<SignKeys, SecretsError> {
+    match keys.source {
+        KeySource::None => Ok(SignKeys::Inline(Vec::new())),
+        KeySource::File => read_files(keys).map(SignKeys::Inline),
+        KeySource::FileBundle => read_bundle(keys).map(SignKeys::Inline),
+        KeySource::Env => read_envs(keys).map(SignKeys::Inline),
+        KeySource::Ssm => Ok(SignKeys::Ssm(get_key_tuples(keys))),
+        KeySource::Vault => read_vault(keys).await.map(SignKeys::Inline),
+        _ => Err(SecretsError::UseNoneFileSsm),
+    }
+}
+
+async fn load_verify_keys(keys: &Keys) -> Result<SecretStore, SecretsError> {
+    let store = SecretStore::default();
+    match (&keys.source, &keys.well_known_iam_endpoint) {
+        (KeySource::None, _) => Ok(store),
+        (KeySource::File, _) => add_verify_keys_from_files(keys, store),
+        (KeySource::FileBundle, _) => add_verify_keys_from_bundle(keys, store),
+        (KeySource::Env, _) => add_verify_keys_from_env(keys, store),
+        (KeySource::Ssm, _) => add_verify_keys_from_ssm(keys, store).await,
+        (KeySource::Vault, _) => add_verify_keys_from_vault(keys, store).await,
+        (KeySource::WellKnown, Some(url)) => store
+            .with_verify_keys_from_well_known(url.as_str())
+            .await
+            .map_err(Into::into),
+        _ => Err(SecretsError::UseNoneFileSsmWellKnonw),
+    }
+}
+
+pub async fn add_verify_keys_from_ssm(
     keys: &Keys,
     store: SecretStore,
 ) -> Result<SecretStore, SecretsError> {
     let key_tuples = get_key_tuples(keys);
     store
-        .with_sign_keys_from_ssm_iter(key_tuples)
+        .with_verify_keys_from_ssm_iter(key_tuples)
         .await
         .map_err(Into::into)
 }
 
-pub async fn add_verify_keys_from_ssm(
+pub fn add_verify_keys_from_files(
     keys: &Keys,
     store: SecretStore,
 ) -> Result<SecretStore, SecretsError> {
-    let key_tuples = get_key_tuples(keys);
+    let key_tuples = read_files(keys)?;
     store
-        .with_verify_keys_from_ssm_iter(key_tuples)
-        .await
+        .with_verify_keys_from_inline_iter(key_tuples)
         .map_err(Into::into)
 }
 
-pub fn add_sign_keys_from_files(
+pub fn add_verify_keys_from_bundle(
     keys: &Keys,
     store: SecretStore,
 ) -> Result<SecretStore, SecretsError> {
-    let key_tuples = get_key_tuples(keys)
+    let key_tuples = read_bundle(keys)?;
+    store
+        .with_verify_keys_from_inline_iter(key_tuples)
+        .map_err(Into::into)
+}
+
+/// Reads the key files of all configured realms.
+fn read_files(keys: &Keys) -> Result<Vec<(String, String)>, SecretsError> {
+    get_key_tuples(keys)
         .into_iter()
         .map(|(k, v)| read_file(&v).map(|content| (k, content)))
-        .collect::<Result<Vec<(String, String)>, SecretsError>>()?;
+        .collect()
+}
+
+/// Reads the key environment variables of all configured realms.
+fn read_envs(keys: &Keys) -> Result<Vec<(String, String)>, SecretsError> {
+    get_key_tuples(keys)
+        .into_iter()
+        .map(|(k, v)| read_env(&v).map(|content| (k, content)))
+        .collect()
+}
+
+/// Reads the keys of all configured realms from the bundle file.
+fn read_bundle(keys: &Keys) -> Result<Vec<(String, String)>, SecretsError> {
+    let file_name = keys
+        .bundle_file
+        .as_ref()
+        .ok_or(SecretsError::BundleFileMissing)?;
+    let mut bundle: serde_json::Map<String, Value> = serde_json::from_str(&read_file(file_name)?)?;
+    get_key_tuples(keys)
+        .into_iter()
+        .map(|(k, _)| match bundle.remove(&k) {
+            Some(Value::String(key)) => Ok((k, key)),
+            Some(key) => Ok((k, key.to_string())),
+            None => Err(SecretsError::BundleRealmMissing(k)),
+        })
+        .collect()
+}
+
+pub fn add_verify_keys_from_env(
+    keys: &Keys,
+    store: SecretStore,
+) -> Result<SecretStore, SecretsError> {
+    let key_tuples = read_envs(keys)?;
     store
-        .with_sign_keys_from_inline_iter(key_tuples)
+        .with_verify_keys_from_inline_iter(key_tuples)
         .map_err(Into::into)
 }
 
-pub fn add_verify_keys_from_files(
+pub async fn add_verify_keys_from_vault(
     keys: &Keys,
     store: SecretStore,
 ) -> Result<SecretStore, SecretsError> {
-    let key_tuples = get_key_tuples(keys)
-        .into_iter()
-        .map(|(k, v)| read_file(&v).map(|content| (k, content)))
-        .collect::<Result<Vec<(String, String)>, SecretsError>>()?;
+    let key_tuples = read_vault(keys).await?;
     store
         .with_verify_keys_from_inline_iter(key_tuples)
         .map_err(Into::into)
 }
 
+/// Reads the `key` field of each configured KV v2 secret.
+async fn read_vault(keys: &Keys) -> Result<Vec<(String, String)>, SecretsError> {
+    let vault = keys
+        .vault
+        .as_ref()
+        .ok_or(SecretsError::VaultConfigMissing)?;
+    let client = Client::new();
+    let token = match (&vault.token, &vault.role_id, &vault.secret_id) {
+        (Some(token), _, _) => token.clone(),
+        (None, Some(role_id), Some(secret_id)) => {
+            vault_approle_login(&client, vault, role_id, secret_id).await?
+        }
+        _ => return Err(SecretsError::VaultConfigMissing),
+    };
+    let mut key_tuples = Vec::new();
+    for (k, path) in get_key_tuples(keys) {
+        let url = vault_url(vault, &format!("v1/{}/data/{}", vault.mount, path))?;
+        let res = client
+            .get(url)
+            .header("X-Vault-Token", &token)
+            .send()
+            .await?;
+        let status = res.status();
+        if status == StatusCode::UNAUTHORIZED || status == StatusCode::FORBIDDEN {
+            return Err(SecretsError::VaultAuthFailed(status.as_u16()));
+        }
+        let read_failed = || SecretsError::VaultReadFailed {
+            path: path.clone(),
+            status: status.as_u16(),
+        };
+        if !status.is_success() {
+            return Err(read_failed());
+        }
+        let secret: Value = res.json().await?;
+        let key = secret["data"]["data"]["key"]
+            .as_str()
+            .ok_or_else(read_failed)?;
+        key_tuples.push((k, key.to_owned()));
+    }
+    Ok(key_tuples)
+}
+
+async fn vault_approle_login(
+    client: &Client,
+    vault: &VaultConfig,
+    role_id: &str,
+    secret_id: &str,
+) -> Result<String, SecretsError> {
+    let res = client
+        .post(vault_url(vault, "v1/auth/approle/login")?)
+        .json(&json!({ "role_id": role_id, "secret_id": secret_id }))
+        .send()
+        .await?;
+    let status = res.status();
+    if !status.is_success() {
+        return Err(SecretsError::VaultAuthFailed(status.as_u16()));
+    }
+    let login: Value = res.json().await?;
+    login["auth"]["client_token"]
+        .as_str()
+        .map(ToOwned::to_owned)
+        .ok_or_else(|| SecretsError::VaultAuthFailed(status.as_u16()))
+}
+
+fn vault_url(vault: &VaultConfig, path: &str) -> Result<Url, SecretsError> {
+    vault
+        .address
+        .join(path)
+        .map_err(|_| SecretsError::VaultConfigMissing)
+}
+
 fn get_key_tuples(keys: &Keys) -> Vec<(String, String)> {
     vec![
         ("mozilliansorg", &keys.mozilliansorg_key),
@@ -103,15 +297,24 @@ fn read_file(file_name: &str) -> Result<String, SecretsError> {
     Ok(content)
 }
 
+fn read_env(var_name: &str) -> Result<String, SecretsError> {
+    std::env::var(var_name).map_err(|_| SecretsError::EnvVarMissing(var_name.to_owned()))
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::settings::CisSettings;
     use anyhow::Error;
 
     #[tokio::test]
     async fn secret_store_from_empty_with_none_setting() -> Result<(), Error> {
         let cis_settings = CisSettings::default();
-        assert!(get_store_from_settings(&cis_settings).await.is_ok());
+        assert!(
+            get_store_from_keys(&cis_settings.sign_keys, &cis_settings.verify_keys)
+                .await
+                .is_ok()
+        );
         Ok(())
     }
 
@@ -122,4 +325,213 @@ mod test {
         assert_eq!(expected, content);
         Ok(())
     }
+
+    #[tokio::test]
+    async fn secret_store_from_env() -> Result<(), Error> {
+        std::env::set_var(
+            "CIS_CLIENT_TEST_LDAP_KEY",
+            include_str!("../tests/data/fake_key.json"),
+        );
+        let sign_keys = Keys {
+            source: KeySource::Env,
+            ldap_key: Some(String::from("CIS_CLIENT_TEST_LDAP_KEY")),
+            ..Default::default()
+        };
+        assert!(get_store_from_keys(&sign_keys, &Keys::default())
+            .await
+            .is_ok());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn secret_store_from_env_missing_var() -> Result<(), Error> {
+        let sign_keys = Keys {
+            source: KeySource::Env,
+            hris_key: Some(String::from("CIS_CLIENT_TEST_MISSING_KEY")),
+            ..Default::default()
+        };
+        match get_store_from_keys(&sign_keys, &Keys::default()).await {
+            Err(SecretsError::EnvVarMissing(var)) => {
+                assert_eq!(var, "CIS_CLIENT_TEST_MISSING_KEY")
+            }
+            _ => panic!("expected EnvVarMissing"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn misconfigured_key_sources_fail_upfront() {
+        let none = Keys::default();
+        let source = |source| Keys {
+            source,
+            ..Default::default()
+        };
+        let well_known = Keys {
+            well_known_iam_endpoint: Some(
+                Url::parse("https://auth.mozilla.com/.well-known/mozilla-iam").unwrap(),
+            ),
+            ..source(KeySource::WellKnown)
+        };
+        let vault = |vault: Value| Keys {
+            ldap_key: Some(String::from("cis/ldap")),
+            vault: Some(serde_json::from_value(vault).unwrap()),
+            ..source(KeySource::Vault)
+        };
+
+        for (key_source, name) in &[
+            (KeySource::File, "file"),
+            (KeySource::Ssm, "ssm"),
+            (KeySource::Env, "env"),
+        ] {
+            assert!(matches!(
+                validate_keys(&source(key_source.clone()), &none),
+                Err(SecretsError::KeyFieldsMissing("sign", n)) if n == *name
+            ));
+            assert!(matches!(
+                validate_keys(&none, &source(key_source.clone())),
+                Err(SecretsError::KeyFieldsMissing("verify", n)) if n == *name
+            ));
+        }
+        assert!(matches!(
+            validate_keys(&none, &source(KeySource::WellKnown)),
+            Err(SecretsError::WellKnownEndpointMissing)
+        ));
+        assert!(matches!(
+            validate_keys(&well_known, &none),
+            Err(SecretsError::UseNoneFileSsm)
+        ));
+        let bundle_without_file = Keys {
+            cis_key: Some(String::from("cis")),
+            ..source(KeySource::FileBundle)
+        };
+        assert!(matches!(
+            validate_keys(&bundle_without_file, &none),
+            Err(SecretsError::BundleFileMissing)
+        ));
+        let bundle_without_realms = Keys {
+            bundle_file: Some(String::from("tests/data/fake_key_bundle.json")),
+            ..source(KeySource::FileBundle)
+        };
+        assert!(matches!(
+            validate_keys(&none, &bundle_without_realms),
+            Err(SecretsError::KeyFieldsMissing("verify", "file_bundle"))
+        ));
+        assert!(matches!(
+            validate_keys(&source(KeySource::Vault), &none),
+            Err(SecretsError::VaultConfigMissing)
+        ));
+        let half_approle =
+            vault(json!({ "address": "https://vault.example.com", "role_id": "role" }));
+        assert!(matches!(
+            validate_keys(&half_approle, &none),
+            Err(SecretsError::VaultConfigMissing)
+        ));
+
+        let token = vault(json!({ "address": "https://vault.example.com", "token": "t" }));
+        assert!(validate_keys(&token, &well_known).is_ok());
+        assert!(validate_keys(&none, &none).is_ok());
+    }
+
+    #[tokio::test]
+    async fn sign_and_verify_keys_load_concurrently() -> Result<(), Error> {
+        let delay = std::time::Duration::from_millis(200);
+        let slow_sign_keys = async move {
+            tokio::time::sleep(delay).await;
+            Ok(SignKeys::Inline(Vec::new()))
+        };
+        let slow_store = async move {
+            tokio::time::sleep(delay).await;
+            Ok(SecretStore::default())
+        };
+        let start = std::time::Instant::now();
+        merge_stores(slow_sign_keys, slow_store).await?;
+        assert!(start.elapsed() < delay * 2);
+        Ok(())
+    }
+
+    fn vault_keys(server: &mockito::ServerGuard, vault: &str) -> Keys {
+        Keys {
+            source: KeySource::Vault,
+            ldap_key: Some(String::from("cis/ldap")),
+            vault: Some(
+                serde_json::from_value(serde_json::json!({
+                    "address": server.url(),
+                    "role_id": "role",
+                    "secret_id": vault,
+                }))
+                .unwrap(),
+            ),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn secret_store_from_vault() -> Result<(), Error> {
+        let mut server = mockito::Server::new_async().await;
+        server
+            .mock("POST", "/v1/auth/approle/login")
+            .match_body(mockito::Matcher::Json(
+                serde_json::json!({ "role_id": "role", "secret_id": "secret" }),
+            ))
+            .with_body(r#"{"auth":{"client_token":"vault-token"}}"#)
+            .create_async()
+            .await;
+        let read = server
+            .mock("GET", "/v1/secret/data/cis/ldap")
+            .match_header("x-vault-token", "vault-token")
+            .with_body(
+                serde_json::json!({
+                    "data": { "data": { "key": include_str!("../tests/data/fake_key.json") } }
+                })
+                .to_string(),
+            )
+            .create_async()
+            .await;
+        get_store_from_keys(&vault_keys(&server, "secret"), &Keys::default()).await?;
+        read.assert_async().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn secret_store_from_vault_auth_failure() -> Result<(), Error> {
+        let mut server = mockito::Server::new_async().await;
+        server
+            .mock("POST", "/v1/auth/approle/login")
+            .with_status(400)
+            .create_async()
+            .await;
+        assert!(matches!(
+            get_store_from_keys(&Keys::default(), &vault_keys(&server, "wrong")).await,
+            Err(SecretsError::VaultAuthFailed(400))
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn secret_store_from_bundle() -> Result<(), Error> {
+        let verify_keys = Keys {
+            source: KeySource::FileBundle,
+            bundle_file: Some(String::from("tests/data/fake_key_bundle.json")),
+            ldap_key: Some(String::from("ldap")),
+            hris_key: Some(String::from("hris")),
+            ..Default::default()
+        };
+        get_store_from_keys(&Keys::default(), &verify_keys).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn secret_store_from_bundle_missing_realm() -> Result<(), Error> {
+        let sign_keys = Keys {
+            source: KeySource::FileBundle,
+            bundle_file: Some(String::from("tests/data/fake_key_bundle.json")),
+            cis_key: Some(String::from("cis")),
+            ..Default::default()
+        };
+        assert!(matches!(
+            get_store_from_keys(&sign_keys, &Keys::default()).await,
+            Err(SecretsError::BundleRealmMissing(realm)) if realm == "cis"
+        ));
+        Ok(())
+    }
 }
@@ -1,28 +1,139 @@
+use crate::builder::DEFAULT_USER_AGENT;
+use crate::error::CisClientError;
+use crate::secrets::validate_keys;
+use reqwest::Client;
 use serde::Deserialize;
+use serde::Serialize;
+use serde_json::Value;
+use std::fmt;
+use std::time::Duration;
 use url::Url;
 
-#[derive(Clone, Debug, Deserialize)]
+/// Placeholder for secrets in [CisSettings::redacted] and `Debug` output.
+const REDACTED: &str = "***";
+
+#[derive(Clone, Debug, Deserialize, Serialize, Default)]
 #[serde(rename_all = "lowercase")]
 pub enum KeySource {
+    #[default]
     None,
     File,
     Ssm,
     WellKnown,
+    /// Read each key from the environment variable named in the key field.
+    Env,
+    /// Read each key from the Vault KV v2 secret named in the key field.
+    Vault,
+    /// Read all keys from the JSON object in `bundle_file` mapping realm names
+    /// to keys. The key fields only select which realms are required.
+    #[serde(rename = "file_bundle")]
+    FileBundle,
 }
 
-impl Default for KeySource {
-    fn default() -> Self {
-        Self::None
+/// Vault server and credentials for [KeySource::Vault]. Either `token` or
+/// `role_id` and `secret_id` for AppRole login have to be set.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct VaultConfig {
+    pub address: Url,
+    /// Mount of the KV v2 secrets engine.
+    #[serde(default = "default_vault_mount")]
+    pub mount: String,
+    pub token: Option<String>,
+    pub role_id: Option<String>,
+    pub secret_id: Option<String>,
+}
+
+fn default_vault_mount() -> String {
+    String::from("secret")
+}
+
+impl fmt::Debug for VaultConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("VaultConfig")
+            .field("address", &self.address)
+            .field("mount", &self.mount)
+            .field("token", &redact(&self.token))
+            .field("role_id", &self.role_id)
+            .field("secret_id", &redact(&self.secret_id))
+            .finish()
+    }
+}
+
+impl VaultConfig {
+    fn redacted(&self) -> Self {
+        VaultConfig {
+            token: redact(&self.token),
+            secret_id: redact(&self.secret_id),
+            ..self.clone()
+        }
     }
 }
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Deserialize, Serialize)]
 pub struct ClientConfig {
     pub client_id: String,
     pub client_secret: String,
     pub audience: String,
     pub token_endpoint: Url,
+    /// Token endpoint used if `token_endpoint` cannot be connected to.
+    #[serde(default)]
+    pub fallback_token_endpoint: Option<Url>,
     pub scopes: String,
+    /// Seconds before expiry at which a cached token is considered stale and
+    /// gets refreshed, see [ClientConfig::with_refresh_margin].
+    #[serde(default = "default_refresh_margin_secs")]
+    pub(crate) refresh_margin_secs: u64,
+    /// Seconds the local clock may be behind the token issuer. Added on top of
+    /// the refresh margin, so tokens are refreshed
+    /// the refresh margin plus `clock_skew_leeway_secs` before they expire.
+    #[serde(default)]
+    pub clock_skew_leeway_secs: u64,
+    /// Client certificate for mutual TLS with the token endpoint, either a
+    /// path to a PEM file or the PEM itself. Requires `client_key`.
+    #[serde(default)]
+    pub client_cert: Option<String>,
+    /// PKCS#8 private key of `client_cert`, either a path to a PEM file or the
+    /// PEM itself.
+    #[serde(default)]
+    pub client_key: Option<String>,
+    /// Verify fetched tokens against this JWKS and check their audience.
+    #[cfg(feature = "verify-token")]
+    #[serde(default)]
+    pub jwks_endpoint: Option<Url>,
+}
+
+fn default_refresh_margin_secs() -> u64 {
+    60
+}
+
+impl ClientConfig {
+    pub fn new(
+        client_id: String,
+        client_secret: String,
+        audience: String,
+        token_endpoint: Url,
+        scopes: String,
+    ) -> Self {
+        ClientConfig {
+            client_id,
+            client_secret,
+            audience,
+            token_endpoint,
+            scopes,
+            ..Default::default()
+        }
+    }
+
+    /// Refresh cached tokens `refresh_margin` before they expire (60 seconds
+    /// by default). The margin is capped at half of a token's lifetime.
+    pub fn with_refresh_margin(mut self, refresh_margin: Duration) -> Self {
+        self.refresh_margin_secs = refresh_margin.as_secs();
+        self
+    }
+
+    pub fn refresh_margin(&self) -> Duration {
+        Duration::from_secs(self.refresh_margin_secs)
+    }
 }
 
 impl Default for ClientConfig {
@@ -32,12 +143,49 @@ impl Default for ClientConfig {
             client_secret: Default::default(),
             audience: Default::default(),
             token_endpoint: Url::parse("https://auth.mozilla.auth0.com/oauth/token").unwrap(),
+            fallback_token_endpoint: None,
             scopes: Default::default(),
+            refresh_margin_secs: default_refresh_margin_secs(),
+            clock_skew_leeway_secs: 0,
+            client_cert: None,
+            client_key: None,
+            #[cfg(feature = "verify-token")]
+            jwks_endpoint: None,
+        }
+    }
+}
+
+impl fmt::Debug for ClientConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut debug = f.debug_struct("ClientConfig");
+        debug
+            .field("client_id", &self.client_id)
+            .field("client_secret", &REDACTED)
+            .field("audience", &self.audience)
+            .field("token_endpoint", &self.token_endpoint)
+            .field("fallback_token_endpoint", &self.fallback_token_endpoint)
+            .field("scopes", &self.scopes)
+            .field("refresh_margin_secs", &self.refresh_margin_secs)
+            .field("clock_skew_leeway_secs", &self.clock_skew_leeway_secs)
+            .field("client_cert", &self.client_cert)
+            .field("client_key", &redact(&self.client_key));
+        #[cfg(feature = "verify-token")]
+        debug.field("jwks_endpoint", &self.jwks_endpoint);
+        debug.finish()
+    }
+}
+
+impl ClientConfig {
+    fn redacted(&self) -> Self {
+        ClientConfig {
+            client_secret: String::from(REDACTED),
+            client_key: redact(&self.client_key),
+            ..self.clone()
         }
     }
 }
 
-#[derive(Clone, Debug, Deserialize, Default)]
+#[derive(Clone, Deserialize, Serialize, Default)]
 pub struct Keys {
     pub source: KeySource,
     pub well_known_iam_endpoint: Option<Url>,
@@ -46,9 +194,45 @@ pub struct Keys {
     pub ldap_key: Option<String>,
     pub cis_key: Option<String>,
     pub access_provider_key: Option<String>,
+    pub vault: Option<VaultConfig>,
+    pub bundle_file: Option<String>,
+}
+
+impl fmt::Debug for Keys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Keys")
+            .field("source", &self.source)
+            .field("well_known_iam_endpoint", &self.well_known_iam_endpoint)
+            .field("mozilliansorg_key", &redact(&self.mozilliansorg_key))
+            .field("hris_key", &redact(&self.hris_key))
+            .field("ldap_key", &redact(&self.ldap_key))
+            .field("cis_key", &redact(&self.cis_key))
+            .field("access_provider_key", &redact(&self.access_provider_key))
+            .field("vault", &self.vault)
+            .field("bundle_file", &self.bundle_file)
+            .finish()
+    }
 }
 
-#[derive(Clone, Debug, Deserialize)]
+impl Keys {
+    fn redacted(&self) -> Self {
+        Keys {
+            mozilliansorg_key: redact(&self.mozilliansorg_key),
+            hris_key: redact(&self.hris_key),
+            ldap_key: redact(&self.ldap_key),
+            cis_key: redact(&self.cis_key),
+            access_provider_key: redact(&self.access_provider_key),
+            vault: self.vault.as_ref().map(VaultConfig::redacted),
+            ..self.clone()
+        }
+    }
+}
+
+fn redact(value: &Option<String>) -> Option<String> {
+    value.as_ref().map(|_| String::from(REDACTED))
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct CisSettings {
     pub person_api_user_endpoint: Url,
     pub person_api_users_endpoint: Url,
@@ -57,6 +241,32 @@ pub struct CisSettings {
     pub client_config: ClientConfig,
     pub sign_keys: Keys,
     pub verify_keys: Keys,
+    /// Proxy for plain http requests.
+    pub http_proxy: Option<Url>,
+    /// Proxy for https requests.
+    pub https_proxy: Option<Url>,
+    /// Comma separated hosts to reach without the proxies.
+    pub no_proxy: Option<String>,
+    /// Seconds to wait for a connection including the TLS handshake.
+    pub connect_timeout_secs: Option<u64>,
+    /// Seconds to wait for a response body once its headers arrived. Only
+    /// applied by the async client.
+    pub read_timeout_secs: Option<u64>,
+    /// Seconds a single API call may take including the token refresh and
+    /// retries. Only applied by the async client.
+    pub deadline_secs: Option<u64>,
+    /// Maximum number of idle connections kept per host.
+    pub pool_max_idle_per_host: Option<usize>,
+    /// Speak HTTP/2 without negotiating it first.
+    #[serde(default)]
+    pub http2_prior_knowledge: bool,
+    /// Maximum size of a response body in bytes.
+    pub max_response_bytes: Option<usize>,
+    /// Seconds between reloads of the sign and verify keys, e.g. to pick up
+    /// rotated keys from the well-known endpoint.
+    pub secret_store_reload_secs: Option<u64>,
+    /// Profile schema version to request from Person and Change API.
+    pub schema_version: Option<String>,
 }
 
 impl Default for CisSettings {
@@ -73,10 +283,84 @@ impl Default for CisSettings {
             client_config: Default::default(),
             sign_keys: Default::default(),
             verify_keys: Default::default(),
+            http_proxy: None,
+            https_proxy: None,
+            no_proxy: None,
+            connect_timeout_secs: None,
+            read_timeout_secs: None,
+            deadline_secs: None,
+            pool_max_idle_per_host: None,
+            http2_prior_knowledge: false,
+            max_response_bytes: None,
+            secret_store_reload_secs: None,
+            schema_version: None,
+        }
+    }
+}
+
+impl CisSettings {
+    /// Copy of these settings with the client secret, key fields and Vault
+    /// credentials replaced by `***`, safe to serialize for inspection.
+    pub fn redacted(&self) -> Self {
+        CisSettings {
+            client_config: self.client_config.redacted(),
+            sign_keys: self.sign_keys.redacted(),
+            verify_keys: self.verify_keys.redacted(),
+            ..self.clone()
+        }
+    }
+
+    /// Check that the sign and verify key sources have the fields they need
+    /// without loading any keys. Building a client runs the same checks.
+    pub fn validate(&self) -> Result<(), CisClientError> {
+        validate_keys(&self.sign_keys, &self.verify_keys).map_err(Into::into)
+    }
+
+    /// Load endpoints from a Mozilla IAM well-known document.
+    ///
+    /// Person and Change API endpoints are derived from `api.endpoints.person`
+    /// and `api.endpoints.change`. The token endpoint is read from the OIDC
+    /// discovery document at `oidc_discovery_uri`. `api.audience` is used as
+    /// the token audience if present. Credentials and keys are left at their
+    /// defaults.
+    pub async fn from_discovery(well_known_url: &Url) -> Result<Self, CisClientError> {
+        let client = Client::builder().user_agent(DEFAULT_USER_AGENT).build()?;
+        let well_known: Value = fetch_json(&client, well_known_url.clone()).await?;
+        let person = discovered_url(&well_known, "/api/endpoints/person")?;
+        let change = discovered_url(&well_known, "/api/endpoints/change")?;
+        let oidc_discovery = discovered_url(&well_known, "/oidc_discovery_uri")?;
+        let oidc: Value = fetch_json(&client, oidc_discovery).await?;
+        let mut client_config = ClientConfig {
+            token_endpoint: discovered_url(&oidc, "/token_endpoint")?,
+            ..Default::default()
+        };
+        if let Some(audience) = well_known.pointer("/api/audience").and_then(Value::as_str) {
+            client_config.audience = audience.to_owned();
         }
+        Ok(CisSettings {
+            person_api_user_endpoint: person.join("/v2/user/")?,
+            person_api_users_endpoint: person.join("/v2/users")?,
+            change_api_user_endpoint: change.join("/v2/user")?,
+            change_api_users_endpoint: change.join("/v2/users")?,
+            client_config,
+            ..Default::default()
+        })
     }
 }
 
+async fn fetch_json(client: &Client, url: Url) -> Result<Value, CisClientError> {
+    let res = client.get(url).send().await?.error_for_status()?;
+    res.json().await.map_err(Into::into)
+}
+
+fn discovered_url(doc: &Value, pointer: &'static str) -> Result<Url, CisClientError> {
+    let url = doc
+        .pointer(pointer)
+        .and_then(Value::as_str)
+        .ok_or(CisClientError::MissingDiscoveryField(pointer))?;
+    Url::parse(url).map_err(Into::into)
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -95,4 +379,117 @@ mod test {
     fn client_config_default() {
         ClientConfig::default();
     }
+
+    #[test]
+    fn redacted_hides_secrets() -> Result<(), serde_json::Error> {
+        let mut settings = CisSettings::default();
+        settings.client_config.client_secret = String::from("hunter2");
+        settings.sign_keys.ldap_key = Some(String::from("ldap-key-material"));
+        let redacted = serde_json::to_string(&settings.redacted())?;
+        assert!(!redacted.contains("hunter2"));
+        assert!(!redacted.contains("ldap-key-material"));
+        assert!(redacted.contains(r#""ldap_key":"***""#));
+        let round_trip: CisSettings = serde_json::from_str(&redacted)?;
+        assert_eq!(
+            round_trip.person_api_user_endpoint,
+            settings.person_api_user_endpoint
+        );
+        assert!(!format!("{:?}", settings.client_config).contains("hunter2"));
+        Ok(())
+    }
+
+    #[test]
+    fn debug_hides_secrets() {
+        let client_config = ClientConfig {
+            client_id: String::from("client"),
+            client_secret: String::from("hunter2"),
+            ..Default::default()
+        };
+        let debug = format!("{:?}", client_config);
+        assert!(debug.contains(r#"client_secret: "***""#));
+        assert!(!debug.contains("hunter2"));
+
+        let settings = CisSettings {
+            client_config,
+            sign_keys: Keys {
+                hris_key: Some(String::from("hris-key-material")),
+                vault: Some(VaultConfig {
+                    address: Url::parse("https://vault.example.com").unwrap(),
+                    mount: default_vault_mount(),
+                    token: Some(String::from("vault-token")),
+                    role_id: None,
+                    secret_id: None,
+                }),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let debug = format!("{:?}", settings);
+        assert!(debug.contains(r#"hris_key: Some("***")"#));
+        assert!(debug.contains("ldap_key: None"));
+        assert!(!debug.contains("hunter2"));
+        assert!(!debug.contains("hris-key-material"));
+        assert!(!debug.contains("vault-token"));
+    }
+
+    #[tokio::test]
+    async fn from_discovery() -> Result<(), CisClientError> {
+        let mut server = mockito::Server::new_async().await;
+        server
+            .mock("GET", "/.well-known/mozilla-iam")
+            .with_body(format!(
+                r#"{{
+                    "oidc_discovery_uri": "{0}/.well-known/openid-configuration",
+                    "api": {{
+                        "audience": "api.example.com",
+                        "endpoints": {{
+                            "person": "https://person.example.com",
+                            "change": "https://change.example.com"
+                        }}
+                    }}
+                }}"#,
+                server.url()
+            ))
+            .create_async()
+            .await;
+        server
+            .mock("GET", "/.well-known/openid-configuration")
+            .with_body(r#"{"token_endpoint": "https://auth.example.com/oauth/token"}"#)
+            .create_async()
+            .await;
+        let well_known = Url::parse(&format!("{}/.well-known/mozilla-iam", server.url()))?;
+        let settings = CisSettings::from_discovery(&well_known).await?;
+        assert_eq!(
+            settings.person_api_user_endpoint.as_str(),
+            "https://person.example.com/v2/user/"
+        );
+        assert_eq!(
+            settings.change_api_users_endpoint.as_str(),
+            "https://change.example.com/v2/users"
+        );
+        assert_eq!(
+            settings.client_config.token_endpoint.as_str(),
+            "https://auth.example.com/oauth/token"
+        );
+        assert_eq!(settings.client_config.audience, "api.example.com");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn from_discovery_missing_field() -> Result<(), CisClientError> {
+        let mut server = mockito::Server::new_async().await;
+        server
+            .mock("GET", "/.well-known/mozilla-iam")
+            .with_body(r#"{"api": {"endpoints": {"person": "https://person.example.com"}}}"#)
+            .create_async()
+            .await;
+        let well_known = Url::parse(&format!("{}/.well-known/mozilla-iam", server.url()))?;
+        assert!(matches!(
+            CisSettings::from_discovery(&well_known).await,
+            Err(CisClientError::MissingDiscoveryField(
+                "/api/endpoints/change"
+            ))
+        ));
+        Ok(())
+    }
 }
@@ -0,0 +1,258 @@
+use crate::error::ProfileError;
+use cis_profile::crypto::SecretStore;
+use cis_profile::crypto::Signer;
+use cis_profile::crypto::Verifier;
+use cis_profile::crypto::WithPublisher;
+use cis_profile::schema::AccessInformationValuesArray;
+use cis_profile::schema::Profile;
+use cis_profile::schema::PublisherAuthority;
+use cis_profile::schema::StandardAttributeBoolean;
+use cis_profile::schema::StandardAttributeString;
+use cis_profile::schema::StandardAttributeValues;
+
+/// Run `$body` for every signed attribute of `$profile`, with `$name` bound
+/// to its dotted name, e.g. `staff_information.title`, and `$attr` to the
+/// attribute borrowed like `$profile` (`&profile` or `&mut profile`).
+macro_rules! for_each_attribute {
+    (&mut $profile:ident, |$name:ident, $attr:ident| $body:block) => {
+        for_each_attribute!(@fields mut, $profile, $name, $attr, $body)
+    };
+    (&$profile:ident, |$name:ident, $attr:ident| $body:block) => {
+        for_each_attribute!(@fields ref, $profile, $name, $attr, $body)
+    };
+    (@fields $mode:ident, $profile:ident, $name:ident, $attr:ident, $body:block) => {
+        for_each_attribute!(
+            @each $mode, $profile, $name, $attr, $body;
+            access_information.access_provider,
+            access_information.hris,
+            access_information.ldap,
+            access_information.mozilliansorg,
+            active,
+            alternative_name,
+            created,
+            description,
+            first_name,
+            fun_title,
+            identities.github_id_v3,
+            identities.github_id_v4,
+            identities.github_primary_email,
+            identities.mozilliansorg_id,
+            identities.bugzilla_mozilla_org_id,
+            identities.bugzilla_mozilla_org_primary_email,
+            identities.mozilla_ldap_id,
+            identities.mozilla_ldap_primary_email,
+            identities.mozilla_posix_id,
+            identities.google_oauth2_id,
+            identities.google_primary_email,
+            identities.firefox_accounts_id,
+            identities.firefox_accounts_primary_email,
+            identities.custom_1_primary_email,
+            identities.custom_2_primary_email,
+            identities.custom_3_primary_email,
+            languages,
+            last_modified,
+            last_name,
+            location,
+            login_method,
+            pgp_public_keys,
+            phone_numbers,
+            picture,
+            primary_email,
+            primary_username,
+            pronouns,
+            ssh_public_keys,
+            staff_information.manager,
+            staff_information.director,
+            staff_information.staff,
+            staff_information.title,
+            staff_information.team,
+            staff_information.cost_center,
+            staff_information.worker_type,
+            staff_information.wpr_desk_number,
+            staff_information.office_location,
+            tags,
+            timezone,
+            uris,
+            user_id,
+            usernames,
+            uuid,
+        )
+    };
+    (
+        @each ref, $profile:ident, $name:ident, $attr:ident, $body:block;
+        $($($field:ident).+,)+
+    ) => {
+        $({
+            let $name: &'static str = stringify!($($field).+);
+            let $attr = &$profile.$($field).+;
+            $body
+        })+
+    };
+    (
+        @each mut, $profile:ident, $name:ident, $attr:ident, $body:block;
+        $($($field:ident).+,)+
+    ) => {
+        $({
+            let $name: &'static str = stringify!($($field).+);
+            let $attr = &mut $profile.$($field).+;
+            $body
+        })+
+    };
+}
+
+/// The attribute types of [Profile] carrying a signature.
+trait Attribute: WithPublisher {
+    /// Whether a value is set. Unset attributes are not signed.
+    fn is_set(&self) -> bool;
+
+    fn realm(&self) -> PublisherAuthority {
+        self.get_publisher().name
+    }
+}
+
+impl Attribute for StandardAttributeString {
+    fn is_set(&self) -> bool {
+        self.value.is_some()
+    }
+}
+
+impl Attribute for StandardAttributeBoolean {
+    fn is_set(&self) -> bool {
+        self.value.is_some()
+    }
+}
+
+impl Attribute for StandardAttributeValues {
+    fn is_set(&self) -> bool {
+        self.values.is_some()
+    }
+}
+
+impl Attribute for AccessInformationValuesArray {
+    fn is_set(&self) -> bool {
+        self.values.is_some()
+    }
+}
+
+/// Verify every attribute carrying a value, including those nested in
+/// `access_information`, `identities` and `staff_information`. Unset
+/// attributes are not signed and therefore skipped.
+pub(crate) fn check_signatures(
+    secret_store: &SecretStore,
+    profile: &Profile,
+) -> Result<(), ProfileError> {
+    for_each_attribute!(&profile, |attribute, attr| {
+        if attr.is_set() {
+            verify_attribute(secret_store, attribute, attr)?;
+        }
+    });
+    Ok(())
+}
+
+/// Sign every attribute published by `realm`, including those nested in
+/// `access_information`, `identities` and `staff_information`.
+pub(crate) fn sign_profile(
+    secret_store: &SecretStore,
+    profile: &mut Profile,
+    realm: PublisherAuthority,
+) -> Result<(), ProfileError> {
+    check_sign_key(secret_store, realm)?;
+    for_each_attribute!(&mut profile, |attribute, attr| {
+        if attr.realm() == realm {
+            sign_attribute(secret_store, attribute, attr)?;
+        }
+    });
+    Ok(())
+}
+
+/// Sign the `active` attribute with the key of its publisher.
+pub(crate) fn sign_active(
+    secret_store: &SecretStore,
+    profile: &mut Profile,
+) -> Result<(), ProfileError> {
+    check_sign_key(secret_store, profile.active.signature.publisher.name)?;
+    sign_attribute(secret_store, "active", &mut profile.active)
+}
+
+/// Dotted names of the signed attributes of [Profile], as passed to
+/// [sign_attributes].
+pub(crate) fn attribute_names() -> Vec<&'static str> {
+    let profile = Profile::default();
+    let mut names = Vec::new();
+    for_each_attribute!(&profile, |attribute, _attr| {
+        names.push(attribute);
+    });
+    names
+}
+
+/// Sign the attributes named in `names` with the key of `realm`, which
+/// becomes their publisher, or else with the key of their publisher. Fails
+/// with [ProfileError::UnsignableAttribute] before signing anything if a name
+/// is not one of [attribute_names].
+pub(crate) fn sign_attributes(
+    secret_store: &SecretStore,
+    profile: &mut Profile,
+    names: &[String],
+    realm: Option<PublisherAuthority>,
+) -> Result<(), ProfileError> {
+    let known = attribute_names();
+    if let Some(name) = names.iter().find(|name| !known.contains(&name.as_str())) {
+        return Err(ProfileError::UnsignableAttribute(name.clone()));
+    }
+    if let Some(realm) = realm {
+        check_sign_key(secret_store, realm)?;
+    }
+    for_each_attribute!(&mut profile, |attribute, attr| {
+        if names.iter().any(|name| name == attribute) {
+            if let Some(realm) = realm {
+                attr.get_publisher_mut().name = realm;
+            }
+            check_sign_key(secret_store, attr.realm())?;
+            sign_attribute(secret_store, attribute, attr)?;
+        }
+    });
+    Ok(())
+}
+
+/// Fail unless `secret_store` signs for `realm`, checked by signing an empty
+/// attribute before any attribute of the profile is touched.
+fn check_sign_key(
+    secret_store: &SecretStore,
+    realm: PublisherAuthority,
+) -> Result<(), ProfileError> {
+    let mut probe = StandardAttributeString::default();
+    probe.signature.publisher.name = realm;
+    secret_store
+        .sign_attribute(&mut probe)
+        .map_err(|_| ProfileError::MissingSignKey(realm_name(realm)))
+}
+
+fn realm_name(realm: PublisherAuthority) -> &'static str {
+    match realm {
+        PublisherAuthority::Mozilliansorg => "mozilliansorg",
+        PublisherAuthority::Hris => "hris",
+        PublisherAuthority::Ldap => "ldap",
+        PublisherAuthority::Cis => "cis",
+        PublisherAuthority::AccessProvider => "access_provider",
+    }
+}
+
+fn verify_attribute(
+    secret_store: &SecretStore,
+    attribute: &'static str,
+    attr: &impl WithPublisher,
+) -> Result<(), ProfileError> {
+    secret_store
+        .verify_attribute(attr)
+        .map_err(|source| ProfileError::InvalidSignature { attribute, source })
+}
+
+fn sign_attribute(
+    secret_store: &SecretStore,
+    attribute: &'static str,
+    attr: &mut impl WithPublisher,
+) -> Result<(), ProfileError> {
+    secret_store
+        .sign_attribute(attr)
+        .map_err(|source| ProfileError::SigningFailed { attribute, source })
+}
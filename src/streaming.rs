@@ -0,0 +1,327 @@
+use crate::batch::parse_profile;
+use crate::batch::Batch;
+use crate::client::read_within;
+use crate::error::CisClientError;
+use cis_profile::schema::Profile;
+use reqwest::Response;
+use serde::de;
+use serde::de::DeserializeSeed;
+use serde::de::Deserializer;
+use serde::de::IgnoredAny;
+use serde::de::MapAccess;
+use serde::de::SeqAccess;
+use serde::de::Visitor;
+use serde_json::Value;
+use std::fmt;
+use std::io;
+use std::io::Read;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+/// Number of received chunks buffered ahead of the parser.
+const CHUNK_BUFFER: usize = 4;
+
+/// Parse a `/users` response read from `reader`, holding at most one item in
+/// memory at a time. `strict` applies to every item like in
+/// [Batch::from_json].
+pub(crate) fn batch_from_reader<R: Read>(reader: R, strict: bool) -> Result<Batch, CisClientError> {
+    let mut de = serde_json::Deserializer::from_reader(reader);
+    let mut rejected = None;
+    let batch = (&mut de).deserialize_map(BatchVisitor {
+        strict,
+        rejected: &mut rejected,
+    });
+    if let Some(e) = rejected {
+        return Err(e);
+    }
+    let batch = batch?;
+    de.end()?;
+    Ok(batch)
+}
+
+/// Like [batch_from_reader] failing with [CisClientError::ResponseTooLarge]
+/// once more than `max_bytes` are read.
+#[cfg(feature = "sync")]
+pub(crate) fn batch_from_limited_reader<R: Read>(
+    reader: R,
+    max_bytes: Option<usize>,
+    strict: bool,
+) -> Result<Batch, CisClientError> {
+    let max = match max_bytes {
+        Some(max) => max,
+        None => return batch_from_reader(reader, strict),
+    };
+    let mut reader = LimitedReader {
+        inner: reader,
+        remaining: max,
+        exceeded: false,
+    };
+    batch_from_reader(&mut reader, strict).map_err(|e| {
+        if reader.exceeded {
+            CisClientError::ResponseTooLarge(max)
+        } else {
+            e
+        }
+    })
+}
+
+/// Parse the body of `res` as `/users` response while it is received. The
+/// parser runs on a blocking thread fed with the body chunks. `read_timeout`
+/// applies to every chunk.
+pub(crate) async fn read_batch(
+    mut res: Response,
+    read_timeout: Option<Duration>,
+    max_bytes: Option<usize>,
+    strict: bool,
+) -> Result<Batch, CisClientError> {
+    let (tx, chunks) = mpsc::channel(CHUNK_BUFFER);
+    let parser = tokio::task::spawn_blocking(move || {
+        batch_from_reader(
+            ChunkReader {
+                chunks,
+                current: None,
+                pos: 0,
+            },
+            strict,
+        )
+    });
+    let mut received = 0;
+    while let Some(chunk) = read_within(read_timeout, res.chunk()).await? {
+        received += chunk.len();
+        match max_bytes {
+            Some(max) if received > max => return Err(CisClientError::ResponseTooLarge(max)),
+            _ => {}
+        }
+        if tx.send(chunk).await.is_err() {
+            // the parser gave up
+            break;
+        }
+    }
+    drop(tx);
+    parser
+        .await
+        .map_err(|e| CisClientError::TaskFailed(e.to_string()))?
+}
+
+/// Blocking [Read] over the chunks sent to `chunks`.
+struct ChunkReader<B> {
+    chunks: mpsc::Receiver<B>,
+    current: Option<B>,
+    pos: usize,
+}
+
+impl<B: AsRef<[u8]>> Read for ChunkReader<B> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if let Some(chunk) = &self.current {
+                let rest = &chunk.as_ref()[self.pos..];
+                if !rest.is_empty() {
+                    let n = rest.len().min(buf.len());
+                    buf[..n].copy_from_slice(&rest[..n]);
+                    self.pos += n;
+                    return Ok(n);
+                }
+            }
+            match self.chunks.blocking_recv() {
+                Some(chunk) => {
+                    self.current = Some(chunk);
+                    self.pos = 0;
+                }
+                None => return Ok(0),
+            }
+        }
+    }
+}
+
+/// [Read] failing once more than `remaining` bytes were read.
+#[cfg(feature = "sync")]
+struct LimitedReader<R> {
+    inner: R,
+    remaining: usize,
+    exceeded: bool,
+}
+
+#[cfg(feature = "sync")]
+impl<R: Read> Read for LimitedReader<R> {
+    // io::Error::other requires Rust 1.74
+    #[allow(unknown_lints, clippy::io_other_error)]
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if n > self.remaining {
+            self.exceeded = true;
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "response body too large",
+            ));
+        }
+        self.remaining -= n;
+        Ok(n)
+    }
+}
+
+/// Parses a `/users` response. An item rejected by strict parsing is stored
+/// in `rejected` as the parse error itself loses its type.
+struct BatchVisitor<'a> {
+    strict: bool,
+    rejected: &'a mut Option<CisClientError>,
+}
+
+impl<'de> Visitor<'de> for BatchVisitor<'_> {
+    type Value = Batch;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("a /users response")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Batch, A::Error> {
+        let mut batch = Batch {
+            items: None,
+            next_page: None,
+            skipped: 0,
+        };
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "Items" => {
+                    batch.items = map.next_value_seed(ItemsSeed {
+                        strict: self.strict,
+                        skipped: &mut batch.skipped,
+                        rejected: &mut *self.rejected,
+                    })?
+                }
+                "nextPage" => {
+                    batch.next_page = serde_json::from_value(map.next_value::<Value>()?).ok()
+                }
+                _ => {
+                    map.next_value::<IgnoredAny>()?;
+                }
+            }
+        }
+        Ok(batch)
+    }
+}
+
+/// Parses `Items` one profile at a time. Anything but an array yields `None`
+/// like [Batch::from_json].
+struct ItemsSeed<'a> {
+    strict: bool,
+    skipped: &'a mut usize,
+    rejected: &'a mut Option<CisClientError>,
+}
+
+impl<'de> DeserializeSeed<'de> for ItemsSeed<'_> {
+    type Value = Option<Vec<Profile>>;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_any(self)
+    }
+}
+
+impl<'de> Visitor<'de> for ItemsSeed<'_> {
+    type Value = Option<Vec<Profile>>;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("a list of profiles")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let mut items = Vec::new();
+        while let Some(item) = seq.next_element::<Value>()? {
+            match parse_profile(item, self.strict, self.skipped) {
+                Ok(profile) => items.extend(profile),
+                Err(e) => {
+                    let message = e.to_string();
+                    *self.rejected = Some(e);
+                    return Err(de::Error::custom(message));
+                }
+            }
+        }
+        Ok(Some(items))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+        while map.next_entry::<IgnoredAny, IgnoredAny>()?.is_some() {}
+        Ok(None)
+    }
+
+    fn visit_unit<E>(self) -> Result<Self::Value, E> {
+        Ok(None)
+    }
+
+    fn visit_bool<E>(self, _: bool) -> Result<Self::Value, E> {
+        Ok(None)
+    }
+
+    fn visit_i64<E>(self, _: i64) -> Result<Self::Value, E> {
+        Ok(None)
+    }
+
+    fn visit_u64<E>(self, _: u64) -> Result<Self::Value, E> {
+        Ok(None)
+    }
+
+    fn visit_f64<E>(self, _: f64) -> Result<Self::Value, E> {
+        Ok(None)
+    }
+
+    fn visit_str<E>(self, _: &str) -> Result<Self::Value, E> {
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::ProfileError;
+    use crate::test_util::profile_body;
+    use anyhow::Error;
+    use serde_json::json;
+
+    #[test]
+    fn batch_from_reader_matches_from_json() -> Result<(), Error> {
+        let profile: Value = serde_json::from_str(&profile_body("some-uuid"))?;
+        for body in &[
+            json!({ "Items": [profile, { "uuid": 1 }, profile], "nextPage": { "id": "2" } }),
+            json!({ "Items": null, "other": [1, 2] }),
+            json!({}),
+        ] {
+            let streamed = batch_from_reader(body.to_string().as_bytes(), false)?;
+            let buffered = Batch::from_json(body.clone(), false)?;
+            assert_eq!(
+                serde_json::to_value(&streamed.items)?,
+                serde_json::to_value(&buffered.items)?
+            );
+            assert_eq!(
+                streamed.next_page.map(|n| n.id),
+                buffered.next_page.map(|n| n.id)
+            );
+            assert_eq!(streamed.skipped, buffered.skipped);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn strict_batches_reject_unknown_fields() -> Result<(), Error> {
+        let mut profile: Value = serde_json::from_str(&profile_body("some-uuid"))?;
+        profile["new_attribute"] = json!({ "value": "x" });
+        let body = json!({ "Items": [{ "uuid": 1 }, profile] });
+        for strict in &[false, true] {
+            let streamed = batch_from_reader(body.to_string().as_bytes(), *strict);
+            let buffered = Batch::from_json(body.clone(), *strict);
+            for batch in [streamed, buffered] {
+                match batch {
+                    Ok(batch) if !strict => {
+                        assert_eq!(batch.items.map(|items| items.len()), Some(1));
+                        assert_eq!(batch.skipped, 1);
+                    }
+                    Err(CisClientError::ProfileError(ProfileError::UnknownFields(fields)))
+                        if *strict =>
+                    {
+                        assert_eq!(fields, vec!["new_attribute"])
+                    }
+                    r => panic!("unexpected result: {:?}", r),
+                }
+            }
+        }
+        Ok(())
+    }
+}
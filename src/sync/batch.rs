@@ -1,22 +1,16 @@
+use crate::batch::check_modified_since;
+use crate::batch::Batch;
+use crate::batch::NextPage;
+use crate::batch::ResumeToken;
+use crate::dedup::Dedup;
 use crate::error::CisClientError;
 use crate::error::ProfileError;
 use crate::sync::client::CisClientTrait;
+use chrono::DateTime;
+use chrono::Utc;
 use cis_profile::schema::Profile;
-use serde::Deserialize;
-use serde::Serialize;
 use std::iter::Iterator;
 
-#[derive(Deserialize, Serialize, Debug, Clone)]
-pub struct NextPage {
-    pub id: String,
-}
-
-#[derive(Debug)]
-pub struct Batch {
-    pub items: Option<Vec<Profile>>,
-    pub next_page: Option<NextPage>,
-}
-
 #[derive(PartialEq)]
 enum ProfileIterState {
     Uninitalized,
@@ -32,6 +26,8 @@ pub struct ProfileIter<T> {
     filter: Option<String>,
     current_batch: Option<Batch>,
     state: ProfileIterState,
+    dedup: Option<Dedup>,
+    modified_since: Option<DateTime<Utc>>,
 }
 
 impl<T> ProfileIter<T> {
@@ -41,8 +37,87 @@ impl<T> ProfileIter<T> {
             filter,
             current_batch: None,
             state: ProfileIterState::Uninitalized,
+            dedup: None,
+            modified_since: None,
         }
     }
+
+    /// Continue an export at `next_page`, see [ProfileIter::next_page].
+    pub fn resume_from(cis_client: T, filter: Option<String>, next_page: NextPage) -> Self {
+        ProfileIter {
+            cis_client,
+            filter,
+            current_batch: Some(Batch {
+                items: None,
+                next_page: Some(next_page),
+                skipped: 0,
+            }),
+            state: ProfileIterState::Inflight,
+            dedup: None,
+            modified_since: None,
+        }
+    }
+
+    /// Token to resume after the most recently yielded page. `None` before the
+    /// first page and after the last one.
+    pub fn next_page(&self) -> Option<&NextPage> {
+        self.current_batch
+            .as_ref()
+            .and_then(|batch| batch.next_page.as_ref())
+    }
+
+    /// Like [ProfileIter::resume_from] but fails with
+    /// [CisClientError::ResumeFilterMismatch] if `token` was issued for
+    /// another filter.
+    pub fn resume_from_token(
+        cis_client: T,
+        filter: Option<String>,
+        token: ResumeToken,
+    ) -> Result<Self, CisClientError> {
+        let next_page = token.next_page_for(&filter)?;
+        Ok(Self::resume_from(cis_client, filter, next_page))
+    }
+
+    /// Drop profiles already yielded by this export, e.g. users returned on
+    /// two adjacent pages because of concurrent writes. Profiles are keyed on
+    /// `uuid`, falling back to `user_id`. With `window` only the keys of the
+    /// last `window` profiles are kept, bounding memory but missing duplicates
+    /// further apart. `None` keeps every key, so memory grows with the size of
+    /// the export.
+    pub fn with_dedup(mut self, window: Option<usize>) -> Self {
+        self.dedup = Some(Dedup::new(window));
+        self
+    }
+
+    /// Only fetch profiles modified at or after `since`, see
+    /// [CisClientTrait::get_batch_modified_since]. The iterator fails with
+    /// [CisClientError::ModifiedSinceIgnored] on a page with an older profile.
+    pub fn with_modified_since(mut self, since: DateTime<Utc>) -> Self {
+        self.modified_since = Some(since);
+        self
+    }
+
+    /// [ProfileIter::next_page] together with the filter of this export.
+    pub fn resume_token(&self) -> Option<ResumeToken> {
+        self.next_page().cloned().map(|next_page| ResumeToken {
+            next_page,
+            filter: self.filter.clone(),
+        })
+    }
+}
+
+impl<T: CisClientTrait> ProfileIter<T> {
+    fn fetch(&self, next_page: &Option<NextPage>) -> Result<Batch, CisClientError> {
+        let since = match self.modified_since {
+            Some(since) => since,
+            None => return self.cis_client.get_batch(next_page, &self.filter),
+        };
+        let batch = self
+            .cis_client
+            .get_batch_modified_since(next_page, &self.filter, since)?;
+        check_modified_since(&batch, since)?;
+        Ok(batch)
+    }
 }
 
 impl<T: CisClientTrait> Iterator for ProfileIter<T> {
@@ -50,25 +125,26 @@ impl<T: CisClientTrait> Iterator for ProfileIter<T> {
     fn next(&mut self) -> Option<Self::Item> {
         match self.state {
             ProfileIterState::Done | ProfileIterState::CisClientError => None,
-            ProfileIterState::Uninitalized => {
-                match self.cis_client.get_batch(&None, &self.filter) {
-                    Ok(new_batch) => {
-                        self.state = ProfileIterState::Inflight;
-                        self.current_batch = Some(new_batch);
-                        self.next()
-                    }
-                    Err(e) => {
-                        self.state = ProfileIterState::CisClientError;
-                        Some(Err(e))
-                    }
+            ProfileIterState::Uninitalized => match self.fetch(&None) {
+                Ok(new_batch) => {
+                    self.state = ProfileIterState::Inflight;
+                    self.current_batch = Some(new_batch);
+                    self.next()
                 }
-            }
+                Err(e) => {
+                    self.state = ProfileIterState::CisClientError;
+                    Some(Err(e))
+                }
+            },
             ProfileIterState::Inflight => {
                 if let Some(batch) = &mut self.current_batch {
-                    if let Some(profiles) = batch.items.take() {
+                    if let Some(mut profiles) = batch.items.take() {
+                        if let Some(dedup) = &mut self.dedup {
+                            dedup.retain_new(&mut profiles);
+                        }
                         Some(Ok(profiles))
                     } else if let Some(next_page) = batch.next_page.take() {
-                        match self.cis_client.get_batch(&Some(next_page), &self.filter) {
+                        match self.fetch(&Some(next_page)) {
                             Ok(new_batch) => {
                                 self.current_batch = Some(new_batch);
                                 self.next()
@@ -94,12 +170,17 @@ impl<T: CisClientTrait> Iterator for ProfileIter<T> {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::batch::NextPage;
+    use crate::bulk::UpdateUsersResult;
     use crate::getby::GetBy;
     use cis_profile::crypto::SecretStore;
     use serde_json::Value;
 
+    #[derive(Clone)]
     struct CisClientFaker {
         count: usize,
+        /// Repeat the last profile of the previous page on every page.
+        repeat: bool,
     }
     impl CisClientTrait for CisClientFaker {
         type PI = ProfileIter<Self>;
@@ -127,9 +208,17 @@ mod test {
         ) -> Result<Profile, CisClientError> {
             unimplemented!()
         }
-        fn get_users_iter(&self, _: Option<&str>) -> Result<Self::PI, CisClientError> {
+        fn get_user_raw_by(
+            &self,
+            _: &str,
+            _: &GetBy,
+            _: Option<&str>,
+        ) -> Result<Value, CisClientError> {
             unimplemented!()
         }
+        fn get_users_iter(&self, filter: Option<&str>) -> Result<Self::PI, CisClientError> {
+            Ok(ProfileIter::new(self.clone(), filter.map(String::from)))
+        }
         fn get_batch(
             &self,
             pagination_token: &Option<NextPage>,
@@ -139,6 +228,7 @@ mod test {
                 return Ok(Batch {
                     items: None,
                     next_page: None,
+                    skipped: 0,
                 });
             };
             let left = if let Some(n) = pagination_token {
@@ -146,8 +236,17 @@ mod test {
             } else {
                 self.count
             };
-            return Ok(Batch {
-                items: Some(vec![Profile::default()]),
+            let profile = |uuid: usize| {
+                let mut profile = Profile::default();
+                profile.uuid.value = Some(uuid.to_string());
+                profile
+            };
+            let mut items = vec![profile(left)];
+            if self.repeat && left < self.count {
+                items.insert(0, profile(left + 1));
+            }
+            Ok(Batch {
+                items: Some(items),
                 next_page: if left > 1 {
                     Some(NextPage {
                         id: format!("{}", left - 1),
@@ -155,15 +254,22 @@ mod test {
                 } else {
                     None
                 },
-            });
+                skipped: 0,
+            })
+        }
+        fn create_user_raw(&self, _: Profile) -> Result<Value, CisClientError> {
+            unimplemented!()
+        }
+        fn update_user_raw(&self, _: &str, _: Profile) -> Result<Value, CisClientError> {
+            unimplemented!()
         }
-        fn update_user(&self, _: &str, _: Profile) -> Result<Value, CisClientError> {
+        fn update_users(&self, _: &[Profile]) -> Result<UpdateUsersResult, CisClientError> {
             unimplemented!()
         }
-        fn update_users(&self, _: &[Profile]) -> Result<Value, CisClientError> {
+        fn delete_user_raw(&self, _: &str, _: Profile) -> Result<Value, CisClientError> {
             unimplemented!()
         }
-        fn delete_user(&self, _: &str, _: Profile) -> Result<Value, CisClientError> {
+        fn delete_users(&self, _: &[Profile]) -> Result<Value, CisClientError> {
             unimplemented!()
         }
         fn get_secret_store(&self) -> &SecretStore {
@@ -173,14 +279,26 @@ mod test {
 
     #[test]
     fn test_profile_iter_empty() -> Result<(), CisClientError> {
-        let mut iter = ProfileIter::new(CisClientFaker { count: 0 }, None);
+        let mut iter = ProfileIter::new(
+            CisClientFaker {
+                count: 0,
+                repeat: false,
+            },
+            None,
+        );
         assert!(iter.next().is_none());
         Ok(())
     }
 
     #[test]
     fn test_profile_iter1() -> Result<(), CisClientError> {
-        let mut iter = ProfileIter::new(CisClientFaker { count: 1 }, None);
+        let mut iter = ProfileIter::new(
+            CisClientFaker {
+                count: 1,
+                repeat: false,
+            },
+            None,
+        );
         assert!(iter.next().is_some());
         assert!(iter.next().is_none());
         Ok(())
@@ -188,10 +306,120 @@ mod test {
 
     #[test]
     fn test_profile_iter2() -> Result<(), CisClientError> {
-        let mut iter = ProfileIter::new(CisClientFaker { count: 2 }, None);
+        let mut iter = ProfileIter::new(
+            CisClientFaker {
+                count: 2,
+                repeat: false,
+            },
+            None,
+        );
         assert!(iter.next().is_some());
         assert!(iter.next().is_some());
         assert!(iter.next().is_none());
         Ok(())
     }
+
+    #[test]
+    fn test_profile_iter_resume() -> Result<(), CisClientError> {
+        let faker = CisClientFaker {
+            count: 3,
+            repeat: false,
+        };
+        let mut iter = ProfileIter::new(faker.clone(), None);
+        iter.next().transpose()?;
+        let token = iter.next_page().cloned().expect("next page");
+        assert_eq!(token.id, "2");
+        let rest = ProfileIter::resume_from(faker, None, token).collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(rest.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_profile_iter_resume_token_checks_filter() -> Result<(), CisClientError> {
+        let faker = CisClientFaker {
+            count: 3,
+            repeat: false,
+        };
+        let mut iter = ProfileIter::new(faker.clone(), None);
+        iter.next().transpose()?;
+        let token = iter.resume_token().expect("resume token");
+        let staff = Some(String::from("staff"));
+        assert!(matches!(
+            ProfileIter::resume_from_token(faker.clone(), staff, token.clone()),
+            Err(CisClientError::ResumeFilterMismatch {
+                token: None,
+                filter: Some(_)
+            })
+        ));
+        let rest =
+            ProfileIter::resume_from_token(faker, None, token)?.collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(rest.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_profile_iter_dedup() -> Result<(), CisClientError> {
+        let faker = CisClientFaker {
+            count: 3,
+            repeat: true,
+        };
+        let uuids = |pages: Vec<Vec<Profile>>| -> Vec<String> {
+            pages
+                .into_iter()
+                .flatten()
+                .filter_map(|p| p.uuid.value)
+                .collect()
+        };
+        let all = ProfileIter::new(faker.clone(), None).collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(uuids(all), vec!["3", "3", "2", "2", "1"]);
+        for window in &[None, Some(1)] {
+            let deduped = ProfileIter::new(faker.clone(), None)
+                .with_dedup(*window)
+                .collect::<Result<Vec<_>, _>>()?;
+            assert_eq!(uuids(deduped), vec!["3", "2", "1"]);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_profile_iter_invalid_state() {
+        let mut iter = ProfileIter::new(
+            CisClientFaker {
+                count: 1,
+                repeat: false,
+            },
+            None,
+        );
+        iter.state = ProfileIterState::Inflight;
+        assert!(matches!(
+            iter.next(),
+            Some(Err(CisClientError::ProfileError(
+                ProfileError::InvalidIterState
+            )))
+        ));
+        assert!(iter.next().is_none());
+    }
+
+    #[test]
+    fn test_get_all_users4() -> Result<(), CisClientError> {
+        let faker = CisClientFaker {
+            count: 4,
+            repeat: false,
+        };
+        assert_eq!(faker.get_all_users(None)?.len(), 4);
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_all_users_with_progress4() -> Result<(), CisClientError> {
+        let faker = CisClientFaker {
+            count: 4,
+            repeat: false,
+        };
+        let mut totals = Vec::new();
+        let profiles = faker.get_all_users_with_progress(None, |total| totals.push(total))?;
+        assert_eq!(profiles.len(), 4);
+        assert_eq!(totals, vec![1, 2, 3, 4]);
+        Ok(())
+    }
 }
@@ -1,21 +1,39 @@
+use crate::batch::modified_since_param;
+use crate::batch::Batch;
+use crate::batch::NextPage;
+use crate::bulk::UpdateUsersResult;
+use crate::change::ChangeResponse;
+use crate::client::api_status;
+use crate::client::conflict_as_existing_profile;
+use crate::client::json_body;
+use crate::client::not_found_as_missing_profile;
+use crate::client::request_error;
+use crate::client::schema_version_accept;
 use crate::client::CisClient;
-use crate::encoding::USERINFO_ENCODE_SET;
 use crate::error::CisClientError;
 use crate::error::ProfileError;
+use crate::getby::ActiveFilter;
 use crate::getby::GetBy;
-use crate::sync::batch::Batch;
-use crate::sync::batch::NextPage;
+use crate::retry;
+use crate::schema::profile_from_json;
+use crate::streaming::batch_from_limited_reader;
 use crate::sync::batch::ProfileIter;
+use chrono::DateTime;
+use chrono::Utc;
 use cis_profile::crypto::SecretStore;
 use cis_profile::schema::Profile;
-use log::info;
-use percent_encoding::utf8_percent_encode;
-use reqwest::blocking::Client;
+use log::trace;
+use reqwest::blocking::RequestBuilder;
+use reqwest::blocking::Response;
+use reqwest::header::ACCEPT;
+use reqwest::Method;
 use reqwest::Url;
 use serde::de::DeserializeOwned;
 use serde::Serialize;
-use serde_json::json;
 use serde_json::Value;
+use std::io::BufReader;
+use std::io::Read;
+use std::time::Instant;
 
 pub trait CisClientTrait {
     type PI: Iterator<Item = Result<Vec<Profile>, CisClientError>>;
@@ -37,77 +55,256 @@ pub trait CisClientTrait {
         by: &GetBy,
         filter: Option<&str>,
     ) -> Result<Profile, CisClientError>;
+    /// Like [CisClientTrait::get_user_by] but returns the untyped JSON and does
+    /// not check whether the profile exists.
+    fn get_user_raw_by(
+        &self,
+        id: &str,
+        by: &GetBy,
+        filter: Option<&str>,
+    ) -> Result<Value, CisClientError>;
     fn get_users_iter(&self, filter: Option<&str>) -> Result<Self::PI, CisClientError>;
     fn get_batch(
         &self,
         next_page: &Option<NextPage>,
         filter: &Option<String>,
     ) -> Result<Batch, CisClientError>;
-    fn update_user(&self, id: &str, profile: Profile) -> Result<Value, CisClientError>;
-    fn update_users(&self, profiles: &[Profile]) -> Result<Value, CisClientError>;
-    fn delete_user(&self, id: &str, profile: Profile) -> Result<Value, CisClientError>;
+    /// Like [CisClientTrait::get_batch] only fetching profiles modified at or
+    /// after `since`. The default implementation ignores `since`.
+    fn get_batch_modified_since(
+        &self,
+        next_page: &Option<NextPage>,
+        filter: &Option<String>,
+        _since: DateTime<Utc>,
+    ) -> Result<Batch, CisClientError> {
+        self.get_batch(next_page, filter)
+    }
+    /// Fetch all matching profiles, failing on the first batch error.
+    ///
+    /// This holds every profile in memory at once. Use
+    /// [CisClientTrait::get_users_iter] to process large exports page by page.
+    fn get_all_users(&self, filter: Option<&str>) -> Result<Vec<Profile>, CisClientError> {
+        let mut profiles = Vec::new();
+        for batch in self.get_users_iter(filter)? {
+            profiles.extend(batch?);
+        }
+        Ok(profiles)
+    }
+    /// Like [CisClientTrait::get_all_users] calling `progress` with the number
+    /// of profiles fetched so far after every page, e.g. to render a progress
+    /// bar.
+    fn get_all_users_with_progress<F: FnMut(usize)>(
+        &self,
+        filter: Option<&str>,
+        mut progress: F,
+    ) -> Result<Vec<Profile>, CisClientError>
+    where
+        Self: Sized,
+    {
+        let mut profiles = Vec::new();
+        for batch in self.get_users_iter(filter)? {
+            profiles.extend(batch?);
+            progress(profiles.len());
+        }
+        Ok(profiles)
+    }
+    fn create_user(&self, profile: Profile) -> Result<ChangeResponse, CisClientError> {
+        ChangeResponse::from_json(self.create_user_raw(profile)?)
+    }
+    /// Like [CisClientTrait::create_user] but returns the untyped JSON.
+    fn create_user_raw(&self, profile: Profile) -> Result<Value, CisClientError>;
+    fn update_user(&self, id: &str, profile: Profile) -> Result<ChangeResponse, CisClientError> {
+        ChangeResponse::from_json(self.update_user_raw(id, profile)?)
+    }
+    /// Like [CisClientTrait::update_user] but returns the untyped JSON.
+    fn update_user_raw(&self, id: &str, profile: Profile) -> Result<Value, CisClientError>;
+    /// Update profiles in chunks of `batch_size`. Every chunk is sent, the
+    /// result records which profiles were rejected.
+    fn update_users(&self, profiles: &[Profile]) -> Result<UpdateUsersResult, CisClientError>;
+    fn delete_user(&self, id: &str, profile: Profile) -> Result<ChangeResponse, CisClientError> {
+        ChangeResponse::from_json(self.delete_user_raw(id, profile)?)
+    }
+    /// Like [CisClientTrait::delete_user] but returns the untyped JSON.
+    fn delete_user_raw(&self, id: &str, profile: Profile) -> Result<Value, CisClientError>;
+    /// Delete profiles in chunks of `batch_size`, stopping at the first failed
+    /// chunk. Returns the responses of all chunks.
+    fn delete_users(&self, profiles: &[Profile]) -> Result<Value, CisClientError>;
     fn get_secret_store(&self) -> &SecretStore;
 }
 
 impl CisClient {
+    /// Blocking counterpart of [CisClient::get_user_by_unchecked].
+    pub fn get_user_by_unchecked_sync(
+        &self,
+        id: &str,
+        by: &GetBy,
+        filter: Option<&str>,
+    ) -> Result<Profile, CisClientError> {
+        self.get_user_sync(id, by, filter, ActiveFilter::Active, false)
+    }
+    /// Blocking counterpart of [CisClient::get_users_modified_since].
+    pub fn get_users_modified_since_sync(
+        &self,
+        since: DateTime<Utc>,
+        filter: Option<&str>,
+    ) -> ProfileIter<CisClient> {
+        ProfileIter::new(self.clone(), filter.map(String::from)).with_modified_since(since)
+    }
+    fn get_batch_sync(
+        &self,
+        next_page: &Option<NextPage>,
+        filter: &Option<String>,
+        extra_params: &[(&str, &str)],
+    ) -> Result<Batch, CisClientError> {
+        let mut url = self.person_api_users_endpoint.clone();
+        if let Some(df) = filter {
+            url.query_pairs_mut().append_pair("filterDisplay", df);
+        }
+        if let Some(next_page_token) = next_page {
+            let next_page_json = serde_json::to_string(next_page_token)?;
+            url.query_pairs_mut()
+                .append_pair("nextPage", &next_page_json);
+        }
+        url.query_pairs_mut()
+            .append_pair("nextPageSize", &self.batch_size.to_string())
+            .extend_pairs(extra_params);
+        trace!("{}", url.as_str());
+        if self.stream_batches {
+            let res = self.get_response("get_batch", url)?;
+            return batch_from_limited_reader(
+                BufReader::new(res),
+                self.max_response_bytes,
+                self.strict_profiles,
+            );
+        }
+        let json: Value = self.get("get_batch", url)?;
+        Batch::from_json(json, self.strict_profiles)
+    }
     fn get_user_sync(
         &self,
         id: &str,
         by: &GetBy,
         filter: Option<&str>,
-        active: Option<bool>,
+        active: ActiveFilter,
+        require_uuid: bool,
     ) -> Result<Profile, CisClientError> {
-        let active = match active {
-            None => String::from("any"),
-            Some(b) => b.to_string(),
-        };
-        let safe_id = utf8_percent_encode(id, USERINFO_ENCODE_SET).to_string();
-        let url = self
-            .person_api_user_endpoint
-            .clone()
-            .join(by.as_str())
-            .and_then(|u| u.join(safe_id.trim_start_matches('.')))
-            .map(|mut u| {
-                if let Some(df) = filter {
-                    u.query_pairs_mut().append_pair("filterDisplay", df);
-                }
-                u.query_pairs_mut().append_pair("active", &active);
-                u
-            })?;
-        let profile: Profile = self.get(url)?;
-        if profile.uuid.value.is_none() {
+        let url = self.get_user_url(id, by, filter, active)?;
+        let json: Value = self
+            .get("get_user", url)
+            .map_err(not_found_as_missing_profile)?;
+        let profile = profile_from_json(json, self.strict_profiles)?;
+        if require_uuid && profile.uuid.value.is_none() {
             return Err(ProfileError::ProfileDoesNotExist.into());
         }
         Ok(profile)
     }
-    fn get<T: DeserializeOwned>(&self, url: Url) -> Result<T, CisClientError> {
+    fn get<T: DeserializeOwned>(&self, op: &'static str, url: Url) -> Result<T, CisClientError> {
+        self.json(self.get_response(op, url)?)
+    }
+    /// Parse a JSON response body within the configured size limit.
+    fn json<T: DeserializeOwned>(&self, res: Response) -> Result<T, CisClientError> {
+        let status = res.status().as_u16();
+        let max = match self.max_response_bytes {
+            Some(max) => max,
+            None => return json_body(status, &res.bytes()?),
+        };
+        let mut body = Vec::new();
+        res.take(max as u64 + 1).read_to_end(&mut body)?;
+        if body.len() > max {
+            return Err(CisClientError::ResponseTooLarge(max));
+        }
+        json_body(status, &body)
+    }
+    fn get_response(&self, op: &'static str, url: Url) -> Result<Response, CisClientError> {
         let token = self.bearer_token_sync()?;
-        let client = Client::new().get(url.as_str()).bearer_auth(token);
-        let res = client.send()?.error_for_status()?;
-        res.json().map_err(Into::into)
+        let client = self.api_request(self.sync_http_client.get(url.as_str()).bearer_auth(token));
+        self.execute(op, Method::GET, client)
     }
     fn post<T: DeserializeOwned, P: Serialize>(
         &self,
+        op: &'static str,
         url: Url,
         payload: P,
     ) -> Result<T, CisClientError> {
         let token = self.bearer_token_sync()?;
-        let client = Client::new().post(url).json(&payload).bearer_auth(token);
-        let res = client.send()?.error_for_status()?;
-        res.json().map_err(Into::into)
+        let client = self.write_request(
+            self.sync_http_client
+                .post(url)
+                .json(&payload)
+                .bearer_auth(token),
+        );
+        let res = self.execute(op, Method::POST, client)?;
+        self.json(res)
     }
     fn delete<T: DeserializeOwned, P: Serialize>(
         &self,
+        op: &'static str,
         url: Url,
         payload: P,
     ) -> Result<T, CisClientError> {
         let token = self.bearer_token_sync()?;
-        let client = Client::new().delete(url).json(&payload).bearer_auth(token);
-        let res = client.send()?.error_for_status()?;
-        res.json().map_err(Into::into)
+        let client = self.write_request(
+            self.sync_http_client
+                .delete(url)
+                .json(&payload)
+                .bearer_auth(token),
+        );
+        let res = self.execute(op, Method::DELETE, client)?;
+        self.json(res)
+    }
+    /// Ask for the configured schema version if any.
+    fn api_request(&self, req: RequestBuilder) -> RequestBuilder {
+        match &self.schema_version {
+            Some(version) => req.header(ACCEPT, schema_version_accept(version)),
+            None => req,
+        }
+    }
+    /// Add a fresh idempotency key to a write if enabled.
+    fn write_request(&self, req: RequestBuilder) -> RequestBuilder {
+        let req = self.api_request(req);
+        if self.idempotency_keys {
+            return req.header(retry::IDEMPOTENCY_KEY, retry::idempotency_key());
+        }
+        req
+    }
+    /// Send `req` with retries reporting the outcome to the configured metrics.
+    fn execute(
+        &self,
+        op: &'static str,
+        method: Method,
+        req: RequestBuilder,
+    ) -> Result<Response, CisClientError> {
+        self.metrics.on_request(op, &method);
+        let start = Instant::now();
+        let retry_config = match method {
+            Method::GET => self.retry_config.clone(),
+            _ => self.retry_config.for_write(self.idempotency_keys),
+        };
+        let res = retry::send_sync(&retry_config, req)
+            .map_err(request_error)
+            .and_then(check_status);
+        let status = match &res {
+            Ok(res) => Some(res.status().as_u16()),
+            Err(e) => api_status(e),
+        };
+        self.metrics.on_response(op, status, start.elapsed());
+        res
     }
 }
 
+/// Turns non-2xx responses into [CisClientError::ApiError] keeping the response body.
+fn check_status(res: Response) -> Result<Response, CisClientError> {
+    let status = res.status();
+    if status.is_client_error() || status.is_server_error() {
+        let body = res.text().unwrap_or_default();
+        return Err(CisClientError::ApiError {
+            status: status.as_u16(),
+            body,
+        });
+    }
+    Ok(res)
+}
+
 impl CisClientTrait for CisClient {
     type PI = ProfileIter<CisClient>;
 
@@ -117,7 +314,7 @@ impl CisClientTrait for CisClient {
         by: &GetBy,
         filter: Option<&str>,
     ) -> Result<Profile, CisClientError> {
-        self.get_user_sync(id, by, filter, Some(false))
+        self.get_user_sync(id, by, filter, ActiveFilter::Inactive, true)
     }
     fn get_any_user_by(
         &self,
@@ -125,7 +322,7 @@ impl CisClientTrait for CisClient {
         by: &GetBy,
         filter: Option<&str>,
     ) -> Result<Profile, CisClientError> {
-        self.get_user_sync(id, by, filter, None)
+        self.get_user_sync(id, by, filter, ActiveFilter::Any, true)
     }
     fn get_user_by(
         &self,
@@ -133,7 +330,17 @@ impl CisClientTrait for CisClient {
         by: &GetBy,
         filter: Option<&str>,
     ) -> Result<Profile, CisClientError> {
-        self.get_user_sync(id, by, filter, Some(true))
+        self.get_user_sync(id, by, filter, ActiveFilter::Active, true)
+    }
+
+    fn get_user_raw_by(
+        &self,
+        id: &str,
+        by: &GetBy,
+        filter: Option<&str>,
+    ) -> Result<Value, CisClientError> {
+        let url = self.get_user_url(id, by, filter, ActiveFilter::Active)?;
+        self.get("get_user_raw", url)
     }
 
     fn get_users_iter(&self, filter: Option<&str>) -> Result<Self::PI, CisClientError> {
@@ -146,55 +353,503 @@ impl CisClientTrait for CisClient {
         next_page: &Option<NextPage>,
         filter: &Option<String>,
     ) -> Result<Batch, CisClientError> {
-        let mut url = self.person_api_users_endpoint.clone();
-        if let Some(df) = filter {
-            url.query_pairs_mut().append_pair("filterDisplay", df);
-        }
-        if let Some(next_page_token) = next_page {
-            let next_page_json = serde_json::to_string(next_page_token)?;
-            let safe_next_page =
-                utf8_percent_encode(&next_page_json, USERINFO_ENCODE_SET).to_string();
-            url.set_query(Some(&format!("nextPage={}", safe_next_page)));
-        }
-        info!("{}", url.as_str());
-        let mut json: Value = self.get(url)?;
-        let raw_items: Value = json["Items"].take();
-        let items: Option<Vec<Profile>> = match raw_items {
-            Value::Array(items) => Some(
-                items
-                    .into_iter()
-                    .filter_map(|item| serde_json::from_value::<Profile>(item).ok())
-                    .collect(),
-            ),
-            _ => None,
-        };
-        let next_page: Option<NextPage> = serde_json::from_value(json["nextPage"].take()).ok();
-        Ok(Batch { items, next_page })
+        self.get_batch_sync(next_page, filter, &[])
+    }
+
+    fn get_batch_modified_since(
+        &self,
+        next_page: &Option<NextPage>,
+        filter: &Option<String>,
+        since: DateTime<Utc>,
+    ) -> Result<Batch, CisClientError> {
+        let since = modified_since_param(since);
+        self.get_batch_sync(next_page, filter, &[("modifiedSince", &since)])
+    }
+
+    fn create_user_raw(&self, profile: Profile) -> Result<Value, CisClientError> {
+        self.post(
+            "create_user",
+            self.change_api_user_endpoint.clone(),
+            profile,
+        )
+        .map_err(conflict_as_existing_profile)
     }
 
-    fn update_user(&self, id: &str, profile: Profile) -> Result<Value, CisClientError> {
-        let safe_id = utf8_percent_encode(id, USERINFO_ENCODE_SET).to_string();
-        let mut url = self.change_api_user_endpoint.clone();
-        url.set_query(Some(&format!("user_id={}", safe_id)));
-        self.post(url, profile)
+    fn update_user_raw(&self, id: &str, profile: Profile) -> Result<Value, CisClientError> {
+        self.check_signatures(&profile)?;
+        let url = self.change_api_user_url(id)?;
+        self.post("update_user", url, profile)
     }
 
-    fn update_users(&self, profiles: &[Profile]) -> Result<Value, CisClientError> {
+    fn update_users(&self, profiles: &[Profile]) -> Result<UpdateUsersResult, CisClientError> {
+        for profile in profiles {
+            self.check_signatures(profile)?;
+        }
         let url = self.change_api_users_endpoint.clone();
+        let mut result = UpdateUsersResult::default();
         for chunk in profiles.chunks(self.batch_size) {
-            self.post(url.clone(), chunk)?;
+            result.push(chunk, self.post("update_users", url.clone(), chunk));
         }
-        Ok(json!({ "status": "all good" }))
+        Ok(result)
     }
 
-    fn delete_user(&self, id: &str, profile: Profile) -> Result<Value, CisClientError> {
-        let safe_id = utf8_percent_encode(id, USERINFO_ENCODE_SET).to_string();
-        let mut url = self.change_api_user_endpoint.clone();
-        url.set_query(Some(&format!("user_id={}", safe_id)));
-        self.delete(url, profile)
+    fn delete_user_raw(&self, id: &str, profile: Profile) -> Result<Value, CisClientError> {
+        let url = self.change_api_user_url(id)?;
+        self.delete("delete_user", url, profile)
+    }
+
+    fn delete_users(&self, profiles: &[Profile]) -> Result<Value, CisClientError> {
+        let url = self.change_api_users_endpoint.clone();
+        profiles
+            .chunks(self.batch_size)
+            .enumerate()
+            .map(|(i, chunk)| {
+                self.delete("delete_users", url.clone(), chunk)
+                    .map_err(|e| CisClientError::ChunkFailed {
+                        chunk: i,
+                        source: Box::new(e),
+                    })
+            })
+            .collect::<Result<Vec<Value>, _>>()
+            .map(Value::Array)
     }
 
     fn get_secret_store(&self) -> &SecretStore {
         &self.secret_store
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::*;
+    use anyhow::Error;
+    use serde_json::json;
+
+    #[test]
+    fn update_user_keeps_error_body() -> Result<(), Error> {
+        let mut server = mockito::Server::new();
+        server
+            .mock("POST", "/oauth/token")
+            .with_body(token_body())
+            .create();
+        server
+            .mock("POST", "/v2/user")
+            .match_query(mockito::Matcher::Any)
+            .with_status(422)
+            .with_body(r#"{"error":"invalid signature"}"#)
+            .create();
+        let client = CisClient::from_settings_sync(&settings_for(&server))?;
+        match client.update_user("some-id", Profile::default()) {
+            Err(CisClientError::ApiError { status, body }) => {
+                assert_eq!(status, 422);
+                assert_eq!(body, r#"{"error":"invalid signature"}"#);
+            }
+            r => panic!("unexpected result: {:?}", r),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn create_user_conflict_is_already_exists() -> Result<(), Error> {
+        let mut server = mockito::Server::new();
+        server
+            .mock("POST", "/oauth/token")
+            .with_body(token_body())
+            .create();
+        server.mock("POST", "/v2/user").with_status(409).create();
+        let client = CisClient::from_settings_sync(&settings_for(&server))?;
+        assert!(matches!(
+            client.create_user(Profile::default()),
+            Err(CisClientError::ProfileError(
+                ProfileError::ProfileAlreadyExists
+            ))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn get_users_modified_since_sync_checks_pages() -> Result<(), Error> {
+        let mut server = mockito::Server::new();
+        server
+            .mock("POST", "/oauth/token")
+            .with_body(token_body())
+            .create();
+        let mut changed = Profile::default();
+        changed.last_modified.value = Some(String::from("2020-06-01T00:00:00.000Z"));
+        let mut unchanged = Profile::default();
+        unchanged.last_modified.value = Some(String::from("2019-06-01T00:00:00.000Z"));
+        let users = server
+            .mock("GET", "/v2/users")
+            .match_query(mockito::Matcher::AllOf(vec![
+                mockito::Matcher::UrlEncoded("modifiedSince".into(), "2020-01-01T00:00:00Z".into()),
+                mockito::Matcher::Regex("^nextPageSize".into()),
+            ]))
+            .with_body(json!({ "Items": [changed], "nextPage": { "id": "page-2" } }).to_string())
+            .expect(1)
+            .create();
+        let ignored = server
+            .mock("GET", "/v2/users")
+            .match_query(mockito::Matcher::Regex("^nextPage=".into()))
+            .with_body(json!({ "Items": [unchanged] }).to_string())
+            .expect(1)
+            .create();
+        let client = CisClient::from_settings_sync(&settings_for(&server))?;
+        let since = "2020-01-01T00:00:00Z".parse::<DateTime<Utc>>()?;
+        let mut pages = client.get_users_modified_since_sync(since, None);
+        assert_eq!(pages.next().transpose()?.map(|page| page.len()), Some(1));
+        assert!(matches!(
+            pages.next(),
+            Some(Err(CisClientError::ModifiedSinceIgnored { .. }))
+        ));
+        assert!(pages.next().is_none());
+        users.assert();
+        ignored.assert();
+        Ok(())
+    }
+
+    #[test]
+    fn get_batch_sends_page_size() -> Result<(), Error> {
+        let mut server = mockito::Server::new();
+        server
+            .mock("POST", "/oauth/token")
+            .with_body(token_body())
+            .create();
+        let users = server
+            .mock("GET", "/v2/users")
+            .match_query(mockito::Matcher::UrlEncoded(
+                "nextPageSize".into(),
+                "10".into(),
+            ))
+            .with_body(r#"{"Items":[]}"#)
+            .create();
+        let next = server
+            .mock("GET", "/v2/users")
+            .match_query(mockito::Matcher::AllOf(vec![
+                mockito::Matcher::UrlEncoded("filterDisplay".into(), "staff".into()),
+                mockito::Matcher::UrlEncoded("nextPage".into(), r#"{"id":"page-2"}"#.into()),
+                mockito::Matcher::UrlEncoded("nextPageSize".into(), "10".into()),
+            ]))
+            .with_body(r#"{"Items":[]}"#)
+            .create();
+        let client = CisClient::from_settings_sync(&settings_for(&server))?.with_batch_size(10)?;
+        client.get_batch(&None, &None)?;
+        let next_page = Some(NextPage {
+            id: String::from("page-2"),
+        });
+        client.get_batch(&next_page, &Some(String::from("staff")))?;
+        users.assert();
+        next.assert();
+        Ok(())
+    }
+
+    #[test]
+    fn get_batch_does_not_log_at_info() -> Result<(), Error> {
+        let mut server = mockito::Server::new();
+        server
+            .mock("POST", "/oauth/token")
+            .with_body(token_body())
+            .create();
+        server
+            .mock("GET", "/v2/users")
+            .match_query(mockito::Matcher::Any)
+            .with_body(r#"{"Items":[]}"#)
+            .create();
+        let client = CisClient::from_settings_sync(&settings_for(&server))?;
+        let logs = LogCapture::start();
+        let next_page = Some(NextPage {
+            id: String::from("page-2"),
+        });
+        client.get_batch(&next_page, &Some(String::from("staff")))?;
+        assert!(logs.logged());
+        assert!(!logs.logged_info());
+        Ok(())
+    }
+
+    #[test]
+    fn get_batch_counts_malformed_profiles() -> Result<(), Error> {
+        let mut server = mockito::Server::new();
+        server
+            .mock("POST", "/oauth/token")
+            .with_body(token_body())
+            .create();
+        let profile: Value = serde_json::from_str(&profile_body("some-uuid"))?;
+        server
+            .mock("GET", "/v2/users")
+            .match_query(mockito::Matcher::Any)
+            .with_body(
+                serde_json::json!({ "Items": [profile, { "uuid": "not-an-attribute" }] })
+                    .to_string(),
+            )
+            .create();
+        let client = CisClient::from_settings_sync(&settings_for(&server))?;
+        let batch = client.get_batch(&None, &None)?;
+        assert_eq!(batch.items.map(|items| items.len()), Some(1));
+        assert_eq!(batch.skipped, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn oversized_responses_are_rejected() -> Result<(), Error> {
+        use crate::CisClientBuilder;
+
+        let mut server = mockito::Server::new();
+        server
+            .mock("POST", "/oauth/token")
+            .with_body(token_body())
+            .create();
+        let profile: Value = serde_json::from_str(&profile_body("some-uuid"))?;
+        server
+            .mock("GET", "/v2/users")
+            .match_query(mockito::Matcher::Any)
+            .with_body(serde_json::json!({ "Items": vec![profile; 20] }).to_string())
+            .create();
+        for stream_batches in &[false, true] {
+            let mut builder =
+                CisClientBuilder::from_settings(&settings_for(&server)).max_response_bytes(1024);
+            if *stream_batches {
+                builder = builder.stream_batches();
+            }
+            let client = builder.build()?;
+            assert!(matches!(
+                client.get_batch(&None, &None),
+                Err(CisClientError::ResponseTooLarge(1024))
+            ));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn update_user_is_retried_only_with_idempotency_keys() -> Result<(), Error> {
+        use crate::CisClientBuilder;
+
+        for idempotency_keys in &[false, true] {
+            let mut server = mockito::Server::new();
+            server
+                .mock("POST", "/oauth/token")
+                .with_body(token_body())
+                .create();
+            let unavailable = server
+                .mock("POST", "/v2/user")
+                .match_query(mockito::Matcher::Any)
+                .with_status(503)
+                .expect(1)
+                .create();
+            let updated = server
+                .mock("POST", "/v2/user")
+                .match_query(mockito::Matcher::Any)
+                .with_body("{}")
+                .expect(usize::from(*idempotency_keys))
+                .create();
+            let mut builder = CisClientBuilder::from_settings(&settings_for(&server));
+            if *idempotency_keys {
+                builder = builder.idempotency_keys();
+            }
+            let mut client = builder.build()?;
+            client.retry_config.base_delay = std::time::Duration::from_millis(1);
+            let res = client.update_user("some-id", Profile::default());
+            assert_eq!(res.is_ok(), *idempotency_keys);
+            unavailable.assert();
+            updated.assert();
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn sequential_get_user_by_share_sync_http_client() -> Result<(), Error> {
+        use crate::CisClientBuilder;
+        use std::sync::atomic::Ordering;
+
+        let (addr, connections) = counting_server(profile_body("some-uuid"));
+        let settings = settings_for_url(&format!("http://{}", addr));
+        let exp = chrono::Utc::now() + chrono::Duration::hours(1);
+        let client = CisClientBuilder::from_settings(&settings)
+            .static_token(FAKE_TOKEN.to_owned(), exp)
+            .build()?;
+        let cloned = client.clone();
+        client.get_user_by("some-uuid", &GetBy::Uuid, None)?;
+        cloned.get_user_by("some-uuid", &GetBy::Uuid, None)?;
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn writes_reject_empty_id() -> Result<(), Error> {
+        let mut server = mockito::Server::new();
+        server
+            .mock("POST", "/oauth/token")
+            .with_body(token_body())
+            .create();
+        let writes: Vec<mockito::Mock> = ["POST", "DELETE"]
+            .iter()
+            .map(|method| {
+                server
+                    .mock(method, "/v2/user")
+                    .match_query(mockito::Matcher::Any)
+                    .expect(0)
+                    .create()
+            })
+            .collect();
+        let client = CisClient::from_settings_sync(&settings_for(&server))?;
+        assert!(matches!(
+            client.update_user_raw("", Profile::default()),
+            Err(CisClientError::InvalidUserId(_))
+        ));
+        assert!(matches!(
+            client.delete_user_raw("", Profile::default()),
+            Err(CisClientError::InvalidUserId(_))
+        ));
+        for write in writes {
+            write.assert();
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn with_sync_http_client_is_used() -> Result<(), Error> {
+        let mut server = mockito::Server::new();
+        server
+            .mock("POST", "/oauth/token")
+            .with_body(token_body())
+            .create();
+        let user = server
+            .mock("GET", "/v2/user/uuid/some-uuid")
+            .match_query(mockito::Matcher::Any)
+            .match_header("x-injected", "yes")
+            .with_body(profile_body("some-uuid"))
+            .create();
+        let mut headers = reqwest::header::HeaderMap::new();
+        headers.insert("x-injected", "yes".parse()?);
+        let http_client = reqwest::blocking::Client::builder()
+            .default_headers(headers)
+            .build()?;
+        let client = CisClient::from_settings_sync(&settings_for(&server))?
+            .with_sync_http_client(http_client);
+        client.get_user_by("some-uuid", &GetBy::Uuid, None)?;
+        user.assert();
+        Ok(())
+    }
+
+    #[test]
+    fn delete_users_reports_failed_chunk() -> Result<(), Error> {
+        let mut server = mockito::Server::new();
+        server
+            .mock("POST", "/oauth/token")
+            .with_body(token_body())
+            .create();
+        server
+            .mock("DELETE", "/v2/users")
+            .with_body(r#"{"status":"deleted"}"#)
+            .expect(1)
+            .create();
+        server
+            .mock("DELETE", "/v2/users")
+            .with_status(500)
+            .expect(1)
+            .create();
+        let client = CisClient::from_settings_sync(&settings_for(&server))?.with_batch_size(2)?;
+        assert!(matches!(
+            client.delete_users(&vec![Profile::default(); 3]),
+            Err(CisClientError::ChunkFailed { chunk: 1, .. })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn update_users_chunks_by_batch_size() -> Result<(), Error> {
+        let mut server = mockito::Server::new();
+        server
+            .mock("POST", "/oauth/token")
+            .with_body(token_body())
+            .create();
+        let users = server
+            .mock("POST", "/v2/users")
+            .with_body("{}")
+            .expect(3)
+            .create();
+        let client = CisClient::from_settings_sync(&settings_for(&server))?.with_batch_size(2)?;
+        client.update_users(&vec![Profile::default(); 5])?;
+        users.assert();
+        Ok(())
+    }
+
+    #[test]
+    fn update_users_returns_chunk_responses() -> Result<(), Error> {
+        let mut server = mockito::Server::new();
+        server
+            .mock("POST", "/oauth/token")
+            .with_body(token_body())
+            .create();
+        server
+            .mock("POST", "/v2/users")
+            .with_body(r#"{"accepted":2}"#)
+            .expect(1)
+            .create();
+        server
+            .mock("POST", "/v2/users")
+            .with_body(r#"{"accepted":0}"#)
+            .expect(1)
+            .create();
+        let client = CisClient::from_settings_sync(&settings_for(&server))?.with_batch_size(2)?;
+        let res = client.update_users(&vec![Profile::default(); 4])?;
+        assert!(res.is_ok());
+        assert_eq!(
+            res.responses(),
+            vec![&json!({ "accepted": 2 }), &json!({ "accepted": 0 })]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn get_user_by_active_query() -> Result<(), Error> {
+        let mut server = mockito::Server::new();
+        server
+            .mock("POST", "/oauth/token")
+            .with_body(token_body())
+            .create();
+        let mocks: Vec<_> = ["true", "false", "any"]
+            .iter()
+            .map(|active| {
+                server
+                    .mock("GET", "/v2/user/uuid/some-uuid")
+                    .match_query(mockito::Matcher::UrlEncoded(
+                        "active".into(),
+                        active.to_string(),
+                    ))
+                    .with_body(profile_body("some-uuid"))
+                    .expect(1)
+                    .create()
+            })
+            .collect();
+        let client = CisClient::from_settings_sync(&settings_for(&server))?;
+        client.get_user_by("some-uuid", &GetBy::Uuid, None)?;
+        client.get_inactive_user_by("some-uuid", &GetBy::Uuid, None)?;
+        client.get_any_user_by("some-uuid", &GetBy::Uuid, None)?;
+        for mock in mocks {
+            mock.assert();
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn get_user_by_404_is_missing() -> Result<(), Error> {
+        let mut server = mockito::Server::new();
+        server
+            .mock("POST", "/oauth/token")
+            .with_body(token_body())
+            .create();
+        server
+            .mock("GET", "/v2/user/uuid/not-found")
+            .match_query(mockito::Matcher::Any)
+            .with_status(404)
+            .create();
+        let client = CisClient::from_settings_sync(&settings_for(&server))?;
+        assert!(matches!(
+            client.get_user_by("not-found", &GetBy::Uuid, None),
+            Err(CisClientError::ProfileError(
+                ProfileError::ProfileDoesNotExist
+            ))
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,101 @@
+use crate::error::CisClientError;
+#[cfg(feature = "csv")]
+use crate::export::csv_header;
+use crate::export::ExportReport;
+use crate::sync::client::CisClientTrait;
+use std::io::Write;
+
+/// Blocking counterpart of [crate::export::export_ndjson].
+pub fn export_ndjson<T: CisClientTrait, W: Write>(
+    client: &T,
+    filter: Option<&str>,
+    writer: &mut W,
+) -> Result<ExportReport, CisClientError> {
+    let mut report = ExportReport::default();
+    let mut buf = Vec::new();
+    for page in client.get_users_iter(filter)? {
+        buf.clear();
+        report.ndjson_page(&page?, &mut buf);
+        writer.write_all(&buf)?;
+        writer.flush()?;
+    }
+    Ok(report)
+}
+
+/// Blocking counterpart of [crate::export::export_csv].
+#[cfg(feature = "csv")]
+pub fn export_csv<T: CisClientTrait, W: Write>(
+    client: &T,
+    fields: &[&str],
+    filter: Option<&str>,
+    writer: &mut W,
+) -> Result<ExportReport, CisClientError> {
+    let mut report = ExportReport::default();
+    writer.write_all(&csv_header(fields)?)?;
+    let mut buf = Vec::new();
+    for page in client.get_users_iter(filter)? {
+        buf.clear();
+        report.csv_page(fields, &page?, &mut buf)?;
+        writer.write_all(&buf)?;
+        writer.flush()?;
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::client::CisClient;
+    use crate::test_util::*;
+    use anyhow::Error;
+    use cis_profile::schema::Profile;
+    use mockito::Matcher;
+    use serde_json::json;
+    use serde_json::Value;
+
+    #[test]
+    fn export_ndjson_three_pages() -> Result<(), Error> {
+        let mut server = mockito::Server::new();
+        server
+            .mock("POST", "/oauth/token")
+            .with_body(token_body())
+            .create();
+        let profile: Value = serde_json::from_str(&profile_body("some-uuid"))?;
+        server
+            .mock("GET", "/v2/users")
+            .match_query(Matcher::Any)
+            .with_body(json!({ "Items": [profile], "nextPage": { "id": "2" } }).to_string())
+            .expect(1)
+            .create();
+        server
+            .mock("GET", "/v2/users")
+            .match_query(Matcher::UrlEncoded(
+                "nextPage".into(),
+                r#"{"id":"2"}"#.into(),
+            ))
+            .with_body(
+                json!({ "Items": [profile, profile], "nextPage": { "id": "3" } }).to_string(),
+            )
+            .expect(1)
+            .create();
+        server
+            .mock("GET", "/v2/users")
+            .match_query(Matcher::UrlEncoded(
+                "nextPage".into(),
+                r#"{"id":"3"}"#.into(),
+            ))
+            .with_body(json!({ "Items": [profile] }).to_string())
+            .expect(1)
+            .create();
+        let client = CisClient::from_settings_sync(&settings_for(&server))?;
+        let mut out = Vec::new();
+        let report = export_ndjson(&client, None, &mut out)?;
+        assert_eq!(report.written, 4);
+        let lines: Vec<&str> = std::str::from_utf8(&out)?.lines().collect();
+        assert_eq!(lines.len(), 4);
+        for line in lines {
+            serde_json::from_str::<Profile>(line)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,120 @@
+use crate::batch::Batch;
+use crate::batch::NextPage;
+use crate::bulk::UpdateUsersResult;
+use crate::client::AsyncCisClientTrait;
+use crate::error::CisClientError;
+use crate::getby::GetBy;
+use crate::mock::MockCall;
+use crate::mock::MockCisClient;
+use crate::sync::batch::ProfileIter;
+use crate::sync::client::CisClientTrait;
+use cis_profile::crypto::SecretStore;
+use cis_profile::schema::Profile;
+use serde_json::Value;
+
+impl CisClientTrait for MockCisClient {
+    type PI = ProfileIter<MockCisClient>;
+
+    fn get_user_by(
+        &self,
+        id: &str,
+        by: &GetBy,
+        _: Option<&str>,
+    ) -> Result<Profile, CisClientError> {
+        let call = MockCall::GetUserBy {
+            id: id.to_owned(),
+            by: *by,
+        };
+        self.user(call, id, by, Some(true))
+    }
+    fn get_inactive_user_by(
+        &self,
+        id: &str,
+        by: &GetBy,
+        _: Option<&str>,
+    ) -> Result<Profile, CisClientError> {
+        let call = MockCall::GetInactiveUserBy {
+            id: id.to_owned(),
+            by: *by,
+        };
+        self.user(call, id, by, Some(false))
+    }
+    fn get_any_user_by(
+        &self,
+        id: &str,
+        by: &GetBy,
+        _: Option<&str>,
+    ) -> Result<Profile, CisClientError> {
+        let call = MockCall::GetAnyUserBy {
+            id: id.to_owned(),
+            by: *by,
+        };
+        self.user(call, id, by, None)
+    }
+    fn get_user_raw_by(
+        &self,
+        id: &str,
+        by: &GetBy,
+        _: Option<&str>,
+    ) -> Result<Value, CisClientError> {
+        self.user_raw(id, by)
+    }
+    fn get_users_iter(&self, filter: Option<&str>) -> Result<Self::PI, CisClientError> {
+        Ok(ProfileIter::new(self.clone(), filter.map(String::from)))
+    }
+    fn get_batch(
+        &self,
+        next_page: &Option<NextPage>,
+        _: &Option<String>,
+    ) -> Result<Batch, CisClientError> {
+        self.batch(next_page)
+    }
+    fn create_user_raw(&self, profile: Profile) -> Result<Value, CisClientError> {
+        Ok(self.write(MockCall::CreateUser(profile)))
+    }
+    fn update_user_raw(&self, id: &str, profile: Profile) -> Result<Value, CisClientError> {
+        Ok(self.write(MockCall::UpdateUser {
+            id: id.to_owned(),
+            profile,
+        }))
+    }
+    fn update_users(&self, profiles: &[Profile]) -> Result<UpdateUsersResult, CisClientError> {
+        Ok(self.write_users(profiles))
+    }
+    fn delete_user_raw(&self, id: &str, profile: Profile) -> Result<Value, CisClientError> {
+        Ok(self.write(MockCall::DeleteUser {
+            id: id.to_owned(),
+            profile,
+        }))
+    }
+    fn delete_users(&self, profiles: &[Profile]) -> Result<Value, CisClientError> {
+        Ok(self.write(MockCall::DeleteUsers(profiles.to_vec())))
+    }
+    fn get_secret_store(&self) -> &SecretStore {
+        AsyncCisClientTrait::get_secret_store(self)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::getby::GetBy;
+    use crate::mock::MockCisClient;
+    use crate::sync::client::CisClientTrait;
+    use anyhow::Error;
+    use cis_profile::schema::Profile;
+
+    #[test]
+    fn programmed_get_user_by_and_pages() -> Result<(), Error> {
+        let mut alice = Profile::default();
+        alice.uuid.value = Some(String::from("alice"));
+        let client = MockCisClient::new()
+            .with_user("alice", GetBy::Uuid, alice.clone())
+            .with_page(vec![alice.clone()])
+            .with_page(vec![alice]);
+        let profile = client.get_user_by("alice", &GetBy::Uuid, None)?;
+        assert_eq!(profile.uuid.value.as_deref(), Some("alice"));
+        assert_eq!(client.get_all_users(None)?.len(), 2);
+        assert_eq!(client.calls().len(), 3);
+        Ok(())
+    }
+}
@@ -1,2 +1,5 @@
 mod batch;
 pub mod client;
+pub mod export;
+#[cfg(feature = "mock")]
+mod mock;